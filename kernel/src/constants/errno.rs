@@ -0,0 +1,60 @@
+//! Error numbers returned (negated) from system calls.
+//!
+//! Values match the x86_64 Linux ABI.
+
+/// Operation not permitted
+pub const EPERM: i64 = 1;
+
+/// No such file or directory
+pub const ENOENT: i64 = 2;
+
+/// No such process
+pub const ESRCH: i64 = 3;
+
+/// I/O error
+pub const EIO: i64 = 5;
+
+/// Argument list too long
+pub const E2BIG: i64 = 7;
+
+/// Exec format error
+pub const ENOEXEC: i64 = 8;
+
+/// Bad file descriptor
+pub const EBADF: i64 = 9;
+
+/// No child processes
+pub const ECHILD: i64 = 10;
+
+/// Resource temporarily unavailable
+pub const EAGAIN: i64 = 11;
+
+/// Out of memory
+pub const ENOMEM: i64 = 12;
+
+/// Bad address
+pub const EFAULT: i64 = 14;
+
+/// No such device
+pub const ENODEV: i64 = 19;
+
+/// Invalid argument
+pub const EINVAL: i64 = 22;
+
+/// Too many open files
+pub const EMFILE: i64 = 24;
+
+/// Illegal seek
+pub const ESPIPE: i64 = 29;
+
+/// Broken pipe
+pub const EPIPE: i64 = 32;
+
+/// Resource deadlock would occur
+pub const EDEADLK: i64 = 35;
+
+/// File name too long
+pub const ENAMETOOLONG: i64 = 36;
+
+/// Function not implemented
+pub const ENOSYS: i64 = 38;
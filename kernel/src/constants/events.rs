@@ -8,3 +8,32 @@ pub const MAX_EVENTS: usize = 256;
 pub const NUM_EVENT_PRIORITIES: usize = 4;
 
 pub const PRIORITY_INC_DELAY: u64 = 5; // TODO try different values
+
+/// Timer ticks between a runner's checks for a much busier runner.
+pub const REBALANCE_INTERVAL: u64 = 10;
+
+/// How many more queued events another runner needs before a runner takes
+/// some of them.
+pub const REBALANCE_THRESHOLD: usize = 4;
+
+/// Lowest (most favoured) nice value.
+pub const MIN_NICE: i32 = -20;
+
+/// Highest nice value.
+pub const MAX_NICE: i32 = 19;
+
+/// Weight of nice 0 under the fair scheduling policy.
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weight of each nice value from `MIN_NICE` to `MAX_NICE`. Each step of nice
+/// changes the share of CPU time by about 10%, as in Linux.
+pub const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, // -20
+    29154, 23254, 18705, 14949, 11916, // -15
+    9548, 7620, 6100, 4904, 3906, // -10
+    3121, 2501, 1991, 1586, 1277, // -5
+    1024, 820, 655, 526, 423, // 0
+    335, 272, 215, 172, 137, // 5
+    110, 87, 70, 56, 45, // 10
+    36, 29, 23, 18, 15, // 15
+];
@@ -5,3 +5,6 @@ pub const TIMER_VECTOR: u8 = 32;
 pub const SYSCALL_HANDLER: u8 = 0x80;
 
 pub const TLB_SHOOTDOWN_VECTOR: u8 = 33;
+
+/// Vector sent to an idle core to make it look for new work.
+pub const WAKEUP_VECTOR: u8 = 34;
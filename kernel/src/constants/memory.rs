@@ -3,15 +3,29 @@
 /// Size of a memory page in bytes.
 pub const PAGE_SIZE: usize = 4096;
 
+/// Size of a 2 MiB huge page in bytes.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
 /// Size of a physical memory frame in bytes.
 pub const FRAME_SIZE: usize = 4096;
 
 /// Starting virtual address of the kernel heap.
 pub const HEAP_START: *mut u8 = 0x_FFFF_8100_0000_0000 as *mut u8;
 
-/// Initial size of the kernel heap (1 MB).
+/// Initial size of the kernel heap (10 MiB), mapped at boot.
 pub const HEAP_SIZE: usize = 10 * 1024 * 1024;
 
+/// Virtual range reserved for the kernel heap to grow into (1 GiB).
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Bytes the kernel heap grows by at least, and keeps free above what is in
+/// use when it shrinks; one huge page (2 MiB).
+pub const HEAP_GROW_STEP: usize = HUGE_PAGE_SIZE;
+
+/// Free bytes at the end of the kernel heap, past the slack it keeps, before
+/// they are unmapped (4 MiB).
+pub const HEAP_SHRINK_THRESHOLD: usize = 4 * 1024 * 1024;
+
 /// Maximum number of frames that can be allocated.
 /// Set to 512 to accommodate heap plus additional allocations.
 pub const MAX_ALLOCATED_FRAMES: usize = 4096;
@@ -22,4 +36,33 @@ pub const BITMAP_ENTRY_SIZE: usize = 64;
 /// Value representing a fully allocated bitmap entry.
 pub const FULL_BITMAP_ENTRY: u64 = 0xFFFFFFFFFFFFFFFF;
 
+/// Frames each core's frame cache holds at most.
+pub const FRAME_CACHE_CAPACITY: usize = 64;
+
+/// Frames a frame cache takes from or gives back to the global allocator at once.
+pub const FRAME_CACHE_BATCH: usize = 32;
+
+/// Bytes the slab allocator takes from the heap at once for a cache, unless
+/// that holds fewer than `SLAB_MIN_OBJECTS` objects.
+pub const SLAB_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Objects a slab holds at least.
+pub const SLAB_MIN_OBJECTS: usize = 8;
+
+/// Free objects each core keeps per slab cache at most.
+pub const SLAB_MAGAZINE_SIZE: usize = 32;
+
+/// Objects moved between a core's magazine and a cache's shared free list at once.
+pub const SLAB_MAGAZINE_BATCH: usize = 16;
+
+/// Largest block order of the buddy frame allocator, 2^18 frames (1 GiB).
+pub const BUDDY_MAX_ORDER: usize = 18;
+
+/// Pages of any size a core invalidates one by one on a TLB shootdown; more
+/// flush its whole TLB.
+pub const TLB_FLUSH_ALL_PAGES: u64 = 32;
+
 pub const EPHEMERAL_KERNEL_MAPPINGS_START: u64 = 0xFFFF_FF80_0000_0000;
+
+/// First address past the lower canonical half, where user space ends.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
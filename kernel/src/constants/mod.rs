@@ -3,11 +3,13 @@
 /// Maximum number of CPU cores supported by the kernel.
 pub const MAX_CORES: usize = 2;
 
+pub mod errno;
 pub mod events;
 pub mod gdt;
 pub mod idt;
 pub mod memory;
 pub mod ports;
 pub mod processes;
+pub mod signals;
 pub mod syscalls;
 pub mod x2apic;
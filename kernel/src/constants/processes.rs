@@ -2,7 +2,24 @@ pub const INFINITE_LOOP: &[u8] = include_bytes!("../processes/test_binaries/rand
 pub const LONG_LOOP: &[u8] = include_bytes!("../processes/test_binaries/long_loop_print");
 pub const PRINT_AND_SLEEP: &[u8] = include_bytes!("../processes/test_binaries/sleep");
 
+/// The first process created, which adopts orphaned processes
+pub const INIT_PID: u32 = 1;
+
 pub const STACK_START: u64 = 0x7000_0000_0000;
 pub const STACK_SIZE: usize = 2 * 4096; // 2 pages for the stack
+/// Default largest size a user stack may grow to
+pub const STACK_LIMIT: u64 = 8 * 1024 * 1024;
+/// Default limit on open file descriptors
+pub const DEFAULT_MAX_FILES: u64 = 256;
+/// Default limit on children and threads of a process
+pub const DEFAULT_MAX_PROCESSES: u64 = 64;
+/// Default limit on user memory mapped to frames, in bytes
+pub const DEFAULT_MAX_RESIDENT: u64 = 64 * 1024 * 1024;
+/// Size of the user stack allocated for a new thread
+pub const THREAD_STACK_SIZE: u64 = 16 * 4096;
+/// Largest executable `execve` reads into the kernel heap, in bytes
+pub const MAX_EXEC_SIZE: usize = 16 * 1024 * 1024;
+/// Lowest address picked for memory mappings without an address hint
+pub const MMAP_START: u64 = 0x1000_0000_0000;
 
 pub const PROCESS_TIMESLICE: u64 = 50_000_000; // 50 ms, to change later
@@ -0,0 +1,49 @@
+//! Signal numbers and `sigaction` ABI values.
+//!
+//! Values match the x86_64 Linux ABI. Real-time signals are not supported.
+
+/// One more than the highest signal number
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+/// Handler value: take the default action
+pub const SIG_DFL: u64 = 0;
+/// Handler value: discard the signal
+pub const SIG_IGN: u64 = 1;
+
+/// `sigaction` flag: `restorer` holds the address handlers return to
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// `sigaction` flag: do not block the signal while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// `sigaction` flag: reset the handler to `SIG_DFL` when it is delivered
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `sigprocmask` how: add signals to the blocked mask
+pub const SIG_BLOCK: u64 = 0;
+/// `sigprocmask` how: remove signals from the blocked mask
+pub const SIG_UNBLOCK: u64 = 1;
+/// `sigprocmask` how: replace the blocked mask
+pub const SIG_SETMASK: u64 = 2;
@@ -1,3 +1,105 @@
-pub const SYSCALL_EXIT: u32 = 60;
-pub const SYSCALL_NANOSLEEP: u32 = 35;
+//! System call numbers and ABI limits.
+
+/// Size of the system call table. Numbers at or above this are unknown.
+pub const MAX_SYSCALLS: usize = 512;
+
+/// Longest path, including the terminating NUL, a syscall will copy in.
+pub const PATH_MAX: usize = 4096;
+
+/// Most bytes of argument and environment strings, plus their pointers,
+/// `execve` will place on a new stack.
+pub const ARG_MAX: usize = 4096;
+
+/// Bytes a pipe buffers before writers have to wait.
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Event priority of kernel work done on behalf of a blocked syscall.
+pub const SYSCALL_IO_PRIORITY: usize = 1;
+
+/// Length of both `syscall` and `int 0x80`, stepped back over to restart a
+/// syscall.
+pub const SYSCALL_INSTRUCTION_LEN: u64 = 2;
+
+pub const SYSCALL_READ: u32 = 0;
+pub const SYSCALL_WRITE: u32 = 1;
+pub const SYSCALL_OPEN: u32 = 2;
 pub const SYSCALL_PRINT: u32 = 3;
+pub const SYSCALL_CLOSE: u32 = 4;
+pub const SYSCALL_LSEEK: u32 = 8;
+pub const SYSCALL_MMAP: u32 = 9;
+pub const SYSCALL_MPROTECT: u32 = 10;
+pub const SYSCALL_MUNMAP: u32 = 11;
+pub const SYSCALL_BRK: u32 = 12;
+pub const SYSCALL_SIGACTION: u32 = 13;
+pub const SYSCALL_SIGPROCMASK: u32 = 14;
+pub const SYSCALL_SIGRETURN: u32 = 15;
+pub const SYSCALL_PIPE: u32 = 22;
+pub const SYSCALL_NANOSLEEP: u32 = 35;
+pub const SYSCALL_GETPID: u32 = 39;
+pub const SYSCALL_FORK: u32 = 57;
+pub const SYSCALL_EXECVE: u32 = 59;
+pub const SYSCALL_EXIT: u32 = 60;
+pub const SYSCALL_WAITPID: u32 = 61;
+pub const SYSCALL_KILL: u32 = 62;
+pub const SYSCALL_GETRLIMIT: u32 = 97;
+pub const SYSCALL_TIMES: u32 = 100;
+pub const SYSCALL_GETPPID: u32 = 110;
+pub const SYSCALL_GETPRIORITY: u32 = 140;
+pub const SYSCALL_SETPRIORITY: u32 = 141;
+pub const SYSCALL_CLOCK_GETTIME: u32 = 228;
+pub const SYSCALL_SETRLIMIT: u32 = 160;
+pub const SYSCALL_WAIT: u32 = 247;
+pub const SYSCALL_SBRK: u32 = 248;
+pub const SYSCALL_THREAD_CREATE: u32 = 249;
+pub const SYSCALL_THREAD_EXIT: u32 = 250;
+pub const SYSCALL_THREAD_JOIN: u32 = 251;
+pub const SYSCALL_PROCINFO: u32 = 252;
+
+/// `lseek` whence: set the offset to `offset`
+pub const SEEK_SET: u64 = 0;
+/// `lseek` whence: move the offset by `offset`
+pub const SEEK_CUR: u64 = 1;
+/// `lseek` whence: set the offset relative to the end of the file
+pub const SEEK_END: u64 = 2;
+
+/// `getpriority`/`setpriority` target: a single process
+pub const PRIO_PROCESS: u64 = 0;
+
+/// `clock_gettime` clock: time since boot, never set back
+pub const CLOCK_MONOTONIC: u64 = 1;
+/// `clock_gettime` clock: time since boot, including suspend
+pub const CLOCK_BOOTTIME: u64 = 7;
+
+/// `waitpid` option: return 0 instead of blocking if no child has exited
+pub const WNOHANG: u64 = 1;
+
+/// `mmap`/`mprotect` protection: pages may be read
+pub const PROT_READ: u64 = 0x1;
+/// `mmap`/`mprotect` protection: pages may be written
+pub const PROT_WRITE: u64 = 0x2;
+/// `mmap`/`mprotect` protection: pages may be executed
+pub const PROT_EXEC: u64 = 0x4;
+
+/// `mmap` flag: changes are visible to other processes mapping the object
+pub const MAP_SHARED: u64 = 0x01;
+/// `mmap` flag: changes are private to the process
+pub const MAP_PRIVATE: u64 = 0x02;
+/// `mmap` flag: map exactly at `addr`, replacing existing mappings
+pub const MAP_FIXED: u64 = 0x10;
+/// `mmap` flag: the mapping is zero-filled memory, not backed by a file
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Resource limit: largest size in bytes the stack may grow to
+pub const RLIMIT_STACK: u64 = 3;
+/// Resource limit: most bytes of user memory mapped to frames at once
+pub const RLIMIT_RSS: u64 = 5;
+/// Resource limit: most children and threads a process may have at once
+pub const RLIMIT_NPROC: u64 = 6;
+/// Resource limit: most open file descriptors
+pub const RLIMIT_NOFILE: u64 = 7;
+/// Resource limit: lowest nice value a process may set, as `20 - nice`
+pub const RLIMIT_NICE: u64 = 13;
+/// Number of resource limits, including ones that are not enforced
+pub const RLIM_NLIMITS: usize = 16;
+/// A resource limit that is never reached
+pub const RLIM_INFINITY: u64 = u64::MAX;
@@ -25,6 +25,39 @@ pub fn _print(args: ::core::fmt::Arguments) {
         .expect("Printing to serial failed");
 }
 
+/// Writes raw bytes to the serial port without interpreting them.
+///
+/// # Arguments
+/// * `bytes` - the bytes to send
+pub fn write_bytes(bytes: &[u8]) {
+    let mut port = SERIAL1.lock();
+    for &byte in bytes {
+        port.send(byte);
+    }
+}
+
+/// Reads whatever bytes the serial port has received, without blocking.
+///
+/// # Arguments
+/// * `buf` - buffer to fill
+///
+/// # Returns
+/// The number of bytes read
+pub fn read_bytes(buf: &mut [u8]) -> usize {
+    let mut port = SERIAL1.lock();
+    let mut read = 0;
+    while read < buf.len() {
+        match port.try_receive() {
+            Ok(byte) => {
+                buf[read] = byte;
+                read += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    read
+}
+
 /// Prints formatted text to the serial port.
 ///
 /// # Examples
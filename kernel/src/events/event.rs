@@ -1,33 +1,60 @@
-use super::{Event, EventId, EventQueue};
-use alloc::{boxed::Box, collections::btree_set::BTreeSet, sync::Arc};
-use core::future::Future;
+use super::{kick_core, Event, EventHome, EventId, SchedParams, ANY_CORE};
+use alloc::{boxed::Box, sync::Arc};
+use core::{future::Future, sync::atomic::Ordering};
 use futures::task::ArcWake;
-use spin::{Mutex, RwLock};
+use spin::Mutex;
 
 impl Event {
     pub fn init(
         future: impl Future<Output = ()> + 'static + Send,
-        rewake_queue: Arc<EventQueue>,
-        blocked_events: Arc<RwLock<BTreeSet<u64>>>,
+        home: EventHome,
         priority: usize,
         pid: u32,
+        tid: u32,
         scheduled_clock: u64,
     ) -> Event {
         Event {
             eid: EventId::init(),
             pid,
+            tid,
             future: Mutex::new(Box::pin(future)),
-            rewake_queue,
-            blocked_events,
+            home: Mutex::new(home),
             priority: priority.into(),
             scheduled_timestamp: scheduled_clock.into(),
+            affinity: ANY_CORE.into(),
+            nice: 0,
+            vruntime: 0.into(),
+            completed: false.into(),
         }
     }
+
+    /// Applies scheduling parameters to an event not yet queued
+    pub fn with_params(mut self, params: SchedParams) -> Event {
+        self.affinity = params.affinity.into();
+        self.nice = params.nice;
+        self.vruntime = params.vruntime.into();
+        self
+    }
+
+    /// Whether the event may run on core `cpuid`
+    pub fn allows(&self, cpuid: u32) -> bool {
+        self.affinity.load(Ordering::Relaxed) & (1 << cpuid) != 0
+    }
+
+    /// Makes the event return to another runner's queues when woken
+    pub fn rehome(&self, home: EventHome) {
+        *self.home.lock() = home;
+    }
 }
 
 impl ArcWake for Event {
     fn wake_by_ref(arc: &Arc<Self>) {
-        arc.rewake_queue.write().push_back(arc.clone());
-        arc.blocked_events.write().remove(&arc.eid.0);
+        let cpuid = {
+            let home = arc.home.lock();
+            home.rewake_queue.write().push_back(arc.clone());
+            home.blocked_events.write().remove(&arc.eid.0);
+            home.cpuid
+        };
+        kick_core(cpuid);
     }
 }
@@ -1,8 +1,10 @@
 use alloc::{
-    collections::{binary_heap::BinaryHeap, btree_set::BTreeSet, vec_deque::VecDeque},
+    collections::{
+        binary_heap::BinaryHeap, btree_map::BTreeMap, btree_set::BTreeSet, vec_deque::VecDeque,
+    },
     sync::Arc,
 };
-use futures::task::waker_ref;
+use futures::task::{waker, waker_ref};
 use spin::rwlock::RwLock;
 use x86_64::instructions::interrupts::{self, without_interrupts};
 
@@ -14,17 +16,24 @@ use core::{
 
 use super::{
     futures::Sleep,
+    kick_idle_core,
+    policy::{scheduling_policy, vruntime_per_tick},
+    set_core_idle,
     tasks::{CancellationToken, JoinHandle, TaskError},
-    Event, EventId, EventQueue, EventRunner,
+    CoreMask, Event, EventHome, EventId, EventQueue, EventRunner, SchedParams, EVENT_RUNNERS,
+};
+use crate::{
+    constants::{events::NUM_EVENT_PRIORITIES, x2apic::NS_PER_TICK},
+    interrupts::{tsc::monotonic_nanos, x2apic::arm_timer},
 };
-use crate::{constants::events::NUM_EVENT_PRIORITIES, interrupts::x2apic::nanos_to_ticks};
 use spin::Mutex;
 
-use crate::constants::events::PRIORITY_INC_DELAY;
+use crate::constants::events::{REBALANCE_INTERVAL, REBALANCE_THRESHOLD};
 
 impl EventRunner {
-    pub fn init() -> EventRunner {
+    pub fn init(cpuid: u32) -> EventRunner {
         EventRunner {
+            cpuid,
             event_queues: core::array::from_fn(|_| Arc::new(RwLock::new(VecDeque::new()))),
             pending_events: RwLock::new(BTreeSet::new()),
             blocked_events: Arc::new(RwLock::new(BTreeSet::new())),
@@ -32,11 +41,17 @@ impl EventRunner {
             current_event: None,
             event_clock: 0,
             system_clock: 0,
+            next_rebalance: REBALANCE_INTERVAL,
+            policy: scheduling_policy().build(),
+            timer_deadline: None,
         }
     }
 
     pub fn run_loop(&mut self) -> ! {
         loop {
+            // Tick while there is work, in case it just arrived
+            without_interrupts(|| self.rearm_timer());
+
             loop {
                 if !self.have_unblocked_events() {
                     break;
@@ -57,7 +72,12 @@ impl EventRunner {
 
                     let mut future_guard = event.future.lock();
 
-                    let ready: bool = future_guard.as_mut().poll(&mut context) != Poll::Pending;
+                    // Another core may have finished the event after stealing it
+                    let ready: bool = event.completed.load(Ordering::Acquire)
+                        || future_guard.as_mut().poll(&mut context) != Poll::Pending;
+                    if ready {
+                        event.completed.store(true, Ordering::Release);
+                    }
 
                     drop(future_guard);
 
@@ -77,16 +97,37 @@ impl EventRunner {
 
                 self.current_event = None;
                 interrupts::enable();
-            }
 
-            // TODO do a lil work-stealing
+                if self.system_clock >= self.next_rebalance {
+                    self.next_rebalance = self.system_clock + REBALANCE_INTERVAL;
+                    without_interrupts(|| self.rebalance());
+
+                    // Idle cores are not interrupted, so they must be told
+                    // there is work to take
+                    if self.queued_events() > 0 {
+                        kick_idle_core(self.cpuid);
+                    }
+                }
+            }
 
-            // Must have pending, but blocked, events
-            if self.have_blocked_events() {
-                self.awake_next_sleeper();
+            if without_interrupts(|| self.steal_work()) > 0 {
+                continue;
             }
 
+            // Halt until the next sleeper's deadline, or until another core
+            // queues work here. Marked idle before the last check, so work
+            // queued after it comes with a kick.
+            interrupts::disable();
+            self.awake_sleepers();
+            set_core_idle(self.cpuid, true);
+            if self.have_unblocked_events() {
+                set_core_idle(self.cpuid, false);
+                interrupts::enable();
+                continue;
+            }
+            self.rearm_timer();
             interrupts::enable_and_hlt();
+            set_core_idle(self.cpuid, false);
         }
     }
 
@@ -96,18 +137,51 @@ impl EventRunner {
         future: impl Future<Output = ()> + 'static + Send,
         priority_level: usize,
         pid: u32,
+        tid: u32,
+    ) -> Option<EventId> {
+        self.schedule_with_params(future, priority_level, pid, tid, SchedParams::default())
+    }
+
+    // Schedules an event that only runs on, and is only stolen by, the cores
+    // in `affinity`
+    pub fn schedule_with_affinity(
+        &mut self,
+        future: impl Future<Output = ()> + 'static + Send,
+        priority_level: usize,
+        pid: u32,
+        tid: u32,
+        affinity: CoreMask,
+    ) -> Option<EventId> {
+        let params = SchedParams {
+            affinity,
+            ..SchedParams::default()
+        };
+        self.schedule_with_params(future, priority_level, pid, tid, params)
+    }
+
+    // Schedules an event with its affinity, nice value and virtual runtime
+    pub fn schedule_with_params(
+        &mut self,
+        future: impl Future<Output = ()> + 'static + Send,
+        priority_level: usize,
+        pid: u32,
+        tid: u32,
+        params: SchedParams,
     ) -> Option<EventId> {
         if priority_level >= NUM_EVENT_PRIORITIES {
             panic!("Invalid event priority: {}", priority_level);
         } else {
-            let event = Arc::new(Event::init(
-                future,
-                self.event_queues[priority_level].clone(),
-                self.blocked_events.clone(),
-                priority_level,
-                pid,
-                self.event_clock,
-            ));
+            let event = Arc::new(
+                Event::init(
+                    future,
+                    self.home(priority_level),
+                    priority_level,
+                    pid,
+                    tid,
+                    self.event_clock,
+                )
+                .with_params(params),
+            );
 
             // serial_println!("Created {:?}", event.eid);
 
@@ -119,31 +193,36 @@ impl EventRunner {
         }
     }
 
-    // Schedules an event with a specified priority level [0, NUM_EVENT_PRIORITIES)
+    // Schedules an event that only runs once woken, returning its waker
     pub fn schedule_blocked(
         &mut self,
         future: impl Future<Output = ()> + 'static + Send,
         priority_level: usize,
         pid: u32,
-    ) -> Option<EventId> {
+        tid: u32,
+        params: SchedParams,
+    ) -> Waker {
         if priority_level >= NUM_EVENT_PRIORITIES {
             panic!("Invalid event priority: {}", priority_level);
         } else {
-            let event = Arc::new(Event::init(
-                future,
-                self.event_queues[priority_level].clone(),
-                self.blocked_events.clone(),
-                priority_level,
-                pid,
-                self.event_clock,
-            ));
+            let event = Arc::new(
+                Event::init(
+                    future,
+                    self.home(priority_level),
+                    priority_level,
+                    pid,
+                    tid,
+                    self.event_clock,
+                )
+                .with_params(params),
+            );
 
             // serial_println!("Created {:?}", event.eid);
 
             self.pending_events.write().insert(event.eid.0);
             self.blocked_events.write().insert(event.eid.0);
 
-            Some(event.eid)
+            waker(event)
         }
     }
 
@@ -151,33 +230,77 @@ impl EventRunner {
         self.current_event.as_ref()
     }
 
-    pub fn inc_system_clock(&mut self) {
-        self.system_clock += 1;
+    /// Brings the tick count up to date with the core's clock
+    ///
+    /// # Returns
+    /// The number of ticks since it was last brought up to date
+    pub fn advance_clock(&mut self) -> u64 {
+        let now = monotonic_nanos() / NS_PER_TICK;
+        let ticks = now.saturating_sub(self.system_clock);
+        self.system_clock += ticks;
+
+        if let Some(event) = &self.current_event {
+            event
+                .vruntime
+                .fetch_add(vruntime_per_tick(event.nice) * ticks, Ordering::Relaxed);
+        }
+        ticks
     }
 
-    pub fn awake_next_sleeper(&mut self) {
-        let sleeper = self.sleeping_events.peek();
+    /// Records that the core's timer fired, so it is no longer armed
+    pub fn timer_fired(&mut self) {
+        self.timer_deadline = None;
+    }
 
-        if sleeper.is_some() {
-            let future = sleeper.unwrap();
-            if future.target_timestamp <= self.system_clock {
-                future.awake();
-                self.blocked_events.write().remove(&future.get_id());
-                self.sleeping_events.pop();
+    /// When the core's timer should fire next: at the next tick while there
+    /// is work, for accounting and preemption, and at the next sleeper's
+    /// deadline. Sleepers already due are left to the run loop to wake.
+    ///
+    /// `u64::MAX` if nothing needs the timer.
+    fn next_timer_deadline(&self) -> u64 {
+        let now = monotonic_nanos();
+        let sleeper = self
+            .sleeping_events
+            .iter()
+            .map(|sleep| sleep.target_timestamp)
+            .filter(|&deadline| deadline > now)
+            .min();
+        let busy = self.current_event.is_some() || self.have_unblocked_events();
+        let tick = busy.then(|| (self.system_clock + 1) * NS_PER_TICK);
+        sleeper.into_iter().chain(tick).min().unwrap_or(u64::MAX)
+    }
+
+    /// Arms the core's timer for its next deadline, unless it is armed for it
+    /// already. Must run on the runner's own core.
+    pub fn rearm_timer(&mut self) {
+        let deadline = self.next_timer_deadline();
+        if self.timer_deadline != Some(deadline) {
+            arm_timer(deadline);
+            self.timer_deadline = Some(deadline);
+        }
+    }
+
+    /// Wakes every sleeper whose deadline has passed
+    pub fn awake_sleepers(&mut self) {
+        let now = monotonic_nanos();
+        while let Some(sleep) = self.sleeping_events.peek() {
+            if sleep.target_timestamp > now {
+                break;
             }
+            sleep.awake();
+            self.blocked_events.write().remove(&sleep.get_id());
+            self.sleeping_events.pop();
         }
     }
 
     pub fn nanosleep_current_event(&mut self, nanos: u64) -> Option<Sleep> {
-        self.current_event.as_ref().map(|e| {
-            let system_ticks = nanos_to_ticks(nanos);
-
-            let sleep = Sleep::new(self.system_clock + system_ticks, (*e).clone());
-            self.sleeping_events.push(sleep.clone());
-            self.blocked_events.write().insert(e.eid.0);
+        let event = self.current_event.clone()?;
+        let sleep = Sleep::new(monotonic_nanos().saturating_add(nanos), event.clone());
+        self.sleeping_events.push(sleep.clone());
+        self.blocked_events.write().insert(event.eid.0);
+        self.rearm_timer();
 
-            sleep
-        })
+        Some(sleep)
     }
 
     pub fn nanosleep_event(
@@ -185,29 +308,33 @@ impl EventRunner {
         future: impl Future<Output = ()> + 'static + Send,
         priority_level: usize,
         pid: u32,
+        tid: u32,
         nanos: u64,
+        params: SchedParams,
     ) -> Option<Sleep> {
         if priority_level >= NUM_EVENT_PRIORITIES {
             panic!("Invalid event priority: {}", priority_level);
         } else {
-            let event = Arc::new(Event::init(
-                future,
-                self.event_queues[priority_level].clone(),
-                self.blocked_events.clone(),
-                priority_level,
-                pid,
-                self.event_clock,
-            ));
+            let event = Arc::new(
+                Event::init(
+                    future,
+                    self.home(priority_level),
+                    priority_level,
+                    pid,
+                    tid,
+                    self.event_clock,
+                )
+                .with_params(params),
+            );
 
             // serial_println!("Created {:?}", event.eid);
 
-            let system_ticks = nanos_to_ticks(nanos);
-
-            let sleep = Sleep::new(self.system_clock + system_ticks, event.clone());
+            let sleep = Sleep::new(monotonic_nanos().saturating_add(nanos), event.clone());
             self.sleeping_events.push(sleep.clone());
 
             self.pending_events.write().insert(event.eid.0);
             self.blocked_events.write().insert(event.eid.0);
+            self.rearm_timer();
 
             Some(sleep)
         }
@@ -251,7 +378,7 @@ impl EventRunner {
             };
 
             // Schedule the wrapped future
-            let eid = without_interrupts(|| self.schedule(wrapped_future, priority_level, 0));
+            let eid = without_interrupts(|| self.schedule(wrapped_future, priority_level, 0, 0));
 
             JoinHandle {
                 result,
@@ -262,8 +389,92 @@ impl EventRunner {
         })
     }
 
-    fn have_blocked_events(&self) -> bool {
-        !self.blocked_events.read().is_empty()
+    /// Number of events ready to run
+    fn queued_events(&self) -> usize {
+        self.event_queues
+            .iter()
+            .map(|queue| queue.read().len())
+            .sum()
+    }
+
+    /// Takes work from the busiest other runner once this one has run out
+    ///
+    /// # Returns
+    /// The number of events taken
+    fn steal_work(&mut self) -> usize {
+        let runners = EVENT_RUNNERS.read();
+        let Some((_, victim)) = self.busiest_runner(&runners) else {
+            return 0;
+        };
+        let victim = victim.read();
+        let count = victim.queued_events().div_ceil(2);
+        self.steal_from(&victim, count)
+    }
+
+    /// Evens out queue depths with the busiest other runner, if it has
+    /// `REBALANCE_THRESHOLD` more events queued than this one
+    fn rebalance(&mut self) {
+        let runners = EVENT_RUNNERS.read();
+        let Some((depth, victim)) = self.busiest_runner(&runners) else {
+            return;
+        };
+        let own_depth = self.queued_events();
+        if depth >= own_depth + REBALANCE_THRESHOLD {
+            self.steal_from(&victim.read(), (depth - own_depth) / 2);
+        }
+    }
+
+    /// Finds the other runner with the most events queued, if any has some
+    fn busiest_runner<'a>(
+        &self,
+        runners: &'a BTreeMap<u32, RwLock<EventRunner>>,
+    ) -> Option<(usize, &'a RwLock<EventRunner>)> {
+        runners
+            .iter()
+            .filter(|(&cpuid, _)| cpuid != self.cpuid)
+            .map(|(_, runner)| (runner.read().queued_events(), runner))
+            .filter(|&(depth, _)| depth > 0)
+            .max_by_key(|&(depth, _)| depth)
+    }
+
+    /// Moves up to `count` ready events that may run on this core from the
+    /// tails of `victim`'s queues to this runner, most urgent first
+    ///
+    /// # Returns
+    /// The number of events moved
+    fn steal_from(&mut self, victim: &EventRunner, count: usize) -> usize {
+        let mut stolen = 0;
+        for priority in 0..NUM_EVENT_PRIORITIES {
+            while stolen < count {
+                let event = {
+                    let mut queue = victim.event_queues[priority].write();
+                    let index = queue.iter().rposition(|event| event.allows(self.cpuid));
+                    index.and_then(|index| queue.remove(index))
+                };
+                let Some(event) = event else {
+                    break;
+                };
+
+                // A stale copy, e.g. queued twice by wakers, is dropped
+                if !victim.pending_events.write().remove(&event.eid.0) {
+                    continue;
+                }
+                event.rehome(self.home(priority));
+                self.pending_events.write().insert(event.eid.0);
+                Self::enqueue(&self.event_queues[priority], event);
+                stolen += 1;
+            }
+        }
+        stolen
+    }
+
+    /// Where an event of this runner at `priority` returns to when woken
+    fn home(&self, priority: usize) -> EventHome {
+        EventHome {
+            cpuid: self.cpuid,
+            rewake_queue: self.event_queues[priority].clone(),
+            blocked_events: self.blocked_events.clone(),
+        }
     }
 
     fn have_unblocked_events(&self) -> bool {
@@ -280,58 +491,105 @@ impl EventRunner {
         self.pending_events.read().contains(&eid.0)
     }
 
-    fn next_event_timestamp(queue: &EventQueue) -> Option<u64> {
-        queue
-            .read()
-            .front()
-            .map(|e| e.scheduled_timestamp.load(Ordering::Relaxed))
-    }
-
-    fn try_pop(queue: &EventQueue) -> Option<Arc<Event>> {
-        queue.write().pop_front()
-    }
-
     fn enqueue(queue: &EventQueue, event: Arc<Event>) {
         queue.write().push_back(event);
     }
 
-    fn reprioritize(&mut self) {
-        for i in 1..NUM_EVENT_PRIORITIES {
-            let scheduled_clock = Self::next_event_timestamp(&self.event_queues[i]);
-
-            scheduled_clock.inspect(|event_scheduled_at| {
-                if event_scheduled_at + PRIORITY_INC_DELAY <= self.event_clock {
-                    let event_to_move = Self::try_pop(&self.event_queues[i]);
-                    event_to_move.inspect(|e| {
-                        Self::enqueue(&self.event_queues[i - 1], e.clone());
+    fn next_event(&mut self) -> Option<Arc<Event>> {
+        self.awake_sleepers();
 
-                        Self::change_priority(&e, i - 1);
-                        e.scheduled_timestamp
-                            .swap(self.event_clock, Ordering::Relaxed);
-                    });
-                }
-            });
+        let policy = scheduling_policy();
+        if self.policy.kind() != policy {
+            self.policy = policy.build();
         }
+        self.policy.next_event(&self.event_queues, self.event_clock)
     }
+}
 
-    fn change_priority(event: &Event, priority: usize) {
-        event.priority.swap(priority, Ordering::Relaxed);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::events::NICE_0_WEIGHT,
+        events::{core_mask, SchedPolicy},
+    };
+
+    #[test_case]
+    fn test_steal_respects_affinity() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut busy = EventRunner::init(0);
+            let mut idle = EventRunner::init(1);
+
+            for _ in 0..3 {
+                busy.schedule(async {}, 1, 0, 0);
+            }
+            let pinned = busy
+                .schedule_with_affinity(async {}, 0, 0, 0, core_mask(0))
+                .unwrap();
+
+            assert_eq!(idle.steal_from(&busy, 2), 2);
+            assert_eq!(busy.queued_events(), 2);
+            assert_eq!(idle.queued_events(), 2);
+
+            // The pinned event stays where it was scheduled
+            assert_eq!(idle.steal_from(&busy, 10), 1);
+            assert_eq!(busy.queued_events(), 1);
+            assert!(busy.contains_event(pinned));
+            assert!(!idle.contains_event(pinned));
+
+            // Stolen events return to the thief's queues when woken
+            let event = idle.event_queues[1].write().pop_front().unwrap();
+            futures::task::waker(event).wake();
+            assert_eq!(idle.queued_events(), 3);
+            assert_eq!(busy.queued_events(), 1);
+        }
     }
 
-    fn next_event(&mut self) -> Option<Arc<Event>> {
-        self.awake_next_sleeper();
-
-        let mut event = None;
+    #[test_case]
+    fn test_fair_policy_orders_by_vruntime() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut runner = EventRunner::init(0);
+            let mut schedule = |level, nice, vruntime| {
+                let params = SchedParams {
+                    nice,
+                    vruntime,
+                    ..SchedParams::default()
+                };
+                runner
+                    .schedule_with_params(async {}, level, 0, 0, params)
+                    .unwrap()
+            };
+            let late = schedule(0, 0, 1000);
+            let behind = schedule(3, 0, 500);
+            let least = schedule(3, 0, 100);
+            let niced = schedule(3, 5, 300);
+
+            // Priority levels do not matter, only virtual runtime
+            let mut policy = SchedPolicy::Fair.build();
+            let mut next = || policy.next_event(&runner.event_queues, 0).unwrap().eid;
+            assert_eq!(next(), least);
+            assert_eq!(next(), niced);
+            assert_eq!(next(), behind);
+            assert_eq!(next(), late);
+            assert!(policy.next_event(&runner.event_queues, 0).is_none());
+
+            // Lower nice values are charged less per tick
+            assert_eq!(vruntime_per_tick(0), NICE_0_WEIGHT);
+            assert!(vruntime_per_tick(-20) < vruntime_per_tick(0));
+            assert!(vruntime_per_tick(0) < vruntime_per_tick(19));
+        }
+    }
 
-        self.reprioritize();
+    #[test_case]
+    fn test_timer_idles_without_work() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut runner = EventRunner::init(0);
+            assert_eq!(runner.next_timer_deadline(), u64::MAX);
 
-        for i in 0..NUM_EVENT_PRIORITIES {
-            event = Self::try_pop(&self.event_queues[i]);
-            if event.is_some() {
-                break;
-            }
+            // Ticks once there is work
+            runner.schedule(async {}, 1, 0, 0);
+            let tick = (runner.system_clock + 1) * NS_PER_TICK;
+            assert_eq!(runner.next_timer_deadline(), tick);
         }
-
-        event
     }
 }
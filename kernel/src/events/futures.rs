@@ -7,10 +7,12 @@ use core::{
 
 use futures::task::ArcWake;
 
-use super::{runner_timestamp, Event};
+use super::Event;
+use crate::interrupts::tsc::monotonic_nanos;
 
 #[derive(Clone)]
 pub struct Sleep {
+    /// Deadline on the TSC clock, in nanoseconds
     pub target_timestamp: u64,
     event: Arc<Event>,
 }
@@ -59,9 +61,7 @@ impl Future for Sleep {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
-        let system_time = runner_timestamp();
-
-        if self.target_timestamp <= system_time {
+        if self.target_timestamp <= monotonic_nanos() {
             Poll::Ready(())
         } else {
             Poll::Pending
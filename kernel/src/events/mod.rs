@@ -12,23 +12,32 @@ use x86_64::instructions::interrupts::without_interrupts;
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::Waker,
 };
 
 use crate::{
-    constants::events::NUM_EVENT_PRIORITIES, interrupts::x2apic,
-    processes::process::run_process_ring3,
+    constants::{events::NUM_EVENT_PRIORITIES, idt::WAKEUP_VECTOR},
+    interrupts::x2apic,
+    memory::slab::SlabCache,
+    processes::{process::run_process_ring3, thread::sched_state},
 };
 
 mod event;
 mod event_runner;
 mod futures;
+mod policy;
 mod tasks;
+mod wait_queue;
 
+pub use policy::{
+    nice_weight, scheduling_policy, set_scheduling_policy, vruntime_per_tick, SchedPolicy,
+};
 pub use tasks::{
     yield_task::{yield_now, Yield},
     JoinHandle,
 };
+pub use wait_queue::{WaitQueue, WaitUntil};
 
 // Thread-safe future that remains pinned to a heap address throughout its lifetime
 type SendFuture = Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>;
@@ -36,6 +45,17 @@ type SendFuture = Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>;
 // Thread-safe static queue of events
 type EventQueue = RwLock<VecDeque<Arc<Event>>>;
 
+/// Cores an event may run on, one bit per core ID
+pub type CoreMask = u64;
+
+/// Lets an event run on, and be stolen by, any core
+pub const ANY_CORE: CoreMask = CoreMask::MAX;
+
+/// The mask of a single core
+pub fn core_mask(cpuid: u32) -> CoreMask {
+    1 << cpuid
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EventId(u64);
 
@@ -52,15 +72,35 @@ impl EventId {
 struct Event {
     eid: EventId,
     pid: u32,
+    tid: u32,
     future: SendFuture,
-    rewake_queue: Arc<EventQueue>,
-    blocked_events: Arc<RwLock<BTreeSet<u64>>>,
+    // Runner the event returns to when woken; changes if it is stolen
+    home: Mutex<EventHome>,
     priority: AtomicUsize,
     scheduled_timestamp: AtomicU64,
+    affinity: AtomicU64,
+    // Weights the event's CPU share under the fair policy
+    nice: i32,
+    // Time the event has run, scaled by its nice weight
+    vruntime: AtomicU64,
+    // Set once the future returned Ready, so stray copies are not polled
+    completed: AtomicBool,
 }
 
-// Schedules and runs events within a single core
+/// Slab cache for `Arc<Event>`, allocated for every spawned future
+pub(crate) static EVENT_CACHE: SlabCache = SlabCache::for_arc::<Event>("event");
+
+// Queues of the runner that owns an event
+struct EventHome {
+    cpuid: u32,
+    rewake_queue: Arc<EventQueue>,
+    blocked_events: Arc<RwLock<BTreeSet<u64>>>,
+}
+
+// Schedules and runs events within a single core, taking ready events from
+// other cores when idle
 struct EventRunner {
+    cpuid: u32,
     event_queues: [Arc<EventQueue>; NUM_EVENT_PRIORITIES],
     pending_events: RwLock<BTreeSet<u64>>,
     blocked_events: Arc<RwLock<BTreeSet<u64>>>,
@@ -68,12 +108,65 @@ struct EventRunner {
     current_event: Option<Arc<Event>>,
     event_clock: u64,
     system_clock: u64,
+    next_rebalance: u64,
+    policy: Box<dyn policy::SchedulingPolicy>,
+    // Deadline the core's timer is armed for; None once it has fired
+    timer_deadline: Option<u64>,
+}
+
+/// How an event is scheduled, besides its priority level
+#[derive(Debug, Clone, Copy)]
+pub struct SchedParams {
+    /// Cores the event may run on
+    pub affinity: CoreMask,
+    /// Nice value, weighting the event under the fair policy
+    pub nice: i32,
+    /// Virtual runtime the event starts with under the fair policy
+    pub vruntime: u64,
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        Self {
+            affinity: ANY_CORE,
+            nice: 0,
+            vruntime: 0,
+        }
+    }
 }
 
 // Global mapping of cores to events
 // TODO will need to expand when distributed, like most globals
 static EVENT_RUNNERS: RwLock<BTreeMap<u32, RwLock<EventRunner>>> = RwLock::new(BTreeMap::new());
 
+// Cores halted with nothing to run. Their timers only fire for sleepers, so
+// other cores interrupt them when queuing work there.
+static IDLE_CORES: AtomicU64 = AtomicU64::new(0);
+
+fn set_core_idle(cpuid: u32, idle: bool) {
+    if idle {
+        IDLE_CORES.fetch_or(core_mask(cpuid), Ordering::SeqCst);
+    } else {
+        IDLE_CORES.fetch_and(!core_mask(cpuid), Ordering::SeqCst);
+    }
+}
+
+// Interrupts core `cpuid` if it is idle, so it runs newly queued work
+fn kick_core(cpuid: u32) {
+    let idle = IDLE_CORES.load(Ordering::SeqCst) & core_mask(cpuid) != 0;
+    if idle && cpuid != x2apic::current_core_id() as u32 {
+        x2apic::send_ipi(cpuid, WAKEUP_VECTOR);
+    }
+}
+
+// Interrupts an idle core other than `cpuid`, if any, so it steals work
+fn kick_idle_core(cpuid: u32) {
+    let idle = IDLE_CORES.load(Ordering::SeqCst) & !core_mask(cpuid);
+    if idle != 0 {
+        x2apic::send_ipi(idle.trailing_zeros(), WAKEUP_VECTOR);
+    }
+}
+
 /// # Safety
 ///
 /// TODO
@@ -85,51 +178,117 @@ pub unsafe fn run_loop(cpuid: u32) -> ! {
 }
 
 pub fn schedule_kernel(future: impl Future<Output = ()> + 'static + Send, priority_level: usize) {
+    schedule_kernel_with_affinity(future, priority_level, ANY_CORE);
+}
+
+/// Schedules kernel work on the current core that only cores in `affinity`
+/// may run, e.g. `core_mask(cpuid)` to keep it off other cores
+pub fn schedule_kernel_with_affinity(
+    future: impl Future<Output = ()> + 'static + Send,
+    priority_level: usize,
+    affinity: CoreMask,
+) {
     let cpuid = x2apic::current_core_id() as u32;
 
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
-        runner.schedule(future, priority_level, 0);
+        runner.schedule_with_affinity(future, priority_level, 0, 0, affinity);
     });
 }
 
+/// Schedules the main thread of a process on the current core
 pub fn schedule_process(pid: u32, // 0 as kernel/sentinel
 ) {
-    let cpuid = x2apic::current_core_id() as u32;
+    schedule_thread(pid, pid);
+}
+
+/// Schedules a thread on the current core
+pub fn schedule_thread(pid: u32, tid: u32) {
+    schedule_thread_on(x2apic::current_core_id() as u32, pid, tid);
+}
+
+/// Schedules a thread on the core `cpuid`
+pub fn schedule_thread_on(cpuid: u32, pid: u32, tid: u32) {
+    let (priority_level, params) = thread_sched_params(pid, tid);
 
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
         unsafe {
-            runner.schedule(run_process_ring3(pid), NUM_EVENT_PRIORITIES - 1, pid);
+            runner.schedule_with_params(
+                run_process_ring3(pid, tid),
+                priority_level,
+                pid,
+                tid,
+                params,
+            );
         }
     });
+    kick_core(cpuid);
 }
 
-pub fn schedule_blocked_process(pid: u32, // 0 as kernel/sentinel
-) {
+// Priority level and parameters of a thread's event. Threads of processes
+// with a negative nice value start a level up under the aging policy.
+fn thread_sched_params(pid: u32, tid: u32) -> (usize, SchedParams) {
+    let (nice, vruntime) = sched_state(pid, tid);
+    let priority_level = if nice < 0 {
+        NUM_EVENT_PRIORITIES - 2
+    } else {
+        NUM_EVENT_PRIORITIES - 1
+    };
+    let params = SchedParams {
+        nice,
+        vruntime,
+        ..SchedParams::default()
+    };
+    (priority_level, params)
+}
+
+/// Picks a core for new work, cycling through every core with a runner
+pub fn next_core() -> u32 {
+    static NEXT_CORE: AtomicUsize = AtomicUsize::new(0);
+
+    let runners = EVENT_RUNNERS.read();
+    let index = NEXT_CORE.fetch_add(1, Ordering::Relaxed) % runners.len();
+    *runners.keys().nth(index).expect("No runner found")
+}
+
+/// Creates the event that runs a blocked thread on the current core, but
+/// does not queue it
+///
+/// # Returns
+/// The waker that queues the event, e.g. to register with a `WaitQueue`
+pub fn schedule_blocked_process(
+    pid: u32, // 0 as kernel/sentinel
+    tid: u32,
+) -> Waker {
     let cpuid = x2apic::current_core_id() as u32;
+    let (priority_level, params) = thread_sched_params(pid, tid);
 
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
         unsafe {
-            runner.schedule_blocked(run_process_ring3(pid), NUM_EVENT_PRIORITIES - 1, pid);
+            runner.schedule_blocked(
+                run_process_ring3(pid, tid),
+                priority_level,
+                pid,
+                tid,
+                params,
+            )
         }
-    });
-
-    todo!();
+    })
 }
 
 pub fn register_event_runner() {
     let cpuid = x2apic::current_core_id() as u32;
 
     without_interrupts(|| {
-        let runner = EventRunner::init();
+        let runner = EventRunner::init(cpuid);
         let mut write_lock = EVENT_RUNNERS.write();
 
         write_lock.insert(cpuid, RwLock::new(runner));
@@ -158,15 +317,23 @@ pub fn current_running_event_priority() -> usize {
     }
 }
 
-pub fn inc_runner_clock() {
+/// Updates the current core's runner after its timer fired, and arms the
+/// timer for the next deadline
+///
+/// # Returns
+/// The number of timer ticks since the timer last fired
+pub fn runner_timer_fired() -> u64 {
     let cpuid = x2apic::current_core_id() as u32;
 
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
-        runner.inc_system_clock();
-    });
+        let ticks = runner.advance_clock();
+        runner.timer_fired();
+        runner.rearm_timer();
+        ticks
+    })
 }
 
 pub fn runner_timestamp() -> u64 {
@@ -191,16 +358,25 @@ pub fn nanosleep_current_event(nanos: u64) -> Option<Sleep> {
 
 pub fn nanosleep_current_process(
     pid: u32, // 0 as kernel/sentinel
+    tid: u32,
     nanos: u64,
 ) {
     let cpuid = x2apic::current_core_id() as u32;
+    let (priority_level, params) = thread_sched_params(pid, tid);
 
     without_interrupts(|| {
         let runners = EVENT_RUNNERS.read();
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
         unsafe {
-            runner.nanosleep_event(run_process_ring3(pid), NUM_EVENT_PRIORITIES - 1, pid, nanos);
+            runner.nanosleep_event(
+                run_process_ring3(pid, tid),
+                priority_level,
+                pid,
+                tid,
+                nanos,
+                params,
+            );
         }
     });
 }
@@ -209,6 +385,7 @@ pub fn nanosleep_current_process(
 pub struct EventInfo {
     pub priority: usize,
     pub pid: u32,
+    pub tid: u32,
 }
 
 pub fn current_running_event_info() -> EventInfo {
@@ -221,10 +398,12 @@ pub fn current_running_event_info() -> EventInfo {
         Some(e) => EventInfo {
             priority: e.priority.load(Ordering::Relaxed),
             pid: e.pid,
+            tid: e.tid,
         },
         None => EventInfo {
             priority: NUM_EVENT_PRIORITIES - 1,
             pid: 0,
+            tid: 0,
         },
     }
 }
@@ -239,6 +418,8 @@ where
         let mut runner = runners.get(&cpuid).expect("No runner found").write();
 
         let res = runner.spawn(future, priority_level);
+        drop(runner);
+        kick_core(cpuid);
         res
     })
 }
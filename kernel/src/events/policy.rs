@@ -0,0 +1,218 @@
+//! Scheduling policies
+//!
+//! A policy decides which ready event a runner polls next. Events wait in the
+//! runner's priority queues whatever the policy, since wakers push them back
+//! onto those queues directly.
+//!
+//! - `RoundRobin`: events run in the order they became ready; priority
+//!   levels and nice values are ignored
+//! - `Aging`: the most urgent priority level runs first, and events left
+//!   waiting move up a level every `PRIORITY_INC_DELAY` polls
+//! - `Fair`: the event with the least virtual runtime runs first. Running
+//!   charges virtual runtime at a rate set by the nice value, so CPU time is
+//!   shared in proportion to nice weights, as in Linux' CFS.
+//!
+//! The policy is chosen for all runners with `set_scheduling_policy`; each
+//! runner switches before it next picks an event. At boot it is read from the
+//! kernel command line, e.g. `sched_policy=fair`.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::{Event, EventQueue};
+use crate::{
+    constants::events::{
+        MAX_NICE, MIN_NICE, NICE_0_WEIGHT, NICE_WEIGHTS, NUM_EVENT_PRIORITIES, PRIORITY_INC_DELAY,
+    },
+    init::cmdline_option,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SchedPolicy {
+    RoundRobin,
+    Aging,
+    Fair,
+}
+
+/// Policy runners switch to; `Aging` is what the scheduler did before
+/// policies were pluggable
+static SCHED_POLICY: AtomicU8 = AtomicU8::new(SchedPolicy::Aging as u8);
+
+impl SchedPolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::RoundRobin,
+            1 => Self::Aging,
+            _ => Self::Fair,
+        }
+    }
+
+    /// Reads `sched_policy=round_robin`, `sched_policy=aging` or
+    /// `sched_policy=fair` from the kernel command line
+    ///
+    /// # Returns
+    /// The policy asked for, `Aging` if none is
+    pub fn from_cmdline() -> Self {
+        match cmdline_option(b"sched_policy") {
+            Some(b"round_robin") => Self::RoundRobin,
+            Some(b"fair") => Self::Fair,
+            _ => Self::Aging,
+        }
+    }
+
+    pub(super) fn build(self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            Self::RoundRobin => Box::new(RoundRobin),
+            Self::Aging => Box::new(Aging {
+                delay: PRIORITY_INC_DELAY,
+            }),
+            Self::Fair => Box::new(Fair { min_vruntime: 0 }),
+        }
+    }
+}
+
+/// Switches every runner to `policy`
+pub fn set_scheduling_policy(policy: SchedPolicy) {
+    SCHED_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The policy runners use, or are about to switch to
+pub fn scheduling_policy() -> SchedPolicy {
+    SchedPolicy::from_u8(SCHED_POLICY.load(Ordering::Relaxed))
+}
+
+/// Weight of a nice value: CPU time under the fair policy is shared in
+/// proportion to it
+pub fn nice_weight(nice: i32) -> u64 {
+    NICE_WEIGHTS[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// Virtual runtime charged for one timer tick of running at `nice`
+pub fn vruntime_per_tick(nice: i32) -> u64 {
+    NICE_0_WEIGHT * NICE_0_WEIGHT / nice_weight(nice)
+}
+
+type Queues = [Arc<EventQueue>; NUM_EVENT_PRIORITIES];
+
+/// Chooses the next event out of a runner's priority queues
+pub(super) trait SchedulingPolicy: Send + Sync {
+    fn kind(&self) -> SchedPolicy;
+
+    /// Removes the next event to poll from the queues
+    ///
+    /// * `event_clock`: polls the runner has made so far
+    fn next_event(&mut self, queues: &Queues, event_clock: u64) -> Option<Arc<Event>>;
+}
+
+struct RoundRobin;
+
+impl SchedulingPolicy for RoundRobin {
+    fn kind(&self) -> SchedPolicy {
+        SchedPolicy::RoundRobin
+    }
+
+    fn next_event(&mut self, queues: &Queues, _event_clock: u64) -> Option<Arc<Event>> {
+        // Each queue is in the order its events became ready, so the oldest
+        // event is at the front of one of them
+        let oldest = queues
+            .iter()
+            .filter_map(|queue| {
+                let timestamp = queue
+                    .read()
+                    .front()?
+                    .scheduled_timestamp
+                    .load(Ordering::Relaxed);
+                Some((timestamp, queue))
+            })
+            .min_by_key(|&(timestamp, _)| timestamp)?;
+        oldest.1.write().pop_front()
+    }
+}
+
+struct Aging {
+    /// Polls an event waits at the front of its queue before moving up
+    delay: u64,
+}
+
+impl Aging {
+    fn reprioritize(&self, queues: &Queues, event_clock: u64) {
+        for i in 1..NUM_EVENT_PRIORITIES {
+            let scheduled_clock = queues[i]
+                .read()
+                .front()
+                .map(|e| e.scheduled_timestamp.load(Ordering::Relaxed));
+
+            scheduled_clock.inspect(|event_scheduled_at| {
+                if event_scheduled_at + self.delay <= event_clock {
+                    let event_to_move = queues[i].write().pop_front();
+                    event_to_move.inspect(|e| {
+                        queues[i - 1].write().push_back(e.clone());
+
+                        e.priority.swap(i - 1, Ordering::Relaxed);
+                        e.scheduled_timestamp.swap(event_clock, Ordering::Relaxed);
+                    });
+                }
+            });
+        }
+    }
+}
+
+impl SchedulingPolicy for Aging {
+    fn kind(&self) -> SchedPolicy {
+        SchedPolicy::Aging
+    }
+
+    fn next_event(&mut self, queues: &Queues, event_clock: u64) -> Option<Arc<Event>> {
+        self.reprioritize(queues, event_clock);
+
+        queues.iter().find_map(|queue| queue.write().pop_front())
+    }
+}
+
+struct Fair {
+    /// Virtual runtime of the last event picked. Events that have not run
+    /// for a while are treated as having this much, so they cannot
+    /// monopolize the core to catch up.
+    min_vruntime: u64,
+}
+
+impl SchedulingPolicy for Fair {
+    fn kind(&self) -> SchedPolicy {
+        SchedPolicy::Fair
+    }
+
+    fn next_event(&mut self, queues: &Queues, _event_clock: u64) -> Option<Arc<Event>> {
+        let min_vruntime = self.min_vruntime;
+        let effective = |event: &Event| event.vruntime.load(Ordering::Relaxed).max(min_vruntime);
+
+        loop {
+            let mut best: Option<(u64, usize, Arc<Event>)> = None;
+            for (level, queue) in queues.iter().enumerate() {
+                for event in queue.read().iter() {
+                    let vruntime = effective(event);
+                    if best.as_ref().is_none_or(|(least, _, _)| vruntime < *least) {
+                        best = Some((vruntime, level, event.clone()));
+                    }
+                }
+            }
+            let (vruntime, level, event) = best?;
+
+            // Another core may have stolen it meanwhile
+            let mut queue = queues[level].write();
+            let Some(index) = queue.iter().position(|e| Arc::ptr_eq(e, &event)) else {
+                continue;
+            };
+            queue.remove(index);
+            drop(queue);
+
+            // Every poll costs at least a tick, so events that return quickly
+            // do not keep the core to themselves
+            event
+                .vruntime
+                .store(vruntime + vruntime_per_tick(event.nice), Ordering::Relaxed);
+            self.min_vruntime = vruntime;
+            return Some(event);
+        }
+    }
+}
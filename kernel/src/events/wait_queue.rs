@@ -0,0 +1,144 @@
+//! Wait queues
+//!
+//! A wait queue holds the wakers of everything waiting for one condition,
+//! such as data arriving in a pipe or a child exiting. Whoever makes the
+//! condition true wakes the queue.
+//!
+//! Kernel futures wait with `wait_until`. A process thread parks on a queue
+//! with `processes::process::park_process`, which re-enqueues the thread when
+//! it is woken.
+//!
+//! To not miss a wakeup, waiters register before their final check of the
+//! condition, and wakers change the condition before waking the queue.
+
+use alloc::collections::vec_deque::VecDeque;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds a waker to the queue, unless it is already waiting
+    pub fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push_back(waker.clone());
+        }
+    }
+
+    /// Removes a waker from the queue
+    ///
+    /// # Returns
+    /// Whether it was still waiting; if not, it has been or is being woken
+    pub fn unregister(&self, waker: &Waker) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.iter().position(|w| w.will_wake(waker)) {
+            Some(index) => {
+                waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes the longest waiting waiter
+    ///
+    /// # Returns
+    /// Whether there was one to wake
+    pub fn wake_one(&self) -> bool {
+        // Woken outside the lock, as a woken waiter may register again
+        let waker = self.waiters.lock().pop_front();
+        match waker {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiter
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Number of waiters
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until `condition` returns a value, checking it whenever the
+    /// queue is woken
+    pub fn wait_until<T, F>(&self, condition: F) -> WaitUntil<'_, F>
+    where
+        F: FnMut() -> Option<T> + Unpin,
+    {
+        WaitUntil {
+            queue: self,
+            condition,
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for WaitQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitQueue")
+            .field("waiters", &self.len())
+            .finish()
+    }
+}
+
+/// Future returned by `WaitQueue::wait_until`
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+}
+
+impl<T, F> Future for WaitUntil<'_, F>
+where
+    F: FnMut() -> Option<T> + Unpin,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if let Some(value) = (self.condition)() {
+            return Poll::Ready(value);
+        }
+
+        // Check again once registered, in case the queue was woken in between
+        self.queue.register(cx.waker());
+        match (self.condition)() {
+            Some(value) => {
+                self.queue.unregister(cx.waker());
+                Poll::Ready(value)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
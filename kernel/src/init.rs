@@ -5,15 +5,17 @@
 use bytes::Bytes;
 use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use limine::{
-    request::SmpRequest,
+    request::{KernelFileRequest, SmpRequest},
     smp::{Cpu, RequestFlags},
     BaseRevision,
 };
 
 use crate::{
     debug, devices,
-    events::{register_event_runner, run_loop, spawn, yield_now},
-    interrupts::{self, idt},
+    events::{
+        register_event_runner, run_loop, set_scheduling_policy, spawn, yield_now, SchedPolicy,
+    },
+    interrupts::{self, idt, tsc},
     ipc::{
         messages::Message,
         mnt_manager,
@@ -23,7 +25,7 @@ use crate::{
     },
     logging,
     memory::{self},
-    serial_println, trace,
+    processes, serial_println, syscalls, trace,
 };
 
 extern crate alloc;
@@ -38,6 +40,11 @@ static BASE_REVISION: BaseRevision = BaseRevision::new();
 #[link_section = ".requests"]
 static SMP_REQUEST: SmpRequest = SmpRequest::new().with_flags(RequestFlags::X2APIC);
 
+/// Kernel file request, for the command line
+#[used]
+#[link_section = ".requests"]
+static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+
 /// Flag indicating completion of boot process
 /// Used to synchronize AP initialization
 static BOOT_COMPLETE: AtomicBool = AtomicBool::new(false);
@@ -52,12 +59,15 @@ static CPU_COUNT: AtomicU64 = AtomicU64::new(0);
 pub fn init() -> u32 {
     assert!(BASE_REVISION.is_supported());
     interrupts::init(0);
+    syscalls::init(0);
+    processes::init(0);
 
     memory::init(0);
     devices::init(0);
     // Should be kept after devices in case logging gets complicated
     // Right now log writes to serial, but if it were to switch to VGA, this would be important
     logging::init(0);
+    set_scheduling_policy(SchedPolicy::from_cmdline());
 
     debug!("Waking cores");
     let bsp_id = wake_cores();
@@ -68,6 +78,22 @@ pub fn init() -> u32 {
     bsp_id
 }
 
+/// Reads an option of the form `name=value` from the kernel command line
+///
+/// # Arguments
+/// * `name` - the option's name, e.g. `b"frame_allocator"`
+///
+/// # Returns
+/// The option's value, or None if the command line does not set it
+pub fn cmdline_option(name: &[u8]) -> Option<&'static [u8]> {
+    let cmdline = KERNEL_FILE_REQUEST
+        .get_response()
+        .map_or(&[][..], |response| response.file().cmdline());
+    cmdline
+        .split(|byte| byte.is_ascii_whitespace())
+        .find_map(|option| option.strip_prefix(name)?.strip_prefix(b"="))
+}
+
 /// Entry point for Application Processors (APs)
 ///
 /// # Arguments
@@ -82,6 +108,8 @@ pub fn init() -> u32 {
 unsafe extern "C" fn secondary_cpu_main(cpu: &Cpu) -> ! {
     CPU_COUNT.fetch_add(1, Ordering::SeqCst);
     interrupts::init(cpu.id);
+    syscalls::init(cpu.id);
+    processes::init(cpu.id);
     memory::init(cpu.id);
     logging::init(cpu.id);
 
@@ -91,6 +119,7 @@ unsafe extern "C" fn secondary_cpu_main(cpu: &Cpu) -> ! {
     while !BOOT_COMPLETE.load(Ordering::SeqCst) {
         core::hint::spin_loop();
     }
+    tsc::sync_current_core();
 
     register_event_runner();
     idt::enable();
@@ -122,6 +151,8 @@ fn wake_cores() -> u32 {
         core::hint::spin_loop();
     }
 
+    // APs line their TSCs up with this reading once released
+    tsc::publish_sync_reference();
     BOOT_COMPLETE.store(true, Ordering::SeqCst);
 
     debug!("All CPUs initialized");
@@ -28,7 +28,7 @@ use crate::constants::{
     MAX_CORES,
 };
 
-/// Number of base GDT entries: null descriptor + kernel code/data + user code/data
+/// Number of base GDT entries: null descriptor + kernel code/data + user data/code
 const BASE_ENTRIES: usize = 5;
 
 /// Number of GDT entries needed per TSS (each TSS requires 16 bytes in long mode)
@@ -67,7 +67,7 @@ lazy_static! {
     /// Global Descriptor Table and segment selectors.
     /// Contains:
     /// - Kernel code and data segments
-    /// - User code and data segments (currently unused)
+    /// - User data and code segments
     /// - TSS descriptors for each CPU core
     pub static ref GDT: (GlobalDescriptorTable<GDT_ENTRIES>, Selectors) = {
         let mut gdt = GlobalDescriptorTable::<GDT_ENTRIES>::empty();
@@ -75,8 +75,10 @@ lazy_static! {
         // Add segments
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        // SYSRET derives CS and SS from a single base in STAR, so user data must
+        // directly precede user code
         let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
 
         let mut tss_selectors = [SegmentSelector::new(0, PrivilegeLevel::Ring0); MAX_CORES];
 
@@ -97,8 +99,8 @@ lazy_static! {
 /// Collection of segment selectors for kernel and user segments, plus TSS selectors.
 #[derive(Debug)]
 pub struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
     pub user_code_selector: SegmentSelector,
     pub user_data_selector: SegmentSelector,
     tss_selectors: [SegmentSelector; MAX_CORES],
@@ -129,3 +131,16 @@ pub fn init(cpu_id: u32) {
         load_tss(GDT.1.tss_selectors[cpu_id as usize]);
     }
 }
+
+/// Returns the top of the ring 0 stack used on privilege changes for a core.
+///
+/// # Arguments
+/// * `cpu_id` - ID of the CPU whose stack to look up
+///
+/// # Panics
+/// Panics if cpu_id >= MAX_CORES
+pub fn privilege_stack_top(cpu_id: u32) -> VirtAddr {
+    assert!(cpu_id < MAX_CORES as u32, "CPU ID exceeds MAX_CORES");
+
+    TSSS[cpu_id as usize].privilege_stack_table[0]
+}
@@ -11,35 +11,50 @@ use core::arch::naked_asm;
 use lazy_static::lazy_static;
 use x86_64::{
     instructions::interrupts,
-    structures::{
-        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-        paging::{OffsetPageTable, Page, PageTable},
-    },
-    VirtAddr,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    PrivilegeLevel, VirtAddr,
 };
 
 use crate::{
     constants::{
-        idt::{SYSCALL_HANDLER, TIMER_VECTOR, TLB_SHOOTDOWN_VECTOR},
-        syscalls::{SYSCALL_EXIT, SYSCALL_NANOSLEEP, SYSCALL_PRINT},
+        idt::{SYSCALL_HANDLER, TIMER_VECTOR, TLB_SHOOTDOWN_VECTOR, WAKEUP_VECTOR},
+        memory::USER_SPACE_END,
+        signals::{SIGBUS, SIGFPE, SIGILL, SIGSEGV},
+    },
+    events::runner_timer_fired,
+    interrupts::x2apic,
+    memory::{
+        cow::handle_cow_fault,
+        tlb::flush_pending_shootdown,
+        vma::{handle_vma_fault, Access},
     },
-    events::inc_runner_clock,
-    interrupts::x2apic::{self, current_core_id, TLB_SHOOTDOWN_ADDR},
-    memory::{paging::create_mapping, HHDM_OFFSET},
     prelude::*,
-    processes::process::preempt_process,
-    syscalls::syscall_handlers::{sys_exit, sys_nanosleep},
+    processes::{
+        accounting::charge_ticks,
+        process::{current_process, kill_faulting_process, preempt_process, Fault},
+    },
+    syscalls::{self, SyscallArgs},
 };
 
 lazy_static! {
     /// The system's Interrupt Descriptor Table.
     /// Contains handlers for:
-    /// - CPU exceptions (breakpoint, page fault, double fault)
+    /// - CPU exceptions (faults, breakpoint, page fault, double fault)
     /// - Timer interrupts
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -50,6 +65,7 @@ lazy_static! {
             .set_handler_fn(naked_syscall_handler)
             .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
         idt[TLB_SHOOTDOWN_VECTOR].set_handler_fn(tlb_shootdown_handler);
+        idt[WAKEUP_VECTOR].set_handler_fn(wakeup_handler);
         idt
     };
 }
@@ -113,6 +129,104 @@ where
     result
 }
 
+/// Whether an exception was raised by code running in ring 3
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Kills the faulting process with `sig` if the exception came from ring 3.
+/// An exception in the kernel is a bug, so it panics.
+fn handle_fault(
+    stack_frame: &InterruptStackFrame,
+    exception: &'static str,
+    error_code: Option<u64>,
+    sig: u32,
+) {
+    if !from_user_mode(stack_frame) {
+        panic!(
+            "EXCEPTION: {}\nError Code: {:?}\n{:#?}",
+            exception, error_code, stack_frame
+        );
+    }
+
+    kill_faulting_process(
+        Fault {
+            exception,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            error_code,
+            address: None,
+        },
+        sig,
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "DIVIDE ERROR", None, SIGFPE);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "OVERFLOW", None, SIGSEGV);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "BOUND RANGE EXCEEDED", None, SIGSEGV);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "INVALID OPCODE", None, SIGILL);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    handle_fault(
+        &stack_frame,
+        "SEGMENT NOT PRESENT",
+        Some(error_code),
+        SIGBUS,
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    handle_fault(
+        &stack_frame,
+        "STACK SEGMENT FAULT",
+        Some(error_code),
+        SIGBUS,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    handle_fault(
+        &stack_frame,
+        "GENERAL PROTECTION FAULT",
+        Some(error_code),
+        SIGSEGV,
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "x87 FLOATING POINT", None, SIGFPE);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    handle_fault(&stack_frame, "ALIGNMENT CHECK", Some(error_code), SIGBUS);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    handle_fault(&stack_frame, "SIMD FLOATING POINT", None, SIGFPE);
+}
+
 /// Handles breakpoint exceptions by printing debug information.
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
@@ -126,23 +240,53 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-/// Handles page fault exceptions by printing fault information.
+/// Handles page fault exceptions.
+/// Resolves writes to copy-on-write pages and first accesses to pages of a
+/// process' memory areas. Any other fault from ring 3 kills the process; a
+/// kernel fault prints fault information and panics.
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::{Cr2, Cr3};
+    use x86_64::registers::control::Cr2;
 
     let faulting_address = Cr2::read().expect("Cannot read faulting address").as_u64();
-    let pml4 = Cr3::read().0;
-    let new_pml4_phys = pml4.start_address();
-    let new_pml4_virt = VirtAddr::new((*HHDM_OFFSET).as_u64()) + new_pml4_phys.as_u64();
-    let new_pml4_ptr: *mut PageTable = new_pml4_virt.as_mut_ptr();
 
-    let mut mapper =
-        unsafe { OffsetPageTable::new(&mut *new_pml4_ptr, VirtAddr::new((*HHDM_OFFSET).as_u64())) };
+    if from_user_mode(&stack_frame) {
+        let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        if let Some(process) = current_process() {
+            let pcb = unsafe { &*process.pcb.get() };
+            let mut vmas = pcb.lock_vmas();
+            let cow_write = error_code.contains(
+                PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+            );
+            if cow_write
+                && faulting_address < USER_SPACE_END
+                && handle_cow_fault(pcb.pml4_frame, VirtAddr::new(faulting_address))
+            {
+                return;
+            }
+            if handle_vma_fault(pcb, &mut vmas, faulting_address, access) {
+                return;
+            }
+        }
 
-    let stack_pointer = stack_frame.stack_pointer.as_u64();
+        kill_faulting_process(
+            Fault {
+                exception: "PAGE FAULT",
+                rip: stack_frame.instruction_pointer.as_u64(),
+                error_code: Some(error_code.bits()),
+                address: Some(faulting_address),
+            },
+            SIGSEGV,
+        );
+    }
 
     serial_println!(
         "EXCEPTION: PAGE FAULT\nFaulting Address: {:?}\nError Code: {:X}\n{:#?}",
@@ -151,13 +295,6 @@ extern "x86-interrupt" fn page_fault_handler(
         stack_frame
     );
 
-    let page = Page::containing_address(VirtAddr::new(faulting_address));
-
-    // check for stack growth
-    if stack_pointer - 64 <= faulting_address && faulting_address < (*HHDM_OFFSET).as_u64() {
-        create_mapping(page, &mut mapper, None);
-    }
-
     panic!("PAGE FAULT!");
 }
 
@@ -210,35 +347,19 @@ pub extern "x86-interrupt" fn naked_syscall_handler(_: InterruptStackFrame) {
     }
 }
 
-// This is the actual syscall handler function that reads the registers from the stack
+/// Common handler for `int 0x80` and `syscall`.
+/// Reads the syscall from the saved registers, dispatches it through the
+/// syscall table and writes the result back into the saved `rax`.
 #[no_mangle]
-#[allow(unused_variables, unused_assignments)] // disable until args p2-6 are used
 fn syscall_handler(rsp: u64) {
-    let syscall_num: u64;
-    let p1: u64;
-    let p2: u64;
-    let p3: u64;
-    let p4: u64;
-    let p5: u64;
-    let p6: u64;
-    let stack_ptr: *const u64 = rsp as *const u64;
+    let (syscall_num, args) = unsafe { SyscallArgs::from_frame(rsp) };
+
+    let ret = syscalls::dispatch(syscall_num, &args);
+
     unsafe {
-        syscall_num = *stack_ptr.add(0);
-        p1 = *stack_ptr.add(5);
-        p2 = *stack_ptr.add(4);
-        p3 = *stack_ptr.add(3);
-        p4 = *stack_ptr.add(8);
-        p5 = *stack_ptr.add(6);
-        p6 = *stack_ptr.add(7);
+        syscalls::set_return_value(rsp, ret);
     }
 
-    match syscall_num as u32 {
-        SYSCALL_EXIT => sys_exit(),
-        SYSCALL_PRINT => serial_println!("Hello world!"),
-        SYSCALL_NANOSLEEP => sys_nanosleep(p1, rsp),
-        _ => panic!("Unknown syscall: {}", syscall_num),
-    };
-
     x2apic::send_eoi();
 }
 
@@ -289,9 +410,12 @@ extern "x86-interrupt" fn naked_timer_handler(_: InterruptStackFrame) {
     }
 }
 
+// The timer is one-shot, armed for the next tick while the core has work and
+// otherwise only for sleepers, so an idle core is not woken every tick
 #[no_mangle]
 fn timer_handler(rsp: u64) {
-    inc_runner_clock();
+    let ticks = runner_timer_fired();
+    charge_ticks(rsp, ticks);
 
     preempt_process(rsp);
 
@@ -303,16 +427,12 @@ fn timer_handler(rsp: u64) {
 // priority to fix
 #[no_mangle]
 extern "x86-interrupt" fn tlb_shootdown_handler(_: InterruptStackFrame) {
-    let core = current_core_id();
-    {
-        let mut addresses = TLB_SHOOTDOWN_ADDR.lock();
-        let vaddr_to_invalidate = addresses[core];
-        if vaddr_to_invalidate != 0 {
-            unsafe {
-                core::arch::asm!("invlpg [{}]", in (reg) vaddr_to_invalidate, options(nostack, preserves_flags));
-            }
-            addresses[core] = 0;
-        }
-    }
+    flush_pending_shootdown();
+    x2apic::send_eoi();
+}
+
+// Sent by another core that queued work for this one while it was idle. The
+// interrupt itself ends the halt, so the run loop looks for work again.
+extern "x86-interrupt" fn wakeup_handler(_: InterruptStackFrame) {
     x2apic::send_eoi();
 }
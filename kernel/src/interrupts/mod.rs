@@ -4,12 +4,14 @@
 //! - Global Descriptor Table (GDT)
 //! - Interrupt Descriptor Table (IDT)
 //! - Advanced Programmable Interrupt Controller (x2APIC)
+//! - The TSC clock, calibrated along with the x2APIC timer
 //! - Exception handlers and interrupt handling
 
 use crate::constants::x2apic::CPU_FREQUENCY;
 
 pub mod gdt;
 pub mod idt;
+pub mod tsc;
 pub mod x2apic;
 
 /// Initialize interrupt handling for a CPU core.
@@ -0,0 +1,156 @@
+//! Invariant TSC clocksource
+//!
+//! - Calibrated against the PIT, together with the x2APIC timer
+//! - Synchronized across cores at boot: each AP measures its offset from the
+//!   BSP's TSC as both leave the boot barrier
+//! - Read as nanoseconds since calibration with `monotonic_nanos`, and as a
+//!   `Timespec` with `clock_gettime`
+//!
+//! The TSC must be invariant, ticking at a constant rate in every power state,
+//! for the clock to hold; QEMU needs `+invtsc` for that.
+
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+
+use crate::{
+    constants::{
+        errno::EINVAL,
+        syscalls::{CLOCK_BOOTTIME, CLOCK_MONOTONIC},
+        MAX_CORES,
+    },
+    interrupts::x2apic::current_core_id,
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// TSC ticks per second, 0 until calibrated
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// BSP TSC reading the clock counts from
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Added to each core's TSC to line it up with the BSP's
+static TSC_OFFSETS: [AtomicI64; MAX_CORES] = [const { AtomicI64::new(0) }; MAX_CORES];
+/// BSP TSC reading published as APs are released from the boot barrier
+static SYNC_REFERENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time, laid out like `struct timespec`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self {
+            tv_sec: (nanos / NANOS_PER_SEC) as i64,
+            tv_nsec: (nanos % NANOS_PER_SEC) as i64,
+        }
+    }
+}
+
+/// Whether the TSC ticks at a constant rate and can serve as the clock
+pub fn is_invariant() -> bool {
+    CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|info| info.has_invariant_tsc())
+}
+
+/// Reads the current core's TSC
+#[inline(always)]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Sets the TSC frequency and starts the clock
+///
+/// # Arguments
+/// * `ticks` - TSC ticks counted over `nanos` nanoseconds
+/// * `now` - TSC reading the clock starts from
+pub fn calibrate(ticks: u64, nanos: u64, now: u64) {
+    let hz = ticks as u128 * NANOS_PER_SEC as u128 / nanos.max(1) as u128;
+    TSC_HZ.store(hz as u64, Ordering::Relaxed);
+    TSC_BASE.store(now, Ordering::Release);
+}
+
+/// TSC ticks per second, 0 until calibrated
+pub fn frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Publishes the BSP's TSC for APs to synchronize to; called right before
+/// they are released from the boot barrier
+pub fn publish_sync_reference() {
+    SYNC_REFERENCE.store(read(), Ordering::SeqCst);
+}
+
+/// Lines the current AP's TSC up with the BSP's; called right after it is
+/// released from the boot barrier
+///
+/// The error is the time the release takes to reach the AP, well under a
+/// microsecond.
+pub fn sync_current_core() {
+    let offset = SYNC_REFERENCE.load(Ordering::SeqCst) as i64 - read() as i64;
+    TSC_OFFSETS[current_core_id()].store(offset, Ordering::Relaxed);
+}
+
+/// The current core's TSC, lined up with the BSP's
+pub fn synchronized_tsc() -> u64 {
+    let offset = TSC_OFFSETS[current_core_id()].load(Ordering::Relaxed);
+    read().wrapping_add_signed(offset)
+}
+
+/// Converts a clock reading into a TSC value of the current core
+pub fn nanos_to_tsc(nanos: u64) -> u64 {
+    let ticks = nanos as u128 * frequency() as u128 / NANOS_PER_SEC as u128;
+    let offset = TSC_OFFSETS[current_core_id()].load(Ordering::Relaxed);
+    let tsc = (TSC_BASE.load(Ordering::Acquire) as u128 + ticks).min(u64::MAX as u128) as u64;
+    tsc.wrapping_add_signed(-offset)
+}
+
+/// Reads the clock, which is the same on every core
+///
+/// # Returns
+/// Nanoseconds since the clock was calibrated, 0 before
+pub fn monotonic_nanos() -> u64 {
+    let hz = frequency();
+    if hz == 0 {
+        return 0;
+    }
+    let elapsed = synchronized_tsc().saturating_sub(TSC_BASE.load(Ordering::Acquire));
+    (elapsed as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64
+}
+
+/// Reads a clock
+///
+/// # Arguments
+/// * `clock` - `CLOCK_MONOTONIC` or `CLOCK_BOOTTIME`, which are the same
+///   clock as the system never suspends
+///
+/// # Returns
+/// The time on the clock, or `EINVAL` for any other clock
+pub fn clock_gettime(clock: u64) -> Result<Timespec, i64> {
+    match clock {
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => Ok(Timespec::from_nanos(monotonic_nanos())),
+        _ => Err(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_monotonic_clock() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            assert!(frequency() > 0);
+            let before = clock_gettime(CLOCK_MONOTONIC).unwrap();
+            let after = clock_gettime(CLOCK_MONOTONIC).unwrap();
+            assert!(before <= after);
+            assert!((0..1_000_000_000).contains(&after.tv_nsec));
+
+            assert_eq!(clock_gettime(0x1234), Err(EINVAL));
+        }
+    }
+}
@@ -1,13 +1,19 @@
 //! x2APIC (Advanced Programmable Interrupt Controller) management.
 //!
 //! - Allows for x2APIC initialization for both BSP and AP cores
-//! - Provides timer configuration and calibration using PIT
+//! - Provides timer configuration and calibration using PIT, which also
+//!   calibrates the TSC clock
+//! - Runs the timer in TSC-deadline mode where supported, one-shot mode
+//!   otherwise, armed for each core's next deadline on the TSC clock
 //! - Delivers inter-processor interrupt (IPI) support
 //! - Timer masking/unmasking
 //! - End-of-interrupt (EOI) handling
 
-use crate::constants::{idt::TIMER_VECTOR, x2apic::NS_PER_TICK, MAX_CORES};
-use core::sync::atomic::{AtomicU32, Ordering};
+use crate::{
+    constants::{idt::TIMER_VECTOR, x2apic::NS_PER_TICK, MAX_CORES},
+    interrupts::tsc,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use raw_cpuid::CpuId;
 use spin::Mutex;
 use x86_64::{instructions::port::Port, registers::model_specific::Msr};
@@ -23,6 +29,10 @@ const X2APIC_LVT_TIMER: u32 = 0x832;
 const X2APIC_TIMER_ICR: u32 = 0x838;
 const X2APIC_TIMER_CCR: u32 = 0x839;
 const X2APIC_TIMER_DCR: u32 = 0x83E;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT timer modes
+const TIMER_MODE_TSC_DEADLINE: u64 = 0b10 << 17;
 
 /// Programmable Interval Timer (PIT) constants for timer calibration
 const PIT_FREQUENCY: u64 = 1_193_182;
@@ -62,8 +72,20 @@ pub enum PitError {
 static mut APIC_MANAGER: X2ApicManager = X2ApicManager::new();
 /// Stores calibrated timer count value shared between cores
 static CALIBRATED_TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
-/// Global to manage what addresses to invalidate when shootdowns happen
-pub static TLB_SHOOTDOWN_ADDR: Mutex<[u64; MAX_CORES]> = Mutex::new([0; MAX_CORES]);
+/// Global to manage what ranges of addresses to invalidate when shootdowns happen,
+/// as start and end per page size (4 KiB, 2 MiB, 1 GiB), empty when nothing is pending
+pub static TLB_SHOOTDOWN_RANGES: Mutex<[[(u64, u64); 3]; MAX_CORES]> =
+    Mutex::new([[(0, 0); 3]; MAX_CORES]);
+/// Whether timers run in TSC-deadline mode rather than one-shot mode
+static TSC_DEADLINE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Converts a duration into timer counts, rounding up and saturating at the
+/// largest count the timer takes
+fn nanos_to_counts(nanos: u64) -> u32 {
+    let per_tick = CALIBRATED_TIMER_COUNT.load(Ordering::Relaxed) as u128;
+    let counts = (nanos as u128 * per_tick).div_ceil(NS_PER_TICK as u128);
+    counts.min(u32::MAX as u128) as u32
+}
 
 /// Manages x2APIC instances for all CPU cores
 pub struct X2ApicManager {
@@ -123,22 +145,55 @@ impl X2ApicManager {
             .map_err(|_| X2ApicError::TimerError)
     }
 
-    /// Configures the timer for the current CPU core
+    /// Configures the timer for the current CPU core, first firing one
+    /// tick from now
     ///
     /// # Arguments
     /// * `counter` - Timer count value from calibration
     #[inline(always)]
     pub fn configure_timer_current_core(counter: u32) -> Result<(), X2ApicError> {
-        // Configure timer: Periodic mode (1 << 17), unmasked (0 << 16), vector 32
-        let timer_config = (1u64 << 17) | (TIMER_VECTOR as u64);
+        let tsc_deadline = TSC_DEADLINE_MODE.load(Ordering::Relaxed);
+        // Configure timer: TSC-deadline (0b10 << 17) or one-shot (0 << 17)
+        // mode, unmasked (0 << 16), vector 32
+        let timer_config = if tsc_deadline {
+            TIMER_MODE_TSC_DEADLINE | TIMER_VECTOR as u64
+        } else {
+            TIMER_VECTOR as u64
+        };
         unsafe {
             Msr::new(X2APIC_LVT_TIMER).write(timer_config);
-            Msr::new(X2APIC_TIMER_DCR).write(0xB); // Set divider to 1
-            Msr::new(X2APIC_TIMER_ICR).write(counter as u64);
+            if tsc_deadline {
+                Self::arm_timer(tsc::monotonic_nanos() + NS_PER_TICK);
+            } else {
+                Msr::new(X2APIC_TIMER_DCR).write(0xB); // Set divider to 1
+                Msr::new(X2APIC_TIMER_ICR).write(counter as u64);
+            }
         }
         Ok(())
     }
 
+    /// Arms the current core's timer to fire once
+    ///
+    /// # Arguments
+    /// * `deadline` - When to fire, as read from `tsc::monotonic_nanos`. A
+    ///   deadline already passed fires right away. In one-shot mode, one
+    ///   further off than the timer can count fires as late as it can.
+    pub fn arm_timer(deadline: u64) {
+        if TSC_DEADLINE_MODE.load(Ordering::Relaxed) {
+            // Writing 0 would disarm the timer instead
+            let tsc = tsc::nanos_to_tsc(deadline).max(1);
+            unsafe {
+                Msr::new(IA32_TSC_DEADLINE).write(tsc);
+            }
+        } else {
+            let delay = deadline.saturating_sub(tsc::monotonic_nanos());
+            let count = nanos_to_counts(delay).max(1);
+            unsafe {
+                Msr::new(X2APIC_TIMER_ICR).write(count as u64);
+            }
+        }
+    }
+
     /// Sends EOI signal to acknowledge the current interrupt
     #[inline(always)]
     pub fn send_eoi() -> Result<(), X2ApicError> {
@@ -195,6 +250,11 @@ impl X2ApicManager {
         let count = Self::calibrate_timer(hz)?;
         CALIBRATED_TIMER_COUNT.store(count, Ordering::Release);
 
+        let tsc_deadline = CpuId::new()
+            .get_feature_info()
+            .is_some_and(|f| f.has_tsc_deadline());
+        TSC_DEADLINE_MODE.store(tsc_deadline && tsc::is_invariant(), Ordering::Relaxed);
+
         // Then initialize BSP's local APIC
         Self::initialize_current_core()?;
         Self::configure_timer_current_core(count)?;
@@ -302,6 +362,7 @@ impl Pit {
 
             self.channel_2.write((pit_divider & 0xFF) as u8);
             self.channel_2.write((pit_divider >> 8) as u8);
+            let tsc_start = tsc::read();
 
             let mut last = self.control.read() & 0x20;
             let mut changes = 0;
@@ -314,8 +375,13 @@ impl Pit {
                 }
             }
 
+            let tsc_end = tsc::read();
             self.control.write(0);
 
+            // 40 output changes are 20 PIT periods
+            let nanos = 20 * pit_divider as u64 * 1_000_000_000 / PIT_FREQUENCY;
+            tsc::calibrate(tsc_end - tsc_start, nanos, tsc_end);
+
             // Calculate ticks
             let final_count = Msr::new(X2APIC_TIMER_CCR).read() as u32;
             let diff = initial - final_count;
@@ -346,6 +412,12 @@ pub fn current_core_id() -> usize {
     X2ApicManager::current_core_id()
 }
 
+/// Whether a core has initialized its x2APIC and so takes interrupts
+pub fn core_online(core: usize) -> bool {
+    let apics = unsafe { &(*core::ptr::addr_of!(APIC_MANAGER)).apics };
+    apics.get(core).is_some_and(Option::is_some)
+}
+
 /// Send IPI to a specific core
 #[inline(always)]
 pub fn send_ipi(target_id: u32, vector: u8) {
@@ -364,7 +436,8 @@ pub fn unmask_timer() {
     X2ApicManager::unmask_timer().expect("Failed to unmask timer");
 }
 
+/// Arm the current core's timer to fire once at `deadline` on the TSC clock
 #[inline(always)]
-pub fn nanos_to_ticks(nanos: u64) -> u64 {
-    nanos / NS_PER_TICK
+pub fn arm_timer(deadline: u64) {
+    X2ApicManager::arm_timer(deadline)
 }
@@ -50,4 +50,5 @@ pub enum Error {
     NotFound,
     AlreadyMounted,
     NotMount,
+    TooManyOpenFiles,
 }
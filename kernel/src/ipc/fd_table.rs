@@ -1,5 +1,9 @@
-use super::error::Error;
-use alloc::collections::BTreeMap;
+use super::{
+    error::Error, messages::Message, mnt_manager, mount_manager::MountId, pipe::PipeEnd,
+    requests::Tclunk,
+};
+use crate::{constants::syscalls::SYSCALL_IO_PRIORITY, events::schedule_kernel};
+use alloc::{collections::BTreeMap, sync::Arc};
 use bitflags::bitflags;
 use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
@@ -22,7 +26,7 @@ pub enum QidType {
 }
 
 bitflags! {
-    #[derive(Clone)]
+    #[derive(Debug, Clone)]
     pub struct OpenFlags: u32 {
         const READ = 0x01;
         const WRITE = 0x02;
@@ -33,25 +37,44 @@ bitflags! {
     }
 }
 
+/// A process' open files. Descriptors refer to shared open files, so a
+/// forked table shares offsets with the original.
+#[derive(Debug)]
 pub struct FdTable {
-    fds: BTreeMap<usize, FileDesc>,
+    fds: BTreeMap<usize, Arc<FileDesc>>,
     next_fd: AtomicUsize,
 }
 
+/// What a file descriptor refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A file on a mount, accessed over 9P through `mount_id` and `fid`
+    Mount,
+    /// The serial console
+    Console,
+    /// One end of a pipe, held in `pipe`
+    Pipe,
+}
+
+#[derive(Debug)]
 pub struct FileDesc {
+    pub kind: FileKind,
     pub mount_id: usize,
     pub fid: u32,
     pub flags: OpenFlags,
     pub offset: AtomicU64,
+    pub pipe: Option<PipeEnd>,
 }
 
 impl Clone for FileDesc {
     fn clone(&self) -> Self {
         Self {
+            kind: self.kind,
             mount_id: self.mount_id,
             fid: self.fid,
             flags: self.flags.clone(),
             offset: AtomicU64::new(self.offset.load(Ordering::Relaxed)),
+            pipe: self.pipe.clone(),
         }
     }
 }
@@ -64,32 +87,108 @@ impl FdTable {
         }
     }
 
-    pub fn allocate(&mut self, mount_id: usize, fid: u32, flags: OpenFlags) -> usize {
+    /// Creates a table with stdin, stdout and stderr (fds 0, 1 and 2) on the
+    /// serial console
+    pub fn with_stdio() -> Self {
+        let mut table = Self::new();
+        table.allocate_console(OpenFlags::READ);
+        table.allocate_console(OpenFlags::WRITE);
+        table.allocate_console(OpenFlags::WRITE);
+        table
+    }
+
+    /// Opens a descriptor for a file on a mount
+    ///
+    /// * `limit`: most descriptors the table may hold
+    pub fn allocate(
+        &mut self,
+        mount_id: usize,
+        fid: u32,
+        flags: OpenFlags,
+        limit: u64,
+    ) -> Result<usize, Error> {
+        if self.open_count() as u64 >= limit {
+            return Err(Error::TooManyOpenFiles);
+        }
+        Ok(self.insert(FileDesc {
+            kind: FileKind::Mount,
+            mount_id,
+            fid,
+            flags,
+            offset: AtomicU64::new(0),
+            pipe: None,
+        }))
+    }
+
+    pub fn allocate_console(&mut self, flags: OpenFlags) -> usize {
+        self.insert(FileDesc {
+            kind: FileKind::Console,
+            mount_id: 0,
+            fid: 0,
+            flags,
+            offset: AtomicU64::new(0),
+            pipe: None,
+        })
+    }
+
+    /// Opens a descriptor for one end of a pipe, readable or writable
+    /// depending on the end
+    ///
+    /// * `limit`: most descriptors the table may hold
+    pub fn allocate_pipe(&mut self, end: PipeEnd, limit: u64) -> Result<usize, Error> {
+        if self.open_count() as u64 >= limit {
+            return Err(Error::TooManyOpenFiles);
+        }
+        let flags = if end.is_write_end() {
+            OpenFlags::WRITE
+        } else {
+            OpenFlags::READ
+        };
+        Ok(self.insert(FileDesc {
+            kind: FileKind::Pipe,
+            mount_id: 0,
+            fid: 0,
+            flags,
+            offset: AtomicU64::new(0),
+            pipe: Some(end),
+        }))
+    }
+
+    fn insert(&mut self, desc: FileDesc) -> usize {
         let fd = self.next_fd.fetch_add(1, Ordering::Relaxed);
-        self.fds.insert(
-            fd,
-            FileDesc {
-                mount_id,
-                fid,
-                flags,
-                offset: AtomicU64::new(0),
-            },
-        );
+        self.fds.insert(fd, Arc::new(desc));
         fd
     }
 
-    pub fn get(&self, fd: usize) -> Option<&FileDesc> {
-        self.fds.get(&fd)
+    /// Number of open descriptors
+    pub fn open_count(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Returns the open file behind a descriptor, which stays open while
+    /// the caller holds it, even if the descriptor is closed
+    pub fn get(&self, fd: usize) -> Option<Arc<FileDesc>> {
+        self.fds.get(&fd).cloned()
     }
 
     pub fn get_offset(&self, fd: usize) -> Option<u64> {
         self.fds.get(&fd).map(|f| f.offset.load(Ordering::Relaxed))
     }
 
-    pub fn remove(&mut self, fd: usize) -> Option<FileDesc> {
+    /// Removes a descriptor. The open file is only closed once no other
+    /// table refers to it, which the caller can check with `Arc::into_inner`.
+    pub fn remove(&mut self, fd: usize) -> Option<Arc<FileDesc>> {
         self.fds.remove(&fd)
     }
 
+    /// Removes every descriptor, e.g. when the process exits. Pipe ends are
+    /// closed and mount fids clunked once no other table refers to them.
+    pub fn clear(&mut self) {
+        for desc in core::mem::take(&mut self.fds).into_values() {
+            release_file(desc);
+        }
+    }
+
     pub fn update_offset(&self, fd: usize, new_offset: u64) -> Result<(), Error> {
         if let Some(file) = self.fds.get(&fd) {
             file.offset.store(new_offset, Ordering::Relaxed);
@@ -99,3 +198,42 @@ impl FdTable {
         }
     }
 }
+
+/// Drops a removed descriptor, clunking its fid in the background if it was
+/// the last reference to a file on a mount. Pipe ends close when dropped.
+pub fn release_file(desc: Arc<FileDesc>) {
+    if desc.kind != FileKind::Mount {
+        return;
+    }
+    let Some(desc) = Arc::into_inner(desc) else {
+        return;
+    };
+    schedule_kernel(
+        async move {
+            let _ = clunk(desc.mount_id, desc.fid).await;
+        },
+        SYSCALL_IO_PRIORITY,
+    );
+}
+
+/// Tells a mount that a fid is no longer used
+pub async fn clunk(mount_id: usize, fid: u32) -> Result<(), Error> {
+    let tclunk = Tclunk::new(0, fid).map_err(|_| Error::Protocol)?;
+    let response = mnt_manager
+        .send_request(MountId(mount_id as u32), fid, Message::Tclunk(tclunk))
+        .await?;
+    match response {
+        Message::Rclunk(_) => Ok(()),
+        _ => Err(Error::Protocol),
+    }
+}
+
+impl Clone for FdTable {
+    /// Duplicates the table, sharing every open file
+    fn clone(&self) -> Self {
+        Self {
+            fds: self.fds.clone(),
+            next_fd: AtomicUsize::new(self.next_fd.load(Ordering::Relaxed)),
+        }
+    }
+}
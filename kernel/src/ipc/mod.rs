@@ -7,6 +7,7 @@ pub mod fd_table;
 pub mod messages;
 pub mod mount_manager;
 pub mod namespace;
+pub mod pipe;
 pub mod requests;
 pub mod responses;
 pub mod serialization;
@@ -9,11 +9,14 @@ use crate::{
     serial_println,
 };
 use alloc::{collections::BTreeMap, sync::Arc};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
 use futures::channel::oneshot;
 use spin::Mutex;
 
+/// Offset of the tag in a serialized message header (after size[4] and type[1])
+const TAG_OFFSET: usize = 5;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct MountId(pub u32);
 
@@ -77,8 +80,12 @@ impl Mount {
             .lock()
             .insert(tag, PendingRequest { response_tx });
 
+        // Responses are matched on the tag, so stamp ours over the caller's
+        let mut request = BytesMut::from(&data.serialize().map_err(|_| Error::Protocol)?[..]);
+        request[TAG_OFFSET..TAG_OFFSET + 2].copy_from_slice(&tag.to_le_bytes());
+
         self.tx
-            .send(data.serialize().unwrap())
+            .send(request.freeze())
             .await
             .map_err(|_| Error::ChannelFull)?;
 
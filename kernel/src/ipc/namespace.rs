@@ -5,11 +5,11 @@ use super::{
     mount_manager::MountId,
     requests::{Tattach, Tclunk, Twalk},
 };
-use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use bytes::Bytes;
 use core::sync::atomic::{AtomicU32, Ordering};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DirEntry {
     name: String,
     mount_id: Option<usize>,
@@ -25,11 +25,23 @@ pub struct PathResolution {
 #[derive(Debug)]
 pub struct Namespace {
     root: DirEntry,
-    next_fid: AtomicU32,
+    /// Shared by every copy of the namespace, as they talk to the same
+    /// mounts and must not hand out the same fid twice
+    next_fid: Arc<AtomicU32>,
 }
 
 const ROOTFID: u32 = 0;
 
+impl Clone for Namespace {
+    /// Copies the mount table, e.g. for a forked process
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            next_fid: self.next_fid.clone(),
+        }
+    }
+}
+
 impl Namespace {
     pub fn new() -> Self {
         Self {
@@ -38,7 +50,7 @@ impl Namespace {
                 mount_id: None,
                 children: BTreeMap::new(),
             },
-            next_fid: AtomicU32::new(1), // 0 is reserved for root
+            next_fid: Arc::new(AtomicU32::new(1)), // 0 is reserved for root
         }
     }
 
@@ -0,0 +1,240 @@
+//! Pipes
+//!
+//! A pipe is a bounded byte buffer, kept in a `Channel<u8>`, shared by any
+//! number of read and write ends. Ends are counted so that readers see end of
+//! file once every write end is closed, and writers fail with `EPIPE` once
+//! every read end is.
+//!
+//! Reads and writes never wait themselves. They fail with `EAGAIN`, and the
+//! caller waits on `readable` or `writable` until `can_read` or `can_write`
+//! holds.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    constants::errno::{EAGAIN, EPIPE},
+    events::WaitQueue,
+    ipc::channel::{Channel, Receiver, RecvError, SendError, Sender},
+};
+
+#[derive(Debug)]
+pub struct Pipe {
+    sender: Sender<u8>,
+    receiver: Receiver<u8>,
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    /// Woken when data arrives or the last write end closes
+    pub readable: WaitQueue,
+    /// Woken when room frees up or the last read end closes
+    pub writable: WaitQueue,
+}
+
+impl Pipe {
+    /// Creates a pipe buffering up to `capacity` bytes
+    ///
+    /// # Returns
+    /// Its read end and write end
+    pub fn create(capacity: usize) -> (PipeEnd, PipeEnd) {
+        let (sender, receiver) = Channel::new(capacity);
+        let pipe = Arc::new(Self {
+            sender,
+            receiver,
+            readers: AtomicUsize::new(1),
+            writers: AtomicUsize::new(1),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        });
+        (
+            PipeEnd {
+                pipe: pipe.clone(),
+                write: false,
+            },
+            PipeEnd { pipe, write: true },
+        )
+    }
+
+    /// Moves buffered bytes into `buf`
+    ///
+    /// # Returns
+    /// The number of bytes read, 0 at end of file, or `EAGAIN` if the pipe
+    /// is empty but still has writers
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, i64> {
+        let mut read = 0;
+        for byte in buf.iter_mut() {
+            match self.receiver.try_recv() {
+                Ok(value) => *byte = value,
+                Err(RecvError::Empty) if read == 0 => return Err(EAGAIN),
+                Err(_) => break,
+            }
+            read += 1;
+        }
+        if read > 0 {
+            self.writable.wake_all();
+        }
+        Ok(read)
+    }
+
+    /// Buffers as much of `data` as there is room for
+    ///
+    /// # Returns
+    /// The number of bytes written, `EAGAIN` if the pipe is full, or `EPIPE`
+    /// if every read end is closed
+    pub fn write(&self, data: &[u8]) -> Result<usize, i64> {
+        if self.readers.load(Ordering::Acquire) == 0 {
+            return Err(EPIPE);
+        }
+        let mut written = 0;
+        for &byte in data {
+            match self.sender.try_send(byte) {
+                Ok(()) => written += 1,
+                Err(SendError::Full(_)) if written == 0 => return Err(EAGAIN),
+                Err(_) => break,
+            }
+        }
+        if written > 0 {
+            self.readable.wake_all();
+        }
+        Ok(written)
+    }
+
+    /// Whether a read would not fail with `EAGAIN`
+    pub fn can_read(&self) -> bool {
+        !self.receiver.is_empty() || self.receiver.is_closed()
+    }
+
+    /// Whether a write would not fail with `EAGAIN`
+    pub fn can_write(&self) -> bool {
+        !self.receiver.is_full() || self.readers.load(Ordering::Acquire) == 0
+    }
+
+    /// Number of buffered bytes
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+/// One end of a pipe, held by a file descriptor. Cloning opens another end
+/// of the same kind, and dropping the last end of a kind wakes the other side.
+#[derive(Debug)]
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    write: bool,
+}
+
+impl PipeEnd {
+    pub fn pipe(&self) -> &Pipe {
+        &self.pipe
+    }
+
+    pub fn is_write_end(&self) -> bool {
+        self.write
+    }
+
+    fn count(&self) -> &AtomicUsize {
+        if self.write {
+            &self.pipe.writers
+        } else {
+            &self.pipe.readers
+        }
+    }
+}
+
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        self.count().fetch_add(1, Ordering::AcqRel);
+        Self {
+            pipe: self.pipe.clone(),
+            write: self.write,
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        if self.count().fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        if self.write {
+            self.pipe.sender.close();
+            self.pipe.readable.wake_all();
+        } else {
+            self.pipe.writable.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_pipe_read_write() -> impl Future<Output = ()> + Send + 'static {
+        let (reader, writer) = Pipe::create(4);
+
+        async move {
+            let pipe = reader.pipe();
+            let mut buf = [0u8; 8];
+            assert_eq!(pipe.read(&mut buf), Err(EAGAIN));
+            assert!(!pipe.can_read());
+
+            // Only what fits is written
+            assert_eq!(writer.pipe().write(b"abcdef"), Ok(4));
+            assert_eq!(pipe.len(), 4);
+            assert!(!pipe.can_write());
+            assert_eq!(writer.pipe().write(b"ef"), Err(EAGAIN));
+
+            assert_eq!(pipe.read(&mut buf[..3]), Ok(3));
+            assert_eq!(&buf[..3], b"abc");
+            assert_eq!(writer.pipe().write(b"ef"), Ok(2));
+            assert_eq!(pipe.read(&mut buf), Ok(3));
+            assert_eq!(&buf[..3], b"def");
+        }
+    }
+
+    #[test_case]
+    fn test_pipe_close() -> impl Future<Output = ()> + Send + 'static {
+        let (reader, writer) = Pipe::create(16);
+
+        async move {
+            let second_writer = writer.clone();
+            assert_eq!(writer.pipe().write(b"hi"), Ok(2));
+            drop(writer);
+            assert!(!reader.pipe().receiver.is_closed());
+
+            // Buffered data is still read after the last writer closes
+            drop(second_writer);
+            let mut buf = [0u8; 4];
+            assert!(reader.pipe().can_read());
+            assert_eq!(reader.pipe().read(&mut buf), Ok(2));
+            assert_eq!(reader.pipe().read(&mut buf), Ok(0));
+
+            let (reader, writer) = Pipe::create(16);
+            drop(reader);
+            assert!(writer.pipe().can_write());
+            assert_eq!(writer.pipe().write(b"lost"), Err(EPIPE));
+        }
+    }
+
+    #[test_case]
+    fn test_pipe_wait_until_readable() -> impl Future<Output = ()> + Send + 'static {
+        let (reader, writer) = Pipe::create(16);
+
+        async move {
+            let pipe = reader.pipe();
+            let read = pipe.readable.wait_until(|| {
+                let mut buf = [0u8; 4];
+                pipe.read(&mut buf).ok()
+            });
+
+            assert_eq!(writer.pipe().write(b"wake"), Ok(4));
+            assert_eq!(read.await, 4);
+            assert!(pipe.readable.is_empty());
+        }
+    }
+}
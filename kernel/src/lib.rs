@@ -12,7 +12,7 @@ extern crate alloc;
 
 use alloc::boxed::Box;
 use core::{future::Future, pin::Pin};
-use events::schedule_kernel;
+use events::{core_mask, schedule_kernel_with_affinity};
 use x86_64::instructions::hlt;
 
 pub mod constants;
@@ -97,7 +97,9 @@ pub fn test_runner(tests: &[&(dyn Testable + Send + Sync)]) {
         exit_qemu(QemuExitCode::Success);
     };
 
-    schedule_kernel(future, 1);
+    // Tests expect to stay on the core they started on
+    let cpuid = interrupts::x2apic::current_core_id() as u32;
+    schedule_kernel_with_affinity(future, 1, core_mask(cpuid));
 }
 
 pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
@@ -2,6 +2,7 @@
 //!
 //! - Another allocator kernel switches into once kernel heap is initialized
 //! - Represents each frame in physical memory as a bit and stores metadata to check against memory leaks
+//! - Hands out physically contiguous, aligned runs of frames below an address limit, e.g. for DMA
 use crate::{
     constants::memory::{BITMAP_ENTRY_SIZE, FRAME_SIZE, FULL_BITMAP_ENTRY},
     serial_println,
@@ -195,6 +196,88 @@ impl BitmapFrameAllocator {
         (self.bitmap[byte_index] & mask) != 0
     }
 
+    /// Allocates physically contiguous frames
+    ///
+    /// # Arguments:
+    /// * 'count' - number of frames to allocate
+    /// * 'align' - alignment of the first frame in bytes, a power of two; frames
+    ///   are always frame aligned
+    /// * 'max_phys' - address every allocated byte must lie below
+    ///
+    /// # Returns:
+    /// The first frame of the run, or None if no free run fits
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_phys: u64,
+    ) -> Option<PhysFrame> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let align_frames = (align / FRAME_SIZE).max(1);
+        let limit = self
+            .total_frames
+            .min((max_phys / FRAME_SIZE as u64) as usize);
+
+        let mut start = 0;
+        while start + count <= limit {
+            match self.last_used_in(start, start + count) {
+                // No run starting at or before a used frame can fit
+                Some(used) => start = (used + 1).next_multiple_of(align_frames),
+                None => {
+                    for frame_index in start..start + count {
+                        self.set_bit(frame_index);
+                    }
+                    self.allocate_count += count;
+                    let addr = start * FRAME_SIZE;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr as u64)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees a run of frames allocated by `alloc_contiguous`
+    ///
+    /// # Arguments:
+    /// * 'start' - first frame of the run
+    /// * 'count' - number of frames in the run
+    pub fn free_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = start.start_address().as_u64() as usize / FRAME_SIZE;
+        for frame_index in first..first + count {
+            assert!(
+                self.is_bit_set(frame_index),
+                "Trying to double free a frame!"
+            );
+            self.clear_bit(frame_index);
+        }
+        self.free_count += count;
+    }
+
+    /// Finds the last used frame in [start, end), checking a bitmap entry at
+    /// a time
+    ///
+    /// # Returns:
+    /// Index of the frame, or None if all are free
+    fn last_used_in(&self, start: usize, end: usize) -> Option<usize> {
+        let mut range_end = end;
+        while range_end > start {
+            let entry = (range_end - 1) / BITMAP_ENTRY_SIZE;
+            let range_start = (entry * BITMAP_ENTRY_SIZE).max(start);
+
+            let low = range_start % BITMAP_ENTRY_SIZE;
+            let high = (range_end - 1) % BITMAP_ENTRY_SIZE;
+            let mask = (FULL_BITMAP_ENTRY << low) & (FULL_BITMAP_ENTRY >> (63 - high));
+            let used = self.bitmap[entry] & mask;
+            if used != 0 {
+                return Some(entry * BITMAP_ENTRY_SIZE + 63 - used.leading_zeros() as usize);
+            }
+            range_end = range_start;
+        }
+        None
+    }
+
     /// Prints the number of free frames in the bitmap
     pub fn print_bitmap_free_frames(&self) {
         serial_println!("Free frames: {:?}", self.free_frames);
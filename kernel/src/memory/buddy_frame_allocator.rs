@@ -0,0 +1,409 @@
+//! Buddy frame allocator
+//!
+//! - An alternative to the bitmap frame allocator, chosen at boot with
+//!   `frame_allocator=buddy` on the kernel command line
+//! - Hands out blocks of 2^order frames, up to 1 GiB, in O(log n): a larger
+//!   free block is split in halves down to the order asked for, and a freed
+//!   block merges with its buddy, the other half of the block they were split
+//!   from, for as long as the buddy is free too
+//! - Keeps the free blocks of each order in a doubly linked list threaded
+//!   through the free frames themselves, and in a bitmap per order so that
+//!   whether a buddy is free is known in O(1)
+use crate::{
+    constants::memory::{BITMAP_ENTRY_SIZE, BUDDY_MAX_ORDER, FRAME_SIZE},
+    memory::HHDM_OFFSET,
+    serial_println,
+};
+use limine::{memory_map::EntryType, response::MemoryMapResponse};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// Number of block sizes
+const ORDERS: usize = BUDDY_MAX_ORDER + 1;
+
+/// End of a free list
+const NONE: usize = usize::MAX;
+
+// Links of a free list, kept at the start of each free block
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    prev: usize,
+    next: usize,
+}
+
+// Free lists and metadata for the allocator
+pub struct BuddyFrameAllocator {
+    // Frames below the top of usable memory
+    total_frames: usize,
+    // Frames in free blocks
+    free_frames: usize,
+    // First frame of the first free block of each order
+    heads: [usize; ORDERS],
+    // Bit per block of each order, set while it is a free block
+    free_maps: Vec<Box<[u64]>>,
+    // Counter for how many total allocations done by allocator
+    allocate_count: usize,
+    // Counter for total amount of frees done by allocator
+    free_count: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator for frames below `total_frames` with none free
+    pub fn new(total_frames: usize) -> Self {
+        Self {
+            total_frames,
+            free_frames: 0,
+            heads: [NONE; ORDERS],
+            free_maps: (0..ORDERS)
+                .map(|order| {
+                    let blocks = (total_frames >> order) + 1;
+                    vec![0; blocks.div_ceil(BITMAP_ENTRY_SIZE)].into_boxed_slice()
+                })
+                .collect(),
+            allocate_count: 0,
+            free_count: 0,
+        }
+    }
+
+    /// Initializes free lists with the usable regions in physical memory.
+    ///
+    /// # Arguments:
+    /// * 'memory_map' - map from Limine telling which parts of physical memory are usable
+    /// * 'initial_frames' - iterator of frames from previous allocator to tell which frames are already in use
+    ///
+    /// # Safety
+    /// Unsafe due to requiring to interface directly with memory regions given by the bootloader
+    pub unsafe fn init(
+        memory_map: &'static MemoryMapResponse,
+        initial_frames: impl Iterator<Item = PhysFrame>,
+    ) -> Self {
+        let mut used: Vec<usize> = initial_frames.map(frame_index).collect();
+        used.sort_unstable();
+        used.dedup();
+
+        let usable = || {
+            memory_map
+                .entries()
+                .iter()
+                .filter(|entry| entry.entry_type == EntryType::USABLE)
+        };
+        let true_end = usable()
+            .map(|entry| (entry.base + entry.length) as usize)
+            .max()
+            .unwrap_or(0);
+        let mut allocator = Self::new(true_end.div_ceil(FRAME_SIZE));
+        for entry in usable() {
+            let start = (entry.base as usize).div_ceil(FRAME_SIZE);
+            let end = (entry.base + entry.length) as usize / FRAME_SIZE;
+
+            // Free what lies between the frames already in use
+            let first_used = used.partition_point(|&index| index < start);
+            let last_used = used.partition_point(|&index| index < end);
+            let mut free_from = start;
+            for &index in &used[first_used..last_used] {
+                allocator.add_free_range(free_from, index);
+                free_from = index + 1;
+            }
+            allocator.add_free_range(free_from, end);
+        }
+        allocator
+    }
+
+    /// Adds the frames [start, end) to the free lists, as the largest
+    /// aligned blocks that fit
+    pub fn add_free_range(&mut self, start: usize, end: usize) {
+        let mut index = start;
+        while index < end {
+            let order = Self::largest_order(index, end - index);
+            self.release(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Order of the largest block starting at `index` that is aligned to its
+    /// size and no larger than `frames`
+    fn largest_order(index: usize, frames: usize) -> usize {
+        let aligned = index.trailing_zeros() as usize;
+        let fits = (usize::BITS - 1 - frames.leading_zeros()) as usize;
+        aligned.min(fits).min(BUDDY_MAX_ORDER)
+    }
+
+    /// Smallest order of a block holding `frames` frames
+    fn order_for(frames: usize) -> usize {
+        frames.next_power_of_two().trailing_zeros() as usize
+    }
+
+    /// Allocates a block of 2^`order` frames, aligned to its size
+    ///
+    /// # Returns:
+    /// The first frame of the block, or None if no block is large enough
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > BUDDY_MAX_ORDER {
+            return None;
+        }
+        let from = (order..ORDERS).find(|&k| self.heads[k] != NONE)?;
+        let index = self.heads[from];
+        self.take(index, from, order);
+        self.allocate_count += 1;
+        Some(frame_at(index))
+    }
+
+    /// Frees a block allocated by `allocate_order`
+    ///
+    /// # Arguments:
+    /// * 'frame' - first frame of the block
+    /// * 'order' - order the block was allocated with
+    pub fn free_order(&mut self, frame: PhysFrame, order: usize) {
+        let index = frame_index(frame);
+        assert!(
+            index % (1 << order) == 0,
+            "Freed block is not aligned to its order"
+        );
+        assert!(
+            !self.is_free(index, order),
+            "Trying to double free a frame!"
+        );
+        self.free_count += 1;
+        self.release(index, order);
+    }
+
+    /// Allocates physically contiguous frames, taking a block large enough
+    /// and returning what is left over past `count` frames
+    ///
+    /// # Arguments:
+    /// * 'count' - number of frames to allocate
+    /// * 'align' - alignment of the first frame in bytes, a power of two
+    /// * 'max_phys' - address every allocated byte must lie below
+    ///
+    /// # Returns:
+    /// The first frame of the run, or None if no free block fits
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_phys: u64,
+    ) -> Option<PhysFrame> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let order = Self::order_for(count.max(align / FRAME_SIZE));
+        if order > BUDDY_MAX_ORDER {
+            return None;
+        }
+        let limit = (max_phys / FRAME_SIZE as u64) as usize;
+
+        // Splitting keeps the lower half at the start of the block, so any
+        // free block starting low enough will do
+        let (index, from) = (order..ORDERS).find_map(|k| {
+            let mut index = self.heads[k];
+            while index != NONE {
+                if index + (1 << order) <= limit {
+                    return Some((index, k));
+                }
+                index = self.block(index).next;
+            }
+            None
+        })?;
+        self.take(index, from, order);
+        self.add_free_range(index + count, index + (1 << order));
+        self.allocate_count += 1;
+        Some(frame_at(index))
+    }
+
+    /// Frees a run of frames allocated by `alloc_contiguous`
+    ///
+    /// # Arguments:
+    /// * 'start' - first frame of the run
+    /// * 'count' - number of frames in the run
+    pub fn free_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let index = frame_index(start);
+        self.free_count += 1;
+        self.add_free_range(index, index + count);
+    }
+
+    /// Removes the free block at `index` of order `from`, and splits it down
+    /// to order `order`, keeping the lower half each time
+    fn take(&mut self, index: usize, from: usize, order: usize) {
+        self.unlink(index, from);
+        for k in (order..from).rev() {
+            self.push(index + (1 << k), k);
+        }
+        self.free_frames -= 1 << order;
+    }
+
+    /// Returns a block to the free lists, merging it with its buddy for as
+    /// long as the buddy is free
+    fn release(&mut self, index: usize, order: usize) {
+        self.free_frames += 1 << order;
+
+        let (mut index, mut order) = (index, order);
+        while order < BUDDY_MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy + (1 << order) > self.total_frames || !self.is_free(buddy, order) {
+                break;
+            }
+            self.unlink(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    /// Whether the block at `index` is a free block of order `order`
+    fn is_free(&self, index: usize, order: usize) -> bool {
+        let block = index >> order;
+        self.free_maps[order][block / BITMAP_ENTRY_SIZE] & (1 << (block % BITMAP_ENTRY_SIZE)) != 0
+    }
+
+    fn set_free(&mut self, index: usize, order: usize, free: bool) {
+        let block = index >> order;
+        let entry = &mut self.free_maps[order][block / BITMAP_ENTRY_SIZE];
+        let mask = 1 << (block % BITMAP_ENTRY_SIZE);
+        if free {
+            *entry |= mask;
+        } else {
+            *entry &= !mask;
+        }
+    }
+
+    /// The list links kept in the free block at `index`
+    #[allow(clippy::mut_from_ref)]
+    fn block(&self, index: usize) -> &mut FreeBlock {
+        let virt = *HHDM_OFFSET + (index * FRAME_SIZE) as u64;
+        unsafe { &mut *virt.as_mut_ptr::<FreeBlock>() }
+    }
+
+    /// Puts a block at the front of its free list
+    fn push(&mut self, index: usize, order: usize) {
+        let head = self.heads[order];
+        *self.block(index) = FreeBlock {
+            prev: NONE,
+            next: head,
+        };
+        if head != NONE {
+            self.block(head).prev = index;
+        }
+        self.heads[order] = index;
+        self.set_free(index, order, true);
+    }
+
+    /// Takes a block out of its free list
+    fn unlink(&mut self, index: usize, order: usize) {
+        let FreeBlock { prev, next } = *self.block(index);
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            self.block(prev).next = next;
+        }
+        if next != NONE {
+            self.block(next).prev = prev;
+        }
+        self.set_free(index, order, false);
+    }
+
+    /// Number of frames in free blocks
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Prints the number of free blocks of each order
+    pub fn print_free_lists(&self) {
+        for order in 0..ORDERS {
+            let mut blocks = 0;
+            let mut index = self.heads[order];
+            while index != NONE {
+                blocks += 1;
+                index = self.block(index).next;
+            }
+            serial_println!("Order {}: {} free blocks", order, blocks);
+        }
+    }
+
+    /// Prints the total number of allocations
+    pub fn get_allocate_count(&self) -> usize {
+        self.allocate_count
+    }
+
+    /// Prints the total number of frees
+    pub fn get_free_count(&self) -> usize {
+        self.free_count
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    frame.start_address().as_u64() as usize / FRAME_SIZE
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new((index * FRAME_SIZE) as u64))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    /// Allocates a single frame, an order 0 block
+    ///
+    /// Returns:
+    /// None if no frame available, otherwise the allocated frame
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_order(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    /// deallocates a frame, merging it with free buddies
+    ///
+    /// # Arguments:
+    /// * 'frame' - frame to be freed
+    ///
+    /// # Safety
+    /// Deallocating memory must be an unsafe operation
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free_order(frame, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::frame_allocator::{alloc_contiguous_frames, dealloc_contiguous_frames};
+    use core::future::Future;
+
+    #[test_case]
+    fn test_buddy_split_and_merge() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            // Manage a run of 64 frames borrowed from the global allocator
+            let run = alloc_contiguous_frames(64, 64 * FRAME_SIZE, u64::MAX)
+                .expect("No contiguous frames");
+            let start = frame_index(run);
+            let mut buddy = BuddyFrameAllocator::new(start + 64);
+            buddy.add_free_range(start, start + 64);
+            assert_eq!(buddy.free_frames(), 64);
+
+            // A single frame splits the run all the way down
+            let frame = buddy.allocate_order(0).unwrap();
+            assert_eq!(frame_index(frame), start);
+            let block = buddy.allocate_order(3).unwrap();
+            assert_eq!(frame_index(block) % 8, 0);
+            assert_eq!(buddy.free_frames(), 64 - 1 - 8);
+            assert!(buddy.allocate_order(6).is_none());
+
+            // Freeing both merges the run back into one block
+            buddy.free_order(frame, 0);
+            buddy.free_order(block, 3);
+            assert_eq!(buddy.free_frames(), 64);
+            assert_eq!(buddy.allocate_order(6), Some(run));
+            buddy.free_order(run, 6);
+
+            // Contiguous runs give back what they do not use
+            let contiguous = buddy.alloc_contiguous(5, FRAME_SIZE, u64::MAX).unwrap();
+            assert_eq!(buddy.free_frames(), 64 - 5);
+            buddy.free_contiguous(contiguous, 5);
+            assert_eq!(buddy.allocate_order(6), Some(run));
+
+            dealloc_contiguous_frames(run, 64);
+        }
+    }
+}
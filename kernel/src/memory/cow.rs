@@ -0,0 +1,276 @@
+//! Copy-on-write address spaces
+//!
+//! - Counts how many address spaces map each shared user frame
+//! - Duplicates the user half of a page table, sharing every frame
+//! - Resolves writes to shared frames by giving the writer its own copy
+//!
+//! Writable pages are shared read-only with `COW_FLAG` set. A write to such a
+//! page faults, and `handle_cow_fault` either copies the frame or, if the
+//! faulting process is the last one mapping it, simply makes it writable again.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{page_table::PageTableEntry, PageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    constants::memory::{PAGE_SIZE, USER_SPACE_END},
+    memory::{
+        frame_allocator::{alloc_frame, dealloc_frame},
+        paging::split_huge_entry,
+        tlb::tlb_shootdown,
+        HHDM_OFFSET,
+    },
+};
+
+/// Marks a page that is writable by its process but currently shares its frame
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of page table entries mapping each shared frame.
+/// Frames mapped only once are not tracked.
+static FRAME_REFCOUNTS: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+/// Records one more mapping of `frame`
+fn share_frame(frame: PhysFrame) {
+    *FRAME_REFCOUNTS.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops one mapping of a user frame
+///
+/// # Arguments
+/// * `frame` - the frame being unmapped
+///
+/// # Returns
+/// Whether this was the last mapping, in which case the caller must free it
+pub fn release_frame(frame: PhysFrame) -> bool {
+    let mut refcounts = FRAME_REFCOUNTS.lock();
+    match refcounts.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                refcounts.remove(&frame);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// Returns how many page table entries map `frame`
+pub fn frame_refcount(frame: PhysFrame) -> usize {
+    FRAME_REFCOUNTS.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// Copies the user half of `parent_pml4` into the empty user half of
+/// `child_pml4`, sharing every mapped frame between the two.
+///
+/// Writable pages become read-only and copy-on-write in both tables. The
+/// caller must flush the parent's TLB if it is active on any core.
+///
+/// # Arguments
+/// * `parent_pml4` - the page table to duplicate
+/// * `child_pml4` - a page table whose user half is unused
+///
+/// # Safety
+/// Both frames must hold valid level 4 page tables, and the parent's user
+/// mappings must not change during the copy
+pub unsafe fn duplicate_user_space(parent_pml4: PhysFrame, child_pml4: PhysFrame) {
+    let parent = table_mut(parent_pml4);
+    let child = table_mut(child_pml4);
+
+    for i in 0..256 {
+        let entry = &parent[i];
+        if entry.is_unused() {
+            continue;
+        }
+        let pdpt = copy_table(PhysFrame::containing_address(entry.addr()), 3);
+        child[i].set_addr(pdpt.start_address(), entry.flags());
+    }
+}
+
+/// Recursively copies a page table at `level`, returning the new table
+unsafe fn copy_table(frame: PhysFrame, level: u8) -> PhysFrame {
+    let new_frame = alloc_frame().expect("Failed to allocate page table frame");
+    let parent = table_mut(frame);
+    let child = table_mut(new_frame);
+    child.zero();
+
+    for (parent_entry, child_entry) in parent.iter_mut().zip(child.iter_mut()) {
+        if parent_entry.is_unused() {
+            continue;
+        }
+
+        // Shared frames are counted per 4 KiB, so huge pages are split first
+        if level > 1 && parent_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            split_huge_entry(parent_entry, level).expect("Failed to allocate page table frame");
+        }
+
+        if level > 1 {
+            let table = copy_table(
+                PhysFrame::containing_address(parent_entry.addr()),
+                level - 1,
+            );
+            child_entry.set_addr(table.start_address(), parent_entry.flags());
+        } else {
+            let mut flags = parent_entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COW_FLAG);
+                parent_entry.set_flags(flags);
+            }
+            share_frame(PhysFrame::containing_address(parent_entry.addr()));
+            child_entry.set_addr(parent_entry.addr(), flags);
+        }
+    }
+
+    new_frame
+}
+
+/// Resolves a write to a copy-on-write page.
+///
+/// # Arguments
+/// * `pml4` - page table of the faulting process
+/// * `addr` - the address written to
+///
+/// # Returns
+/// Whether `addr` was a copy-on-write page and is now writable, also when
+/// another thread of the process broke the sharing first. If not, the fault
+/// is a genuine protection violation, or the page is still shared and no
+/// frame was free to copy it into.
+pub fn handle_cow_fault(pml4: PhysFrame, addr: VirtAddr) -> bool {
+    let Some(entry) = (unsafe { leaf_entry(pml4, addr) }) else {
+        return false;
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | COW_FLAG) {
+        // The write is retried if it lost the race to copy the page
+        let user_writable =
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        return !flags.contains(COW_FLAG) && flags.contains(user_writable);
+    }
+    let writable = (flags | PageTableFlags::WRITABLE) - COW_FLAG;
+    let old_frame = PhysFrame::containing_address(entry.addr());
+
+    // Allocate before taking the refcount lock; exiting processes free
+    // frames under the allocator lock and then take the refcount lock
+    let new_frame = alloc_frame();
+
+    let copied = {
+        let mut refcounts = FRAME_REFCOUNTS.lock();
+        match (refcounts.get_mut(&old_frame), new_frame) {
+            (Some(count), Some(new_frame)) => {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        frame_ptr(old_frame),
+                        frame_ptr(new_frame),
+                        PAGE_SIZE,
+                    );
+                }
+                *count -= 1;
+                if *count == 1 {
+                    refcounts.remove(&old_frame);
+                }
+                entry.set_addr(new_frame.start_address(), writable);
+                true
+            }
+            (Some(_), None) => return false,
+            (None, _) => {
+                // Every other process already took its own copy
+                entry.set_flags(writable);
+                false
+            }
+        }
+    };
+
+    if let (false, Some(new_frame)) = (copied, new_frame) {
+        dealloc_frame(new_frame);
+    }
+    tlb_shootdown(addr.align_down(PAGE_SIZE as u64));
+    true
+}
+
+/// Makes every copy-on-write page in `[addr, addr + len)` writable, so the
+/// kernel can write user memory through the HHDM like the process would.
+/// Pages that cannot be copied for lack of frames stay read-only, so the
+/// write is refused with `EFAULT`.
+///
+/// # Arguments
+/// * `pml4` - page table of the process that owns the range
+/// * `addr` - start of the range
+/// * `len` - length of the range in bytes
+pub fn break_cow_range(pml4: PhysFrame, addr: u64, len: usize) {
+    let Some(end) = addr.checked_add(len as u64) else {
+        return;
+    };
+    if len == 0 || end > USER_SPACE_END {
+        return;
+    }
+
+    let mut page = addr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        handle_cow_fault(pml4, VirtAddr::new(page));
+        page += PAGE_SIZE as u64;
+    }
+}
+
+/// Finds the level 1 entry mapping `addr`, if every level above is present
+unsafe fn leaf_entry(pml4: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table = table_mut(pml4);
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if level == 3 {
+            return Some(entry);
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = table_mut(PhysFrame::containing_address(entry.addr()));
+    }
+    None
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr::<PageTable>()
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_shared_frame_refcounts() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let frame = alloc_frame().expect("Could not allocate frame");
+            assert_eq!(frame_refcount(frame), 1);
+
+            share_frame(frame);
+            share_frame(frame);
+            assert_eq!(frame_refcount(frame), 3);
+
+            assert!(!release_frame(frame));
+            assert!(!release_frame(frame));
+            assert_eq!(frame_refcount(frame), 1);
+            assert!(release_frame(frame));
+
+            dealloc_frame(frame);
+        }
+    }
+}
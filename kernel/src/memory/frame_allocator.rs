@@ -1,13 +1,20 @@
 //! Frame allocators for use in allocation and deallocation
 //! Contains a GlobalFrameAllocator, which is a wrapper around
-//! the BootIntoFrameAllocator and the BitmapFrameAllocator
+//! the BootIntoFrameAllocator and either the BitmapFrameAllocator or the
+//! BuddyFrameAllocator, chosen on the kernel command line
 
-use crate::memory::{
-    bitmap_frame_allocator::BitmapFrameAllocator, boot_frame_allocator::BootIntoFrameAllocator,
+use crate::{
+    init::cmdline_option,
+    memory::{
+        bitmap_frame_allocator::BitmapFrameAllocator,
+        boot_frame_allocator::BootIntoFrameAllocator,
+        buddy_frame_allocator::BuddyFrameAllocator,
+        frame_cache::{alloc_cached_frame, dealloc_cached_frame},
+    },
 };
 use spin::Mutex;
 
-use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
 
 /// Global frame allocator that makes it so we just have one actual allocator throughout codebase
 /// Requires some basic synchronization
@@ -17,11 +24,87 @@ pub static FRAME_ALLOCATOR: Mutex<Option<GlobalFrameAllocator>> = Mutex::new(Non
 pub enum GlobalFrameAllocator {
     Boot(BootIntoFrameAllocator),
     Bitmap(BitmapFrameAllocator),
+    Buddy(BuddyFrameAllocator),
+}
+
+/// Allocators the boot frame allocator can be switched to once the heap is up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameAllocatorKind {
+    Bitmap,
+    Buddy,
+}
+
+impl FrameAllocatorKind {
+    /// Reads `frame_allocator=bitmap` or `frame_allocator=buddy` from the
+    /// kernel command line
+    ///
+    /// # Returns
+    /// The allocator asked for, the bitmap allocator if none is
+    pub fn from_cmdline() -> Self {
+        match cmdline_option(b"frame_allocator") {
+            Some(b"buddy") => Self::Buddy,
+            _ => Self::Bitmap,
+        }
+    }
+}
+
+impl GlobalFrameAllocator {
+    /// Allocates physically contiguous frames, e.g. for device DMA buffers
+    ///
+    /// # Arguments
+    /// * `count`: number of frames to allocate
+    /// * `align`: alignment of the first frame in bytes, a power of two
+    /// * `max_phys`: address every allocated byte must lie below
+    ///
+    /// # Returns
+    /// The first frame of the run, or None if no free run fits or the boot
+    /// frame allocator is in use
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_phys: u64,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        match self {
+            GlobalFrameAllocator::Boot(_) => None,
+            GlobalFrameAllocator::Bitmap(ref mut bitmap_alloc) => {
+                bitmap_alloc.alloc_contiguous(count, align, max_phys)
+            }
+            GlobalFrameAllocator::Buddy(ref mut buddy_alloc) => {
+                buddy_alloc.alloc_contiguous(count, align, max_phys)
+            }
+        }
+    }
+
+    /// Frees frames allocated by `alloc_contiguous`
+    ///
+    /// # Arguments
+    /// * `start`: first frame of the run
+    /// * `count`: number of frames in the run
+    ///
+    /// # Safety
+    /// The run must have been allocated by `alloc_contiguous` with `count`,
+    /// and must no longer be in use
+    pub unsafe fn free_contiguous(&mut self, start: PhysFrame<Size4KiB>, count: usize) {
+        match self {
+            GlobalFrameAllocator::Boot(ref mut boot_alloc) => {
+                for frame in PhysFrame::range(start, start + count as u64) {
+                    boot_alloc.deallocate_frame(frame);
+                }
+            }
+            GlobalFrameAllocator::Bitmap(ref mut bitmap_alloc) => {
+                bitmap_alloc.free_contiguous(start, count)
+            }
+            GlobalFrameAllocator::Buddy(ref mut buddy_alloc) => {
+                buddy_alloc.free_contiguous(start, count)
+            }
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
-    /// Global allocator that allocates a frame using either the boot frame allocator or the bitmap
-    /// depending on what the current selected allocator is
+    /// Global allocator that allocates a frame using the boot frame allocator, the bitmap or the
+    /// buddy allocator depending on what the current selected allocator is
     ///
     /// # Returns
     /// The allocated frame
@@ -29,12 +112,13 @@ unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
         match self {
             GlobalFrameAllocator::Boot(ref mut boot_alloc) => boot_alloc.allocate_frame(),
             GlobalFrameAllocator::Bitmap(ref mut bitmap_alloc) => bitmap_alloc.allocate_frame(),
+            GlobalFrameAllocator::Buddy(ref mut buddy_alloc) => buddy_alloc.allocate_frame(),
         }
     }
 }
 
 impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
-    /// Global deallocator that calls either the boot frame allocator or the bitmap
+    /// Global deallocator that calls the boot frame allocator, the bitmap or the buddy allocator
     /// depending on what the current selected allocator is
     ///
     /// # Arguments
@@ -48,24 +132,76 @@ impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
             GlobalFrameAllocator::Bitmap(ref mut bitmap_alloc) => {
                 bitmap_alloc.deallocate_frame(frame)
             }
+            GlobalFrameAllocator::Buddy(ref mut buddy_alloc) => buddy_alloc.deallocate_frame(frame),
         }
     }
 }
 
-/// Exposed function to allocate a frame that runs the global's allocate_frame
+/// Exposed function to allocate a frame, from the current core's frame cache
 ///
 /// # Returns
 /// The allocated frame
 pub fn alloc_frame() -> Option<PhysFrame> {
-    with_generic_allocator(|allocator| allocator.allocate_frame())
+    alloc_cached_frame()
 }
 
-/// Exposed function to deallocate a frame that runs the global's deallocate_frame
+/// Exposed function to deallocate a frame, into the current core's frame cache
 ///
 /// # Arguments
 /// * `frame`: The frame to deallocate
 pub fn dealloc_frame(frame: PhysFrame<Size4KiB>) {
-    with_generic_allocator(|allocator| unsafe { allocator.deallocate_frame(frame) })
+    dealloc_cached_frame(frame)
+}
+
+/// Exposed function to allocate physically contiguous frames that runs the
+/// global's alloc_contiguous
+///
+/// # Arguments
+/// * `count`: number of frames to allocate
+/// * `align`: alignment of the first frame in bytes, a power of two
+/// * `max_phys`: address every allocated byte must lie below
+///
+/// # Returns
+/// The first frame of the run
+pub fn alloc_contiguous_frames(count: usize, align: usize, max_phys: u64) -> Option<PhysFrame> {
+    with_generic_allocator(|allocator| allocator.alloc_contiguous(count, align, max_phys))
+}
+
+/// Exposed function to free frames from `alloc_contiguous_frames`
+///
+/// # Arguments
+/// * `start`: first frame of the run
+/// * `count`: number of frames in the run
+pub fn dealloc_contiguous_frames(start: PhysFrame<Size4KiB>, count: usize) {
+    with_generic_allocator(|allocator| unsafe { allocator.free_contiguous(start, count) })
+}
+
+/// Exposed function to allocate a frame of any page size. Huge frames are
+/// runs of contiguous 4 KiB frames aligned to their size.
+///
+/// # Returns
+/// The allocated frame
+pub fn alloc_sized_frame<S: PageSize>() -> Option<PhysFrame<S>> {
+    let frame = if S::SIZE == Size4KiB::SIZE {
+        alloc_frame()?
+    } else {
+        let count = (S::SIZE / Size4KiB::SIZE) as usize;
+        alloc_contiguous_frames(count, S::SIZE as usize, u64::MAX)?
+    };
+    Some(PhysFrame::containing_address(frame.start_address()))
+}
+
+/// Exposed function to deallocate a frame from `alloc_sized_frame`
+///
+/// # Arguments
+/// * `frame`: The frame to deallocate
+pub fn dealloc_sized_frame<S: PageSize>(frame: PhysFrame<S>) {
+    let start = PhysFrame::containing_address(frame.start_address());
+    if S::SIZE == Size4KiB::SIZE {
+        dealloc_frame(start)
+    } else {
+        dealloc_contiguous_frames(start, (S::SIZE / Size4KiB::SIZE) as usize)
+    }
 }
 
 /// Gives access to the bitmap frame allocator to any passed in closure
@@ -89,6 +225,27 @@ where
     f(alloc)
 }
 
+/// Gives access to the buddy frame allocator to any passed in closure
+/// Example:
+/// with_buddy_frame_allocator(|allocator| {
+///     // code to run
+/// })
+///
+/// Arguments:
+///
+/// * `f`: The closure to run
+pub fn with_buddy_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BuddyFrameAllocator) -> R,
+{
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let alloc = match &mut *guard {
+        Some(GlobalFrameAllocator::Buddy(alloc)) => alloc,
+        _ => panic!("Allocator is not a BuddyFrameAllocator"),
+    };
+    f(alloc)
+}
+
 /// Gives access to the boot into frame allocator to any passed in closure
 /// Example:
 /// with_boot_into_frame_allocator(|allocator| {
@@ -130,3 +287,44 @@ where
         panic!("Allocator does not exist.");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::memory::FRAME_SIZE;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_alloc_contiguous() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let align = 16 * FRAME_SIZE;
+            let max_phys = 1 << 32;
+            let start = alloc_contiguous_frames(8, align, max_phys).expect("No contiguous frames");
+
+            let addr = start.start_address().as_u64();
+            assert_eq!(addr % align as u64, 0);
+            assert!(addr + 8 * FRAME_SIZE as u64 <= max_phys);
+            // Only the bitmap allocator tracks single frames
+            let bitmap = FrameAllocatorKind::from_cmdline() == FrameAllocatorKind::Bitmap;
+            if bitmap {
+                with_bitmap_frame_allocator(|allocator| {
+                    for frame in PhysFrame::range(start, start + 8) {
+                        assert!(allocator.is_frame_used(frame));
+                    }
+                });
+            }
+
+            dealloc_contiguous_frames(start, 8);
+            if bitmap {
+                with_bitmap_frame_allocator(|allocator| {
+                    for frame in PhysFrame::range(start, start + 8) {
+                        assert!(!allocator.is_frame_used(frame));
+                    }
+                });
+            }
+
+            // Nothing fits below the first frame
+            assert!(alloc_contiguous_frames(1, FRAME_SIZE, FRAME_SIZE as u64 - 1).is_none());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Per-core frame caches
+//!
+//! Each core keeps a magazine of free frames, so most frame allocations and
+//! frees skip the global `FRAME_ALLOCATOR` lock. An empty magazine refills
+//! with a batch of frames from the global allocator under one lock, and a
+//! full one drains a batch back the same way.
+//!
+//! A magazine is only used by its own core, with interrupts disabled, so its
+//! lock is uncontended unless a core ran out of frames and is reclaiming the
+//! frames every core has cached. Magazines are always locked before the
+//! global allocator.
+//!
+//! Caching starts once the boot frame allocator has been switched out, as it
+//! cannot take frames back.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{
+    constants::{
+        memory::{FRAME_CACHE_BATCH, FRAME_CACHE_CAPACITY},
+        MAX_CORES,
+    },
+    interrupts::{idt::without_interrupts, x2apic::current_core_id},
+    memory::frame_allocator::with_generic_allocator,
+};
+
+static CACHES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static FRAME_CACHES: [Mutex<FrameMagazine>; MAX_CORES] =
+    [const { Mutex::new(FrameMagazine::new()) }; MAX_CORES];
+
+/// Free frames cached by one core, kept as a stack of addresses
+struct FrameMagazine {
+    frames: [u64; FRAME_CACHE_CAPACITY],
+    len: usize,
+}
+
+impl FrameMagazine {
+    const fn new() -> Self {
+        Self {
+            frames: [0; FRAME_CACHE_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, frame: PhysFrame) {
+        self.frames[self.len] = frame.start_address().as_u64();
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        self.len = self.len.checked_sub(1)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            self.frames[self.len],
+        )))
+    }
+
+    /// Takes up to a batch of frames from the global allocator
+    fn refill(&mut self) {
+        with_generic_allocator(|allocator| {
+            while self.len < FRAME_CACHE_BATCH {
+                let Some(frame) = allocator.allocate_frame() else {
+                    break;
+                };
+                self.push(frame);
+            }
+        });
+    }
+
+    /// Gives up to `count` frames back to the global allocator
+    fn drain(&mut self, count: usize) {
+        with_generic_allocator(|allocator| {
+            for _ in 0..count {
+                let Some(frame) = self.pop() else {
+                    break;
+                };
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// Starts caching frames; called once the global allocator can free frames
+pub fn enable_frame_caches() {
+    CACHES_ENABLED.store(true, Ordering::Release);
+}
+
+/// Allocates a frame from the current core's cache, refilling it if empty
+///
+/// # Returns
+/// The allocated frame, or None if no core has a frame left to give
+pub fn alloc_cached_frame() -> Option<PhysFrame> {
+    if !CACHES_ENABLED.load(Ordering::Acquire) {
+        return with_generic_allocator(|allocator| allocator.allocate_frame());
+    }
+
+    let frame = without_interrupts(|| {
+        let mut magazine = FRAME_CACHES[current_core_id()].lock();
+        if magazine.len == 0 {
+            magazine.refill();
+        }
+        magazine.pop()
+    });
+
+    // Other cores may be sitting on the last free frames
+    frame.or_else(|| {
+        reclaim_cached_frames();
+        with_generic_allocator(|allocator| allocator.allocate_frame())
+    })
+}
+
+/// Frees a frame into the current core's cache, draining a batch first if
+/// it is full
+///
+/// # Arguments
+/// * `frame`: the frame to free, no longer in use
+pub fn dealloc_cached_frame(frame: PhysFrame) {
+    if !CACHES_ENABLED.load(Ordering::Acquire) {
+        return with_generic_allocator(|allocator| unsafe { allocator.deallocate_frame(frame) });
+    }
+
+    without_interrupts(|| {
+        let mut magazine = FRAME_CACHES[current_core_id()].lock();
+        if magazine.len == FRAME_CACHE_CAPACITY {
+            magazine.drain(FRAME_CACHE_BATCH);
+        }
+        magazine.push(frame);
+    });
+}
+
+/// Returns the frames every core has cached to the global allocator
+pub fn reclaim_cached_frames() {
+    for cache in &FRAME_CACHES {
+        without_interrupts(|| {
+            let mut magazine = cache.lock();
+            let len = magazine.len;
+            magazine.drain(len);
+        });
+    }
+}
+
+/// Number of frames cached by all cores
+pub fn cached_frames() -> usize {
+    FRAME_CACHES
+        .iter()
+        .map(|cache| without_interrupts(|| cache.lock().len))
+        .sum()
+}
+
+/// Frame allocator going through the per-core caches, for mapping and
+/// unmapping without holding the global allocator's lock
+pub struct CachedFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for CachedFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        alloc_cached_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for CachedFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        dealloc_cached_frame(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_frame_cache_batches() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let core = current_core_id();
+            let cached = || without_interrupts(|| FRAME_CACHES[core].lock().len);
+            reclaim_cached_frames();
+
+            // The first allocation refills a whole batch
+            let frame = alloc_cached_frame().unwrap();
+            assert_eq!(cached(), FRAME_CACHE_BATCH - 1);
+            dealloc_cached_frame(frame);
+            assert_eq!(cached(), FRAME_CACHE_BATCH);
+
+            // A full cache drains a batch before taking another frame
+            let global_frame = || with_generic_allocator(|allocator| allocator.allocate_frame());
+            while cached() < FRAME_CACHE_CAPACITY {
+                dealloc_cached_frame(global_frame().unwrap());
+            }
+            dealloc_cached_frame(global_frame().unwrap());
+            assert_eq!(cached(), FRAME_CACHE_CAPACITY - FRAME_CACHE_BATCH + 1);
+
+            reclaim_cached_frames();
+            assert_eq!(cached(), 0);
+        }
+    }
+}
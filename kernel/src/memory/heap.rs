@@ -1,26 +1,295 @@
 //! The Kernel Heap
-//! Contains the initialization for the kernel heap using the Talc allocator
+//! Contains the initialization for the kernel heap using the Talc allocator,
+//! behind the slab allocator for small objects
+//!
+//! The heap starts as `HEAP_SIZE` bytes mapped at boot. When Talc runs out,
+//! it grows by mapping fresh frames above its end, up to `HEAP_MAX_SIZE`,
+//! as 2 MiB pages where a run of frames is free; once a large span at the
+//! end is free again, those frames are unmapped and returned to the frame
+//! allocator.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
-    constants::memory::{HEAP_SIZE, HEAP_START},
-    memory::{frame_allocator::FRAME_ALLOCATOR, paging::create_mapping, MAPPER},
+    constants::memory::{
+        HEAP_GROW_STEP, HEAP_MAX_SIZE, HEAP_SHRINK_THRESHOLD, HEAP_SIZE, HEAP_START,
+        HUGE_PAGE_SIZE, PAGE_SIZE,
+    },
+    memory::{
+        frame_allocator::{
+            alloc_frame, alloc_sized_frame, dealloc_frame, dealloc_sized_frame, FRAME_ALLOCATOR,
+        },
+        frame_cache::CachedFrameAllocator,
+        paging::{create_mapping, remove_mapped_frame, split_huge_page},
+        slab::SlabAllocator,
+        MAPPER,
+    },
     serial_println,
 };
-use talc::{ClaimOnOom, Span, Talc, Talck};
+use talc::{OomHandler, Span, Talc, Talck};
 use x86_64::{
-    structures::paging::{mapper::MapToError, Page, Size4KiB},
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageTableFlags, Size2MiB, Size4KiB, Translate,
+    },
     VirtAddr,
 };
 
-use super::{bitmap_frame_allocator::BitmapFrameAllocator, frame_allocator::GlobalFrameAllocator};
+use super::{
+    bitmap_frame_allocator::BitmapFrameAllocator,
+    buddy_frame_allocator::BuddyFrameAllocator,
+    frame_allocator::{FrameAllocatorKind, GlobalFrameAllocator},
+    frame_cache::enable_frame_caches,
+};
 
 #[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> = Talc::new(unsafe {
-    ClaimOnOom::new(Span::new(HEAP_START, HEAP_START.wrapping_add(HEAP_SIZE)))
-})
-.lock();
+static ALLOCATOR: SlabAllocator<Heap> = SlabAllocator::new(Heap::new());
+
+/// Bytes handed out by the heap and not yet freed
+static HEAP_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Most bytes the heap has had mapped at once
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(HEAP_SIZE);
+/// Times the heap grew and shrank
+static HEAP_GROWS: AtomicUsize = AtomicUsize::new(0);
+static HEAP_SHRINKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A snapshot of the heap's usage
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes of virtual memory backed by frames
+    pub mapped: usize,
+    /// Most bytes mapped at once
+    pub peak_mapped: usize,
+    /// Bytes allocated and not yet freed, including slabs
+    pub allocated: usize,
+    pub grows: usize,
+    pub shrinks: usize,
+}
+
+/// Grows the heap on demand
+struct GrowOnOom {
+    /// The heap as Talc knows it, empty until the first allocation claims
+    /// the span mapped at boot
+    heap: Span,
+}
+
+impl OomHandler for GrowOnOom {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let Some((base, acme)) = talc.oom_handler.heap.get_base_acme() else {
+            let initial = Span::from_base_size(HEAP_START, HEAP_SIZE);
+            talc.oom_handler.heap = unsafe { talc.claim(initial)? };
+            return Ok(());
+        };
 
-/// Initialize the heap and switch to using the bitmap frame_allocator
+        // Room for the allocation, its alignment and Talc's tags, in whole steps
+        let needed = layout.size() + layout.align() + PAGE_SIZE;
+        let limit = HEAP_START as usize + HEAP_MAX_SIZE;
+        let target = (acme as usize + needed.next_multiple_of(HEAP_GROW_STEP)).min(limit);
+        let mapped = map_heap_pages(acme as usize, target);
+        if mapped == acme as usize {
+            return Err(());
+        }
+
+        let old_heap = talc.oom_handler.heap;
+        talc.oom_handler.heap =
+            unsafe { talc.extend(old_heap, Span::new(base, mapped as *mut u8)) };
+        HEAP_GROWS.fetch_add(1, Ordering::Relaxed);
+        HEAP_PEAK.fetch_max(mapped - HEAP_START as usize, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Maps fresh frames over `[start, end)` of the heap's range, as 2 MiB
+/// pages where possible
+///
+/// # Returns
+/// The end of what was mapped, short of `end` if frames ran out, or `start`
+/// if the allocation comes from code on this core holding the mapper
+fn map_heap_pages(start: usize, end: usize) -> usize {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if MAPPER.held_by_current_core() {
+        return start;
+    }
+    let mut mapper = MAPPER.lock();
+    let mut addr = start;
+    while addr < end {
+        if addr % HUGE_PAGE_SIZE == 0
+            && end - addr >= HUGE_PAGE_SIZE
+            && map_heap_huge_page(&mut mapper, addr, flags)
+        {
+            addr += HUGE_PAGE_SIZE;
+            continue;
+        }
+
+        let Some(frame) = alloc_frame() else {
+            break;
+        };
+        let page = Page::containing_address(VirtAddr::new(addr as u64));
+        match unsafe { mapper.map_to(page, frame, flags, &mut CachedFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                dealloc_frame(frame);
+                break;
+            }
+        }
+        addr += PAGE_SIZE;
+    }
+    addr
+}
+
+/// Maps a 2 MiB page at `addr`, unless no run of frames is free or a 4 KiB
+/// page table already covers it
+///
+/// # Returns
+/// Whether the page was mapped
+fn map_heap_huge_page(mapper: &mut OffsetPageTable, addr: usize, flags: PageTableFlags) -> bool {
+    let Some(frame) = alloc_sized_frame::<Size2MiB>() else {
+        return false;
+    };
+    let page = Page::containing_address(VirtAddr::new(addr as u64));
+    match unsafe { mapper.map_to(page, frame, flags, &mut CachedFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            dealloc_sized_frame(frame);
+            false
+        }
+    }
+}
+
+/// Talc over the heap's range, growing and shrinking it as needed
+pub struct Heap {
+    talc: Talck<spin::Mutex<()>, GrowOnOom>,
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            talc: Talc::new(GrowOnOom {
+                heap: Span::empty(),
+            })
+            .lock(),
+        }
+    }
+
+    /// Unmaps the end of the heap if enough of it is free, keeping a step of
+    /// slack above what is in use and never going below `HEAP_SIZE`
+    fn release_free_end(&self) {
+        let mut talc = self.talc.lock();
+        let heap = talc.oom_handler.heap;
+        let Some((base, acme)) = heap.get_base_acme() else {
+            return;
+        };
+        let used_acme = unsafe { talc.get_allocated_span(heap) }
+            .get_base_acme()
+            .map_or(base, |(_, acme)| acme);
+        let keep = (used_acme as usize + HEAP_GROW_STEP)
+            .next_multiple_of(HEAP_GROW_STEP)
+            .max(HEAP_START as usize + HEAP_SIZE);
+        if (acme as usize).saturating_sub(keep) < HEAP_SHRINK_THRESHOLD {
+            return;
+        }
+        // The free may come from code on this core holding the mapper;
+        // shrink on a later one
+        if MAPPER.held_by_current_core() {
+            return;
+        }
+        let mut mapper = MAPPER.lock();
+
+        let new_heap = unsafe { talc.truncate(heap, Span::new(base, keep as *mut u8)) };
+        talc.oom_handler.heap = new_heap;
+        let new_acme = new_heap.get_base_acme().map_or(base, |(_, acme)| acme) as usize;
+
+        let acme = acme as usize;
+        let mut addr = new_acme.next_multiple_of(PAGE_SIZE);
+        // Part of the huge page holding the new end stays in the heap. Without
+        // a frame for the table it is split into, the heap keeps its size.
+        if addr % HUGE_PAGE_SIZE != 0
+            && split_huge_page(&mut mapper, VirtAddr::new(addr as u64)).is_err()
+        {
+            talc.oom_handler.heap = unsafe { talc.extend(new_heap, heap) };
+            return;
+        }
+
+        // Huge pages are only mapped whole inside the heap, so every one
+        // left lies entirely past the new end
+        while addr < acme {
+            let vaddr = VirtAddr::new(addr as u64);
+            if let TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } = mapper.translate(vaddr)
+            {
+                remove_mapped_frame::<Size2MiB>(Page::containing_address(vaddr), &mut *mapper);
+                addr += HUGE_PAGE_SIZE;
+                continue;
+            }
+            remove_mapped_frame::<Size4KiB>(Page::containing_address(vaddr), &mut *mapper);
+            addr += PAGE_SIZE;
+        }
+        HEAP_SHRINKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.talc.alloc(layout);
+        if !ptr.is_null() {
+            HEAP_ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.talc.dealloc(ptr, layout);
+        HEAP_ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        // Only large frees open up spans worth unmapping
+        if layout.size() >= PAGE_SIZE {
+            self.release_free_end();
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.talc.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            HEAP_ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            HEAP_ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// The heap's current usage
+pub fn heap_stats() -> HeapStats {
+    let mapped = ALLOCATOR.backing().talc.lock().oom_handler.heap.size();
+    HeapStats {
+        mapped,
+        peak_mapped: HEAP_PEAK.load(Ordering::Relaxed),
+        allocated: HEAP_ALLOCATED.load(Ordering::Relaxed),
+        grows: HEAP_GROWS.load(Ordering::Relaxed),
+        shrinks: HEAP_SHRINKS.load(Ordering::Relaxed),
+    }
+}
+
+/// Prints the heap's current usage
+pub fn print_heap_stats() {
+    let stats = heap_stats();
+    serial_println!(
+        "Heap: {} bytes mapped (peak {}), {} allocated, {} grows, {} shrinks",
+        stats.mapped,
+        stats.peak_mapped,
+        stats.allocated,
+        stats.grows,
+        stats.shrinks
+    );
+}
+
+/// Initialize the heap and switch to the frame allocator chosen on the kernel command line
 ///
 /// # Returns
 /// An error, whether the heap was created successfully or not
@@ -28,7 +297,7 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
+        let heap_start_page: Page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
@@ -37,28 +306,66 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
         create_mapping(page, &mut *MAPPER.lock(), None);
     }
 
-    switch_allocator();
+    let kind = FrameAllocatorKind::from_cmdline();
+    switch_allocator(kind);
 
-    serial_println!("Allocator switched to bitmap allocator");
+    serial_println!("Allocator switched to {:?} allocator", kind);
 
     Ok(())
 }
 
-/// Switches the allocator from the boot into frame allocator to the bitmap frame allocator
-fn switch_allocator() {
+/// Switches the allocator from the boot into frame allocator to the bitmap or buddy frame allocator
+///
+/// # Arguments
+/// * `kind`: the allocator to switch to
+fn switch_allocator(kind: FrameAllocatorKind) {
     let mut alloc = FRAME_ALLOCATOR.lock();
     match *alloc {
         Some(GlobalFrameAllocator::Boot(ref boot_alloc)) => {
             unsafe {
-                let bitmap_frame_allocator = BitmapFrameAllocator::init(
-                    boot_alloc.memory_map,
-                    boot_alloc.allocated_frames(),
-                );
-                *alloc = Some(GlobalFrameAllocator::Bitmap(bitmap_frame_allocator));
+                let memory_map = boot_alloc.memory_map;
+                let allocated_frames = boot_alloc.allocated_frames();
+                *alloc = Some(match kind {
+                    FrameAllocatorKind::Bitmap => GlobalFrameAllocator::Bitmap(
+                        BitmapFrameAllocator::init(memory_map, allocated_frames),
+                    ),
+                    FrameAllocatorKind::Buddy => GlobalFrameAllocator::Buddy(
+                        BuddyFrameAllocator::init(memory_map, allocated_frames),
+                    ),
+                });
 
+                enable_frame_caches();
                 serial_println!("new frame allocator set");
             };
         }
         _ => panic!("We must be using Boot Frame Allocator at this point"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_heap_grows_and_shrinks() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let before = heap_stats();
+
+            // Larger than the heap mapped at boot
+            let mut big = vec![0u8; HEAP_SIZE];
+            big[HEAP_SIZE - 1] = 1;
+            let grown = heap_stats();
+            assert!(grown.grows > before.grows);
+            assert!(grown.mapped > HEAP_SIZE);
+            assert!(grown.allocated >= before.allocated + HEAP_SIZE);
+
+            drop(big);
+            let shrunk = heap_stats();
+            assert!(shrunk.shrinks > grown.shrinks);
+            assert!(shrunk.mapped < grown.mapped);
+            assert!(shrunk.peak_mapped >= grown.mapped);
+        }
+    }
+}
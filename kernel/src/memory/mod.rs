@@ -1,23 +1,33 @@
 //! The Virtual memory system
 //! Initializes a kernel heap and the frame allocators
+//! Serves small kernel objects from slab caches
+//! Caches free frames per core to keep off the global frame allocator's lock
 //! Provides an interface for paging and mapping frames of memory
 //! Implements TLB shootdowns
+//! Shares user frames copy-on-write between forked processes
+//! Validates and copies user memory for system calls
+//! Tracks user memory areas and maps their pages on demand
 
 pub mod bitmap_frame_allocator;
 pub mod boot_frame_allocator;
+pub mod buddy_frame_allocator;
+pub mod cow;
 pub mod frame_allocator;
+pub mod frame_cache;
 pub mod heap;
 pub mod paging;
+pub mod slab;
 pub mod tlb;
+pub mod user_access;
+pub mod vma;
 
 use boot_frame_allocator::BootIntoFrameAllocator;
 use frame_allocator::{GlobalFrameAllocator, FRAME_ALLOCATOR};
 use lazy_static::lazy_static;
 use limine::request::HhdmRequest;
-use spin::Mutex;
+use paging::KernelMapper;
 use x86_64::{
     registers::model_specific::{Efer, EferFlags},
-    structures::paging::OffsetPageTable,
     VirtAddr,
 };
 
@@ -31,7 +41,7 @@ extern "C" {
 
 lazy_static! {
     // The kernel mapper
-    pub static ref MAPPER: Mutex<OffsetPageTable<'static>> = Mutex::new(unsafe { paging::init() });
+    pub static ref MAPPER: KernelMapper = KernelMapper::new(unsafe { paging::init() });
     // Start of kernel virtual memory
     pub static ref HHDM_OFFSET: VirtAddr = VirtAddr::new(
         HHDM_REQUEST
@@ -2,18 +2,29 @@
 // however it could be used in a plethora of places later so I am keeping it for now
 #![allow(dead_code)]
 
+use core::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use raw_cpuid::CpuId;
+use spin::{Mutex, MutexGuard};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
-        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        mapper::MapToError, page_table::PageTableEntry, Mapper, OffsetPageTable, Page, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
 use crate::{
     constants::memory::EPHEMERAL_KERNEL_MAPPINGS_START,
+    interrupts::x2apic::current_core_id,
     memory::{
-        frame_allocator::{alloc_frame, dealloc_frame, FRAME_ALLOCATOR},
-        tlb::tlb_shootdown,
+        frame_allocator::{alloc_frame, alloc_sized_frame, dealloc_sized_frame},
+        frame_cache::CachedFrameAllocator,
+        tlb::tlb_shootdown_sized,
     },
 };
 
@@ -21,6 +32,80 @@ use super::HHDM_OFFSET;
 
 static mut NEXT_EPH_OFFSET: u64 = 0;
 
+/// Owner of a `KernelMapper` that no core holds
+const NO_OWNER: usize = usize::MAX;
+
+/// The kernel page table behind a spinlock that records which core holds it,
+/// so code that may run while its own core holds it, like the heap growing
+/// for an allocation, can tell instead of deadlocking
+pub struct KernelMapper {
+    table: Mutex<OffsetPageTable<'static>>,
+    owner: AtomicUsize,
+}
+
+/// Access to the kernel page table, held by the current core until dropped
+pub struct KernelMapperGuard<'a> {
+    table: MutexGuard<'a, OffsetPageTable<'static>>,
+    owner: &'a AtomicUsize,
+}
+
+impl KernelMapper {
+    pub fn new(table: OffsetPageTable<'static>) -> Self {
+        Self {
+            table: Mutex::new(table),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    /// Spins until the page table is free, then takes it
+    pub fn lock(&self) -> KernelMapperGuard<'_> {
+        // An interrupt must not find the lock taken but not yet owned
+        interrupts::without_interrupts(|| self.guard(self.table.lock()))
+    }
+
+    /// Takes the page table if no core holds it
+    pub fn try_lock(&self) -> Option<KernelMapperGuard<'_>> {
+        interrupts::without_interrupts(|| self.table.try_lock().map(|table| self.guard(table)))
+    }
+
+    /// Whether the current core holds the page table
+    pub fn held_by_current_core(&self) -> bool {
+        self.owner.load(Ordering::Acquire) == current_core_id()
+    }
+
+    fn guard<'a>(
+        &'a self,
+        table: MutexGuard<'a, OffsetPageTable<'static>>,
+    ) -> KernelMapperGuard<'a> {
+        self.owner.store(current_core_id(), Ordering::Release);
+        KernelMapperGuard {
+            table,
+            owner: &self.owner,
+        }
+    }
+}
+
+impl Deref for KernelMapperGuard<'_> {
+    type Target = OffsetPageTable<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.table
+    }
+}
+
+impl DerefMut for KernelMapperGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.table
+    }
+}
+
+impl Drop for KernelMapperGuard<'_> {
+    fn drop(&mut self) {
+        // Cleared before the table field unlocks
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
 /// initializes vmem system. activates pml4 and sets up page tables
 ///
 /// # Safety
@@ -50,22 +135,29 @@ pub unsafe fn active_level_4_table() -> &'static mut PageTable {
     &mut *page_table_ptr
 }
 
-/// Creates a mapping
+/// Whether the CPU can map 1 GiB pages; 2 MiB pages are always supported
+pub fn supports_1gib_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .is_some_and(|features| features.has_1gib_pages())
+}
+
+/// Creates a mapping of any page size
 /// Default flags: PRESENT | WRITABLE
 ///
 /// # Arguments
-/// * `page` - a Page that we want to map
+/// * `page` - a Page that we want to map, of 4 KiB, 2 MiB or 1 GiB
 /// * `mapper` - anything that implements a the Mapper trait
 /// * `flags` - Optional flags, can be None
 ///
 /// # Returns
 /// Returns the frame that was allocated and mapped to this page
-pub fn create_mapping(
-    page: Page,
-    mapper: &mut impl Mapper<Size4KiB>,
+pub fn create_mapping<S: PageSize + Debug>(
+    page: Page<S>,
+    mapper: &mut impl Mapper<S>,
     flags: Option<PageTableFlags>,
-) -> PhysFrame {
-    let frame = alloc_frame().expect("no more frames");
+) -> PhysFrame<S> {
+    let frame = alloc_sized_frame().expect("no more frames");
 
     let _ = unsafe {
         mapper
@@ -77,10 +169,7 @@ pub fn create_mapping(
                         | PageTableFlags::WRITABLE
                         | PageTableFlags::USER_ACCESSIBLE,
                 ),
-                FRAME_ALLOCATOR
-                    .lock()
-                    .as_mut()
-                    .expect("Global allocator not initialized"),
+                &mut CachedFrameAllocator,
             )
             .expect("Mapping failed")
     };
@@ -94,8 +183,12 @@ pub fn create_mapping(
 /// # Arguments
 /// * `page` - a Page that we want to map, must already be mapped
 /// * `mapper` - anything that implements a the Mapper trait
-/// * `frame` - the PhysFrame<Size4KiB> to map to
-pub fn update_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>, frame: PhysFrame<Size4KiB>) {
+/// * `frame` - the PhysFrame to map to, of the page's size
+pub fn update_mapping<S: PageSize>(
+    page: Page<S>,
+    mapper: &mut impl Mapper<S>,
+    frame: PhysFrame<S>,
+) {
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
     let (old_frame, _) = mapper
@@ -103,19 +196,9 @@ pub fn update_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>, frame: Phy
         .expect("Unmap failed, frame likely was not mapped already");
 
     if old_frame != frame {
-        let _ = unsafe {
-            mapper.map_to(
-                page,
-                frame,
-                flags,
-                FRAME_ALLOCATOR
-                    .lock()
-                    .as_mut()
-                    .expect("Global allocator not initialized"),
-            )
-        };
+        let _ = unsafe { mapper.map_to(page, frame, flags, &mut CachedFrameAllocator) };
 
-        tlb_shootdown(page.start_address());
+        tlb_shootdown_sized(page.start_address(), S::SIZE);
     }
 }
 
@@ -129,9 +212,9 @@ pub fn update_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>, frame: Phy
 ///
 /// # Returns
 /// Returns the frame we unmapped
-pub fn remove_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>) -> PhysFrame<Size4KiB> {
+pub fn remove_mapping<S: PageSize>(page: Page<S>, mapper: &mut impl Mapper<S>) -> PhysFrame<S> {
     let (frame, _) = mapper.unmap(page).expect("Unmap failed");
-    tlb_shootdown(page.start_address());
+    tlb_shootdown_sized(page.start_address(), S::SIZE);
     frame
 }
 
@@ -142,10 +225,10 @@ pub fn remove_mapping(page: Page, mapper: &mut impl Mapper<Size4KiB>) -> PhysFra
 /// # Arguments
 /// * `page` - a Page that we want to map, must already be mapped
 /// * `mapper` - anything that implements a the Mapper trait
-pub fn remove_mapped_frame(page: Page, mapper: &mut impl Mapper<Size4KiB>) {
+pub fn remove_mapped_frame<S: PageSize>(page: Page<S>, mapper: &mut impl Mapper<S>) {
     let (frame, _) = mapper.unmap(page).expect("map_to failed");
-    dealloc_frame(frame);
-    tlb_shootdown(page.start_address());
+    dealloc_sized_frame(frame);
+    tlb_shootdown_sized(page.start_address(), S::SIZE);
 }
 
 /// Mappes a frame to kernel pages
@@ -174,15 +257,7 @@ pub fn map_kernel_frame(
     let temp_page = Page::containing_address(temp_virt);
 
     unsafe {
-        let result = mapper.map_to(
-            temp_page,
-            frame,
-            flags,
-            FRAME_ALLOCATOR
-                .lock()
-                .as_mut()
-                .expect("Global allocator not initialized"),
-        );
+        let result = mapper.map_to(temp_page, frame, flags, &mut CachedFrameAllocator);
         result.expect("Map To Failed").flush();
     }
 
@@ -199,14 +274,125 @@ pub fn map_kernel_frame(
 /// # Safety
 ///
 /// Updating the flags for a page may result in undefined behavior
-pub fn update_permissions(page: Page, mapper: &mut impl Mapper<Size4KiB>, flags: PageTableFlags) {
+pub fn update_permissions<S: PageSize>(
+    page: Page<S>,
+    mapper: &mut impl Mapper<S>,
+    flags: PageTableFlags,
+) {
     let _ = unsafe {
         mapper
             .update_flags(page, flags)
             .expect("Updating flags failed")
     };
 
-    tlb_shootdown(page.start_address());
+    tlb_shootdown_sized(page.start_address(), S::SIZE);
+}
+
+/// Splits the huge page mapping an address into pages of the next size
+/// down, 1 GiB into 2 MiB and 2 MiB into 4 KiB, keeping its frames and flags
+///
+/// Performs a TLB Shootdown of the huge page
+///
+/// # Arguments
+/// * `mapper` - the page table holding the mapping
+/// * `addr` - any address in the huge page
+///
+/// # Returns
+/// Whether a huge page mapped `addr` and was split, or
+/// `FrameAllocationFailed` if no frame was free for the new table, in which
+/// case the huge page is left as it is
+pub fn split_huge_page(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let offset = mapper.phys_offset();
+    let mut table: &mut PageTable = mapper.level_4_table_mut();
+
+    for level in [4, 3, 2] {
+        let index = match level {
+            4 => addr.p4_index(),
+            3 => addr.p3_index(),
+            _ => addr.p2_index(),
+        };
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            return Ok(false);
+        }
+
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            unsafe { split_huge_entry(entry, level)? };
+            let huge_size = if level == 3 {
+                Size1GiB::SIZE
+            } else {
+                Size2MiB::SIZE
+            };
+            tlb_shootdown_sized(addr, huge_size);
+            return Ok(true);
+        }
+        if level == 2 {
+            return Ok(false);
+        }
+
+        table = unsafe { &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>() };
+    }
+    Ok(false)
+}
+
+/// Replaces a huge page entry by a new table of the pages of the next size
+/// down, mapping the same frames with the same flags
+///
+/// The caller must flush the huge page from every TLB
+///
+/// # Arguments
+/// * `entry` - the huge page entry
+/// * `level` - level of the table holding `entry`, 3 for 1 GiB and 2 for 2 MiB
+///
+/// # Returns
+/// `FrameAllocationFailed` if no frame was free for the new table, in which
+/// case `entry` is left as it is
+///
+/// # Safety
+/// `entry` must be a present huge page entry of a table at `level`
+pub unsafe fn split_huge_entry(
+    entry: &mut PageTableEntry,
+    level: u8,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = entry.flags();
+    let (child_size, child_flags) = if level == 3 {
+        (Size2MiB::SIZE, flags)
+    } else {
+        (Size4KiB::SIZE, flags - PageTableFlags::HUGE_PAGE)
+    };
+
+    let table_frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let child_table =
+        &mut *(*HHDM_OFFSET + table_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+    for (i, child) in child_table.iter_mut().enumerate() {
+        child.set_addr(entry.addr() + i as u64 * child_size, child_flags);
+    }
+
+    // Permissions stay on the leaves, so the new table allows everything
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_addr(table_frame.start_address(), table_flags);
+    Ok(())
+}
+
+/// Splits whatever huge page maps an address down to 4 KiB pages
+///
+/// # Arguments
+/// * `mapper` - the page table holding the mapping
+/// * `addr` - any address in the huge page
+///
+/// # Returns
+/// `FrameAllocationFailed` if no frame was free for a new table
+pub fn split_to_4kib(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    while split_huge_page(mapper, addr)? {}
+    Ok(())
 }
 
 /// Returns a reference to the page table entry for the given page.
@@ -252,6 +438,7 @@ unsafe fn get_page_table_entry<'a>(
 #[cfg(test)]
 mod tests {
     use core::{
+        future::Future,
         ptr::{read_volatile, write_volatile},
         sync::atomic::{AtomicU64, Ordering},
     };
@@ -340,8 +527,9 @@ mod tests {
 
         let mut frames = Vec::new();
         for i in 0..num_pages {
-            let page = Page::from_start_address(start_page.start_address() + i * PAGE_SIZE as u64)
-                .expect("Invalid page address");
+            let page: Page =
+                Page::from_start_address(start_page.start_address() + i * PAGE_SIZE as u64)
+                    .expect("Invalid page address");
             let frame = create_mapping(page, &mut *mapper, Some(flags));
             frames.push((page, frame));
         }
@@ -360,6 +548,36 @@ mod tests {
         }
     }
 
+    // Test that a 2 MiB page maps its frames contiguously and that splitting
+    // it keeps them mapped, now as 4 KiB pages
+    #[test_case]
+    fn test_huge_page_split() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut mapper = MAPPER.lock();
+            let huge_page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x600000000));
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let frame = create_mapping(huge_page, &mut *mapper, Some(flags));
+
+            let last = huge_page.start_address() + (Size2MiB::SIZE - 8);
+            unsafe { write_volatile(last.as_mut_ptr::<u64>(), 0x42) };
+
+            assert!(matches!(split_huge_page(&mut mapper, last), Ok(true)));
+            assert!(matches!(split_huge_page(&mut mapper, last), Ok(false)));
+
+            let last_page: Page = Page::containing_address(last);
+            let expected = PhysFrame::containing_address(
+                frame.start_address() + (Size2MiB::SIZE - PAGE_SIZE as u64),
+            );
+            assert_eq!(mapper.translate_page(last_page).unwrap(), expected);
+            assert_eq!(unsafe { read_volatile(last.as_ptr::<u64>()) }, 0x42);
+
+            let first_page = Page::containing_address(huge_page.start_address());
+            for page in Page::range(first_page, last_page + 1) {
+                remove_mapped_frame(page, &mut *mapper);
+            }
+        }
+    }
+
     // Goal: Create a mapping and access it on some core such that it is cached.
     // Then, change the mapping to map to a different frame such that a TLB Shootdown
     // is necessary.
@@ -0,0 +1,418 @@
+//! Slab allocator
+//!
+//! Sits in front of the Talc heap as the global allocator. Small objects come
+//! from object caches, each holding objects of a single size carved out of
+//! page-aligned slabs taken from Talc, so the hot objects of the event loop
+//! neither fragment the heap nor wait on its lock.
+//!
+//! - Hot kernel types get a dedicated cache, declared next to the type and
+//!   listed in `DEDICATED_CACHES`. An allocation whose layout matches one
+//!   exactly is served from it, so an `Arc<Event>` or `Arc<UnsafePCB>` uses
+//!   its cache without changing how it is created.
+//! - Other allocations up to the largest size class use the smallest class
+//!   that fits, and larger ones go to Talc directly
+//! - Each cache keeps a magazine of free objects per core, used with
+//!   interrupts disabled, and refills or drains it a batch at a time from a
+//!   free list shared by all cores
+//!
+//! Slabs are never given back to Talc; freed objects stay in their cache.
+
+use alloc::vec::Vec;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+use crate::{
+    constants::{
+        memory::{PAGE_SIZE, SLAB_MAGAZINE_BATCH, SLAB_MAGAZINE_SIZE, SLAB_MIN_OBJECTS, SLAB_SIZE},
+        MAX_CORES,
+    },
+    events::EVENT_CACHE,
+    interrupts::{idt::without_interrupts, x2apic::current_core_id},
+    processes::{process::PCB_CACHE, thread::TCB_CACHE},
+    serial_println,
+};
+
+/// Caches for hot kernel types, checked before the size classes
+static DEDICATED_CACHES: [&SlabCache; 3] = [&EVENT_CACHE, &PCB_CACHE, &TCB_CACHE];
+
+/// Caches for any other small allocation, by increasing size
+static SIZE_CLASSES: [SlabCache; 8] = [
+    SlabCache::new("size-16", 16, 16),
+    SlabCache::new("size-32", 32, 32),
+    SlabCache::new("size-64", 64, 64),
+    SlabCache::new("size-128", 128, 128),
+    SlabCache::new("size-256", 256, 256),
+    SlabCache::new("size-512", 512, 512),
+    SlabCache::new("size-1024", 1024, 1024),
+    SlabCache::new("size-2048", 2048, 2048),
+];
+
+/// Free objects of a cache held by one core, as a stack of addresses
+struct Magazine {
+    objects: [usize; SLAB_MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [0; SLAB_MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, object: usize) {
+        self.objects[self.len] = object;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.objects[self.len])
+    }
+}
+
+/// Free objects shared by all cores, linked through their first word
+struct FreeList {
+    head: usize,
+    len: usize,
+}
+
+impl FreeList {
+    fn push(&mut self, object: usize) {
+        unsafe { *(object as *mut usize) = self.head };
+        self.head = object;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let object = self.head;
+        self.head = unsafe { *(object as *const usize) };
+        self.len -= 1;
+        Some(object)
+    }
+}
+
+/// A snapshot of a cache's counters
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    /// Bytes per object, after rounding
+    pub object_size: usize,
+    /// Slabs taken from the heap
+    pub slabs: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl SlabStats {
+    /// Objects allocated and not yet freed
+    pub fn in_use(&self) -> u64 {
+        self.allocations.saturating_sub(self.frees)
+    }
+}
+
+/// Objects of one size and alignment
+pub struct SlabCache {
+    name: &'static str,
+    /// Layout of the allocations served
+    layout_size: usize,
+    layout_align: usize,
+    /// Size of each object, a multiple of `align` large enough to link it
+    size: usize,
+    align: usize,
+    magazines: [Mutex<Magazine>; MAX_CORES],
+    depot: Mutex<FreeList>,
+    slabs: AtomicUsize,
+    allocations: AtomicU64,
+    frees: AtomicU64,
+}
+
+impl SlabCache {
+    /// Creates an empty cache for allocations of `size` bytes aligned to
+    /// `align`
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let object_align = if align > align_of::<usize>() {
+            align
+        } else {
+            align_of::<usize>()
+        };
+        let object_size = if size > size_of::<usize>() {
+            size
+        } else {
+            size_of::<usize>()
+        };
+        Self {
+            name,
+            layout_size: size,
+            layout_align: align,
+            size: object_size.next_multiple_of(object_align),
+            align: object_align,
+            magazines: [const { Mutex::new(Magazine::new()) }; MAX_CORES],
+            depot: Mutex::new(FreeList { head: 0, len: 0 }),
+            slabs: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            frees: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a cache for `Box<T>`
+    pub const fn for_box<T>(name: &'static str) -> Self {
+        Self::new(name, size_of::<T>(), align_of::<T>())
+    }
+
+    /// Creates a cache for `Arc<T>`, which keeps its two reference counts in
+    /// front of the value
+    pub const fn for_arc<T>(name: &'static str) -> Self {
+        let align = if align_of::<T>() > align_of::<usize>() {
+            align_of::<T>()
+        } else {
+            align_of::<usize>()
+        };
+        let header = (2 * size_of::<usize>()).next_multiple_of(align);
+        Self::new(
+            name,
+            (header + size_of::<T>()).next_multiple_of(align),
+            align,
+        )
+    }
+
+    /// Takes a free object, refilling this core's magazine if it is empty
+    ///
+    /// # Arguments
+    /// * `backing`: allocator new slabs are taken from
+    ///
+    /// # Returns
+    /// The object, or null if the backing allocator is out of memory
+    fn alloc(&self, backing: &impl GlobalAlloc) -> *mut u8 {
+        let object = without_interrupts(|| {
+            let mut magazine = self.magazines[current_core_id()].lock();
+            if magazine.len == 0 {
+                self.refill(&mut magazine, backing);
+            }
+            magazine.pop()
+        });
+
+        match object {
+            Some(object) => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                object as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Returns an object to this core's magazine, draining a batch to the
+    /// shared free list first if it is full
+    fn free(&self, object: *mut u8) {
+        without_interrupts(|| {
+            let mut magazine = self.magazines[current_core_id()].lock();
+            if magazine.len == SLAB_MAGAZINE_SIZE {
+                let mut depot = self.depot.lock();
+                for _ in 0..SLAB_MAGAZINE_BATCH {
+                    if let Some(object) = magazine.pop() {
+                        depot.push(object);
+                    }
+                }
+            }
+            magazine.push(object as usize);
+        });
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Moves a batch of objects from the shared free list into a magazine,
+    /// carving a new slab if the list is empty
+    fn refill(&self, magazine: &mut Magazine, backing: &impl GlobalAlloc) {
+        let mut depot = self.depot.lock();
+        if depot.len == 0 {
+            self.grow(&mut depot, backing);
+        }
+        while magazine.len < SLAB_MAGAZINE_BATCH {
+            let Some(object) = depot.pop() else {
+                break;
+            };
+            magazine.push(object);
+        }
+    }
+
+    /// Takes a slab from the backing allocator and adds its objects to the
+    /// shared free list
+    fn grow(&self, depot: &mut FreeList, backing: &impl GlobalAlloc) {
+        let slab_size = SLAB_SIZE
+            .max(self.size * SLAB_MIN_OBJECTS)
+            .next_multiple_of(PAGE_SIZE);
+        let Ok(layout) = Layout::from_size_align(slab_size, self.align.max(PAGE_SIZE)) else {
+            return;
+        };
+        let slab = unsafe { backing.alloc(layout) };
+        if slab.is_null() {
+            return;
+        }
+        // Pushed from the end so objects are handed out in address order
+        for index in (0..slab_size / self.size).rev() {
+            depot.push(slab as usize + index * self.size);
+        }
+        self.slabs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether this dedicated cache serves allocations of `layout`
+    fn serves(&self, layout: Layout) -> bool {
+        layout.size() == self.layout_size && layout.align() == self.layout_align
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.size,
+            slabs: self.slabs.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The cache that serves allocations of `layout`, if any
+fn cache_for(layout: Layout) -> Option<&'static SlabCache> {
+    if let Some(cache) = DEDICATED_CACHES.iter().find(|cache| cache.serves(layout)) {
+        return Some(cache);
+    }
+    let needed = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().find(|cache| cache.size >= needed)
+}
+
+/// Counters of every cache
+pub fn slab_stats() -> Vec<SlabStats> {
+    DEDICATED_CACHES
+        .iter()
+        .copied()
+        .chain(SIZE_CLASSES.iter())
+        .map(SlabCache::stats)
+        .collect()
+}
+
+/// Prints the counters of every cache
+pub fn print_slab_stats() {
+    for stats in slab_stats() {
+        serial_println!(
+            "{}: {} bytes, {} slabs, {} in use, {} allocations, {} frees",
+            stats.name,
+            stats.object_size,
+            stats.slabs,
+            stats.in_use(),
+            stats.allocations,
+            stats.frees
+        );
+    }
+}
+
+/// Global allocator serving small objects from slab caches and everything
+/// else from `backing`, which also provides the slabs
+pub struct SlabAllocator<A> {
+    backing: A,
+}
+
+impl<A> SlabAllocator<A> {
+    pub const fn new(backing: A) -> Self {
+        Self { backing }
+    }
+
+    /// The allocator behind the caches
+    pub fn backing(&self) -> &A {
+        &self.backing
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for SlabAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match cache_for(layout) {
+            Some(cache) => cache.alloc(&self.backing),
+            None => self.backing.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match cache_for(layout) {
+            Some(cache) => cache.free(ptr),
+            None => self.backing.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (cache_for(layout), cache_for(new_layout)) {
+            (None, None) => self.backing.realloc(ptr, layout, new_size),
+            // The object already has room for the new size
+            (Some(old), Some(new)) if ptr::eq(old, new) => ptr,
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec};
+    use core::future::Future;
+
+    #[test_case]
+    fn test_size_classes() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let cache = |size, align| cache_for(Layout::from_size_align(size, align).unwrap());
+            assert_eq!(cache(1, 1).unwrap().name, "size-16");
+            assert_eq!(cache(40, 8).unwrap().name, "size-64");
+            assert_eq!(cache(8, 256).unwrap().name, "size-256");
+            assert!(cache(4096, 8).is_none());
+
+            // A freed object is the next one handed out on the same core
+            let first = Box::new([0u64; 5]);
+            let address = &*first as *const _ as usize;
+            let before = cache(40, 8).unwrap().stats();
+            drop(first);
+            let second = Box::new([1u64; 5]);
+            assert_eq!(&*second as *const _ as usize, address);
+            let after = cache(40, 8).unwrap().stats();
+            assert_eq!(after.allocations, before.allocations + 1);
+            assert_eq!(after.frees, before.frees + 1);
+
+            // Growing within a class keeps the object in place
+            let mut bytes = vec![0u8; 20];
+            let address = bytes.as_ptr();
+            bytes.reserve_exact(10);
+            assert_eq!(bytes.as_ptr(), address);
+        }
+    }
+
+    #[test_case]
+    fn test_dedicated_cache() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            // Two reference counts, then the value
+            let arc = SlabCache::for_arc::<u64>("test");
+            assert!(arc.serves(Layout::from_size_align(24, 8).unwrap()));
+
+            let before = PCB_CACHE.stats();
+            let layout =
+                Layout::from_size_align(PCB_CACHE.layout_size, PCB_CACHE.layout_align).unwrap();
+            assert!(ptr::eq(cache_for(layout).unwrap(), &PCB_CACHE));
+            let object = unsafe { alloc::alloc::alloc(layout) };
+            assert!(!object.is_null());
+            unsafe { alloc::alloc::dealloc(object, layout) };
+            let after = PCB_CACHE.stats();
+            assert_eq!(after.allocations, before.allocations + 1);
+            assert_eq!(after.in_use(), before.in_use());
+        }
+    }
+}
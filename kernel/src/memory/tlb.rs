@@ -1,33 +1,177 @@
 //! Translation Lookaside Buffer Shootdowns
 //!
-//! - Exposes a function to perform TLB Shootdowns
+//! - Exposes functions to perform TLB Shootdowns of mappings of any page size
+//!
+//! Each core has a pending range to invalidate per page size, which
+//! shootdowns sent before it takes the interrupt widen rather than overwrite.
+//! A few pages are invalidated one by one, whatever their size; more flush
+//! the core's whole TLB, global entries included.
 
 use crate::{
-    constants::{idt::TLB_SHOOTDOWN_VECTOR, MAX_CORES},
-    interrupts::x2apic::{current_core_id, send_ipi, TLB_SHOOTDOWN_ADDR},
+    constants::{
+        idt::TLB_SHOOTDOWN_VECTOR,
+        memory::{PAGE_SIZE, TLB_FLUSH_ALL_PAGES},
+        MAX_CORES,
+    },
+    interrupts::x2apic::{core_online, current_core_id, send_ipi, TLB_SHOOTDOWN_RANGES},
 };
 use core::arch::asm;
-use x86_64::VirtAddr;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::{Cr4, Cr4Flags},
+    structures::paging::{PageSize, Size1GiB, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+/// Page sizes with a pending range each, in the order ranges are kept
+const PAGE_SIZES: [u64; 3] = [Size4KiB::SIZE, Size2MiB::SIZE, Size1GiB::SIZE];
 
 /// Sends an inter-process interrupt to all other cores to clear TLB entry with a specific VA
 ///
 /// # Arguments:
 /// * target_vaddr: VA that has to be flushed in all TLBs
 pub fn tlb_shootdown(target_vaddr: VirtAddr) {
+    tlb_shootdown_sized(target_vaddr, PAGE_SIZE as u64);
+}
+
+/// Sends an inter-process interrupt to all other cores to clear the TLB
+/// entries of a mapping of any page size
+///
+/// # Arguments:
+/// * target_vaddr: VA within the mapping
+/// * size: size of the mapping's page, 4 KiB, 2 MiB or 1 GiB
+pub fn tlb_shootdown_sized(target_vaddr: VirtAddr, size: u64) {
     let current_core = current_core_id();
-    let vaddr = target_vaddr.as_u64();
+    let start = target_vaddr.align_down(size).as_u64();
+    let end = start + size;
+    let kind = PAGE_SIZES.iter().position(|&s| s == size).unwrap_or(0);
 
     {
         // Acquire the lock and update all cores except the current one.
-        let mut addresses = TLB_SHOOTDOWN_ADDR.lock();
+        let mut ranges = TLB_SHOOTDOWN_RANGES.lock();
         for core in 0..MAX_CORES {
             if core != current_core {
-                addresses[core] = vaddr;
+                let (pending_start, pending_end) = ranges[core][kind];
+                ranges[core][kind] = if pending_start == pending_end {
+                    (start, end)
+                } else {
+                    (pending_start.min(start), pending_end.max(end))
+                };
                 send_ipi(core as u32, TLB_SHOOTDOWN_VECTOR);
             }
         }
     }
 
+    // A single INVLPG drops a translation whatever page size it is cached at
+    invlpg(start);
+}
+
+/// Invalidates the page-aligned range `[start, end)` in every core's TLB,
+/// whatever the size of the pages mapping it, and waits until the other
+/// cores have done so, e.g. before freeing the frames of unmapped pages
+pub fn tlb_shootdown_range(start: u64, end: u64) {
+    let current_core = current_core_id();
+    {
+        let mut ranges = TLB_SHOOTDOWN_RANGES.lock();
+        for core in 0..MAX_CORES {
+            if core != current_core {
+                let (pending_start, pending_end) = ranges[core][0];
+                ranges[core][0] = if pending_start == pending_end {
+                    (start, end)
+                } else {
+                    (pending_start.min(start), pending_end.max(end))
+                };
+                send_ipi(core as u32, TLB_SHOOTDOWN_VECTOR);
+            }
+        }
+    }
+
+    if (end - start) / PAGE_SIZE as u64 > TLB_FLUSH_ALL_PAGES {
+        flush_all();
+    } else {
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            invlpg(vaddr);
+        }
+    }
+    wait_for_shootdowns(current_core);
+}
+
+/// Flushes every core's whole TLB, e.g. after write-protecting a whole
+/// address space, and waits until the other cores have done so
+pub fn tlb_shootdown_all() {
+    let current_core = current_core_id();
+    {
+        let mut ranges = TLB_SHOOTDOWN_RANGES.lock();
+        for core in 0..MAX_CORES {
+            if core != current_core {
+                ranges[core][0] = (0, u64::MAX);
+                send_ipi(core as u32, TLB_SHOOTDOWN_VECTOR);
+            }
+        }
+    }
+    flush_all();
+    wait_for_shootdowns(current_core);
+}
+
+/// Waits until every other online core has invalidated what was queued for
+/// it, servicing this core's own queue meanwhile
+fn wait_for_shootdowns(current_core: usize) {
+    let pending = || {
+        let ranges = TLB_SHOOTDOWN_RANGES.lock();
+        (0..MAX_CORES).any(|core| {
+            core != current_core
+                && ranges[core].iter().any(|&(start, end)| start != end)
+                && core_online(core)
+        })
+    };
+    while pending() {
+        // This core may have interrupts off while another one waits on it
+        flush_pending_shootdown();
+        core::hint::spin_loop();
+    }
+}
+
+/// Invalidates the ranges other cores queued for this one; called by the
+/// shootdown interrupt handler
+pub fn flush_pending_shootdown() {
+    let core = current_core_id();
+    let ranges = core::mem::take(&mut TLB_SHOOTDOWN_RANGES.lock()[core]);
+    let pages: u64 = ranges
+        .iter()
+        .zip(PAGE_SIZES)
+        .map(|(&(start, end), size)| (end - start).div_ceil(size))
+        .sum();
+    if pages == 0 {
+        return;
+    }
+
+    if pages > TLB_FLUSH_ALL_PAGES {
+        flush_all();
+    } else {
+        // A single INVLPG drops a translation whatever page size it is cached at
+        for (&(start, end), size) in ranges.iter().zip(PAGE_SIZES) {
+            for vaddr in (start..end).step_by(size as usize) {
+                invlpg(vaddr);
+            }
+        }
+    }
+}
+
+/// Flushes this core's whole TLB. Reloading CR3 keeps global entries, so
+/// toggling CR4.PGE drops those too when global pages are enabled.
+fn flush_all() {
+    let cr4 = Cr4::read();
+    if !cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        tlb::flush_all();
+        return;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(cr4);
+    });
+}
+
+fn invlpg(vaddr: u64) {
     unsafe {
         asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags));
     }
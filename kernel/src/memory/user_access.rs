@@ -0,0 +1,278 @@
+//! User memory access
+//!
+//! - Validates user pointers against a process page table, first mapping any
+//!   untouched pages of the process' memory areas
+//! - Copies between kernel buffers and user memory through the HHDM, so a bad
+//!   pointer yields `EFAULT` instead of a kernel page fault
+//!
+//! Each access holds the process' `vmas` lock throughout, so another thread
+//! cannot unmap the pages while they are copied.
+
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+use crate::{
+    constants::{
+        errno::EFAULT,
+        memory::{PAGE_SIZE, USER_SPACE_END},
+    },
+    memory::{cow::break_cow_range, vma::fault_in_range, HHDM_OFFSET},
+    processes::process::PCB,
+};
+
+/// Copies `dst.len()` bytes from user memory into a kernel buffer.
+///
+/// # Arguments
+/// * `pcb` - the process that owns `user_src`
+/// * `dst` - kernel buffer to fill
+/// * `user_src` - user virtual address to copy from
+///
+/// # Returns
+/// `Err(EFAULT)` if any byte is not mapped user-accessible
+pub fn copy_from_user(pcb: &PCB, dst: &mut [u8], user_src: u64) -> Result<(), i64> {
+    let mut vmas = pcb.lock_vmas();
+    fault_in_range(pcb, &mut vmas, user_src, dst.len(), false);
+    let mapper = unsafe { pcb.create_mapper() };
+    copy_from_user_with(&mapper, dst, user_src)
+}
+
+/// Copies a kernel buffer into user memory.
+///
+/// Copy-on-write pages in the range are copied first, as a user write would.
+///
+/// # Arguments
+/// * `pcb` - the process that owns `user_dst`
+/// * `user_dst` - user virtual address to copy to
+/// * `src` - kernel buffer to copy from
+///
+/// # Returns
+/// `Err(EFAULT)` if any byte is not mapped user-accessible and writable
+pub fn copy_to_user(pcb: &PCB, user_dst: u64, src: &[u8]) -> Result<(), i64> {
+    let mut vmas = pcb.lock_vmas();
+    fault_in_range(pcb, &mut vmas, user_dst, src.len(), true);
+    break_cow_range(pcb.pml4_frame, user_dst, src.len());
+    let mapper = unsafe { pcb.create_mapper() };
+    copy_to_user_with(&mapper, user_dst, src)
+}
+
+/// Copies a NUL-terminated string from user memory into a kernel buffer.
+///
+/// Stops at the first NUL byte or after `dst.len()` bytes, whichever is first.
+/// The NUL itself is copied when it fits.
+///
+/// # Arguments
+/// * `pcb` - the process that owns `user_src`
+/// * `dst` - kernel buffer to fill
+/// * `user_src` - user virtual address of the string
+///
+/// # Returns
+/// The length of the string without the NUL, or `dst.len()` if no NUL was
+/// found. `Err(EFAULT)` if the string runs into unmapped memory.
+pub fn strncpy_from_user(pcb: &PCB, dst: &mut [u8], user_src: u64) -> Result<usize, i64> {
+    let mut vmas = pcb.lock_vmas();
+    fault_in_range(pcb, &mut vmas, user_src, dst.len(), false);
+    let mapper = unsafe { pcb.create_mapper() };
+    strncpy_from_user_with(&mapper, dst, user_src)
+}
+
+/// Checks that `[addr, addr + len)` is entirely mapped for user access,
+/// mapping untouched pages of the process' memory areas first.
+///
+/// When `write` is set, copy-on-write pages in the range are copied so the
+/// check reflects what a write would see.
+///
+/// # Arguments
+/// * `pcb` - the process that owns the range
+/// * `addr` - start of the range
+/// * `len` - length of the range in bytes
+/// * `write` - whether the range must also be writable
+pub fn access_ok(pcb: &PCB, addr: u64, len: usize, write: bool) -> bool {
+    let mut vmas = pcb.lock_vmas();
+    fault_in_range(pcb, &mut vmas, addr, len, write);
+    if write {
+        break_cow_range(pcb.pml4_frame, addr, len);
+    }
+    let mapper = unsafe { pcb.create_mapper() };
+    for_each_user_chunk(&mapper, addr, len, required_flags(write), |_, _, _| {}).is_ok()
+}
+
+fn required_flags(write: bool) -> PageTableFlags {
+    if write {
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+    }
+}
+
+fn copy_from_user_with(mapper: &impl Translate, dst: &mut [u8], user_src: u64) -> Result<(), i64> {
+    let len = dst.len();
+    for_each_user_chunk(
+        mapper,
+        user_src,
+        len,
+        required_flags(false),
+        |kernel_ptr, done, chunk| unsafe {
+            core::ptr::copy_nonoverlapping(kernel_ptr, dst.as_mut_ptr().add(done), chunk);
+        },
+    )
+}
+
+fn copy_to_user_with(mapper: &impl Translate, user_dst: u64, src: &[u8]) -> Result<(), i64> {
+    for_each_user_chunk(
+        mapper,
+        user_dst,
+        src.len(),
+        required_flags(true),
+        |kernel_ptr, done, chunk| unsafe {
+            core::ptr::copy_nonoverlapping(src.as_ptr().add(done), kernel_ptr, chunk);
+        },
+    )
+}
+
+fn strncpy_from_user_with(
+    mapper: &impl Translate,
+    dst: &mut [u8],
+    user_src: u64,
+) -> Result<usize, i64> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = user_src.checked_add(copied as u64).ok_or(EFAULT)?;
+        let chunk = (PAGE_SIZE - (addr as usize % PAGE_SIZE)).min(dst.len() - copied);
+        let kernel_ptr = translate_user(mapper, addr, required_flags(false))?;
+
+        for i in 0..chunk {
+            let byte = unsafe { *kernel_ptr.add(i) };
+            dst[copied] = byte;
+            if byte == 0 {
+                return Ok(copied);
+            }
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Walks `[addr, addr + len)` one page-bounded chunk at a time, handing `f` a
+/// kernel pointer to each chunk, the bytes processed so far and the chunk size.
+fn for_each_user_chunk(
+    mapper: &impl Translate,
+    addr: u64,
+    len: usize,
+    required: PageTableFlags,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) -> Result<(), i64> {
+    let end = addr.checked_add(len as u64).ok_or(EFAULT)?;
+    if end > USER_SPACE_END {
+        return Err(EFAULT);
+    }
+
+    let mut done = 0;
+    while done < len {
+        let current = addr + done as u64;
+        let chunk = (PAGE_SIZE - (current as usize % PAGE_SIZE)).min(len - done);
+        let kernel_ptr = translate_user(mapper, current, required)?;
+        f(kernel_ptr, done, chunk);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Resolves a user address to its HHDM alias if it is mapped with `required`.
+fn translate_user(
+    mapper: &impl Translate,
+    addr: u64,
+    required: PageTableFlags,
+) -> Result<*mut u8, i64> {
+    if addr >= USER_SPACE_END {
+        return Err(EFAULT);
+    }
+
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } if flags.contains(required) => {
+            let phys = frame.start_address() + offset;
+            Ok((*HHDM_OFFSET + phys.as_u64()).as_mut_ptr())
+        }
+        _ => Err(EFAULT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{
+        paging::{create_mapping, remove_mapped_frame},
+        MAPPER,
+    };
+    use core::future::Future;
+    use x86_64::structures::paging::Page;
+
+    #[test_case]
+    fn test_copy_roundtrip_across_pages() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut mapper = MAPPER.lock();
+            let first: Page = Page::containing_address(VirtAddr::new(0x500000000));
+            let second = first + 1;
+            create_mapping(first, &mut *mapper, None);
+            create_mapping(second, &mut *mapper, None);
+
+            // Straddle the page boundary
+            let user_addr = second.start_address().as_u64() - 4;
+            let src = *b"straddle";
+            copy_to_user_with(&*mapper, user_addr, &src).expect("copy_to_user failed");
+
+            let mut dst = [0u8; 8];
+            copy_from_user_with(&*mapper, &mut dst, user_addr).expect("copy_from_user failed");
+            assert_eq!(dst, src);
+
+            remove_mapped_frame(first, &mut *mapper);
+            remove_mapped_frame(second, &mut *mapper);
+        }
+    }
+
+    #[test_case]
+    fn test_bad_pointers_fault() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mapper = MAPPER.lock();
+            let mut dst = [0u8; 4];
+
+            assert_eq!(copy_from_user_with(&*mapper, &mut dst, 0), Err(EFAULT));
+            assert_eq!(
+                copy_from_user_with(&*mapper, &mut dst, USER_SPACE_END - 2),
+                Err(EFAULT)
+            );
+            assert_eq!(
+                strncpy_from_user_with(&*mapper, &mut dst, u64::MAX),
+                Err(EFAULT)
+            );
+        }
+    }
+
+    #[test_case]
+    fn test_strncpy_stops_at_nul() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut mapper = MAPPER.lock();
+            let page: Page = Page::containing_address(VirtAddr::new(0x500000000));
+            create_mapping(page, &mut *mapper, None);
+            let user_addr = page.start_address().as_u64();
+            copy_to_user_with(&*mapper, user_addr, b"path\0junk").expect("copy_to_user failed");
+
+            let mut dst = [0u8; 16];
+            assert_eq!(strncpy_from_user_with(&*mapper, &mut dst, user_addr), Ok(4));
+            assert_eq!(&dst[..5], b"path\0");
+
+            let mut short = [0u8; 2];
+            assert_eq!(
+                strncpy_from_user_with(&*mapper, &mut short, user_addr),
+                Ok(2)
+            );
+
+            remove_mapped_frame(page, &mut *mapper);
+        }
+    }
+}
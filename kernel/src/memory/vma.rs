@@ -0,0 +1,735 @@
+//! Virtual memory areas
+//!
+//! - Records the ranges of user memory a process may access, with their
+//!   permissions and what backs them
+//! - Maps pages on first access: anonymous pages are zero-filled, file-backed
+//!   pages are filled from the file
+//! - Grows stacks down on faults below them, up to the stack limit and never
+//!   closer than a guard page to the area below
+//! - Unmaps and reprotects ranges for `munmap`, `mprotect` and `brk`
+//!
+//! Pages of an area are only mapped once touched, either by the process
+//! faulting on them or by the kernel copying to or from user memory. Anonymous
+//! areas covering a whole 2 MiB block get a huge page for it, which is split
+//! when only part of it is unmapped or reprotected; if no frame is left for
+//! the split, the unmap or reprotect fails.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ptr::{copy_nonoverlapping, write_bytes};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateError, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    constants::{
+        memory::{HUGE_PAGE_SIZE, PAGE_SIZE, USER_SPACE_END},
+        processes::{MMAP_START, STACK_LIMIT},
+    },
+    memory::{
+        cow::{frame_refcount, release_frame, COW_FLAG},
+        frame_allocator::{alloc_frame, alloc_sized_frame, dealloc_frame, dealloc_sized_frame},
+        frame_cache::CachedFrameAllocator,
+        paging::{split_huge_page, update_permissions},
+        tlb::tlb_shootdown_range,
+        HHDM_OFFSET,
+    },
+    processes::process::PCB,
+};
+
+/// Frames in a 2 MiB page, counted towards `VmaList::resident`
+const HUGE_PAGE_FRAMES: u64 = (HUGE_PAGE_SIZE / PAGE_SIZE) as u64;
+
+/// Rounds `addr` down to a page boundary
+pub fn page_align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE as u64 - 1)
+}
+
+/// Rounds `addr` up to a page boundary
+pub fn page_align_up(addr: u64) -> u64 {
+    page_align_down(addr + PAGE_SIZE as u64 - 1)
+}
+
+/// What fills a page of an area the first time it is accessed
+#[derive(Debug, Clone)]
+pub enum Backing {
+    /// Zero-filled memory
+    Anonymous,
+    /// `size` bytes of `data`, starting at `offset`, appear at `vaddr`; the
+    /// rest of the area is zero-filled
+    File {
+        data: Arc<[u8]>,
+        offset: u64,
+        vaddr: u64,
+        size: u64,
+    },
+}
+
+/// A page-aligned range of user memory
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    /// Flags of the pages mapped in the area
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// Extends downwards on accesses just below `start`
+    pub grows_down: bool,
+}
+
+impl Vma {
+    /// A zero-filled area
+    pub fn anonymous(start: u64, end: u64, flags: PageTableFlags) -> Self {
+        Self {
+            start,
+            end,
+            flags,
+            backing: Backing::Anonymous,
+            grows_down: false,
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Whether the area's pages may be accessed in this way
+    pub fn permits(&self, access: Access) -> bool {
+        flags_permit(self.flags, access)
+    }
+
+    /// Writes the initial contents of the page at `page_addr` to `dst`
+    ///
+    /// # Safety
+    /// `dst` must be valid for writes of a whole page
+    unsafe fn fill_page(&self, page_addr: u64, dst: *mut u8) {
+        write_bytes(dst, 0, PAGE_SIZE);
+
+        if let Backing::File {
+            data,
+            offset,
+            vaddr,
+            size,
+        } = &self.backing
+        {
+            let from = page_addr.max(*vaddr);
+            let to = (page_addr + PAGE_SIZE as u64).min(vaddr + size);
+            if from < to {
+                let src = (offset + (from - vaddr)) as usize;
+                copy_nonoverlapping(
+                    data[src..].as_ptr(),
+                    dst.add((from - page_addr) as usize),
+                    (to - from) as usize,
+                );
+            }
+        }
+    }
+}
+
+/// Flags a page needs for the process to read it. Inaccessible areas keep
+/// their pages present but not user-accessible, so their contents survive.
+const USER_READABLE: PageTableFlags =
+    PageTableFlags::PRESENT.union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Whether a page with `flags` may be accessed in this way
+fn flags_permit(flags: PageTableFlags, access: Access) -> bool {
+    match access {
+        Access::Read => flags.contains(USER_READABLE),
+        Access::Write => flags.contains(USER_READABLE | PageTableFlags::WRITABLE),
+        Access::Execute => {
+            flags.contains(USER_READABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+        }
+    }
+}
+
+/// Kind of access that touched a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The areas of one address space, keyed by start address
+#[derive(Debug, Clone)]
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+    /// Largest size in bytes a stack may grow to, measured from its top
+    pub stack_limit: u64,
+    /// Start of the heap, just past the program image
+    pub brk_start: u64,
+    /// Current program break
+    pub brk: u64,
+    /// Pages of the areas currently mapped to a frame
+    pub resident: u64,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+            stack_limit: STACK_LIMIT,
+            brk_start: 0,
+            brk: 0,
+            resident: 0,
+        }
+    }
+
+    /// Adds an area
+    ///
+    /// # Returns
+    /// False if the area overlaps an existing one, in which case nothing is
+    /// added
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        debug_assert!(vma.start % PAGE_SIZE as u64 == 0 && vma.end % PAGE_SIZE as u64 == 0);
+        if vma.start >= vma.end {
+            return false;
+        }
+        if self
+            .areas
+            .range(..vma.end)
+            .next_back()
+            .is_some_and(|(_, below)| below.end > vma.start)
+        {
+            return false;
+        }
+        self.areas.insert(vma.start, vma);
+        true
+    }
+
+    /// Finds the area containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Removes every area and the heap
+    pub fn clear(&mut self) {
+        self.areas.clear();
+        self.brk_start = 0;
+        self.brk = 0;
+        self.resident = 0;
+    }
+
+    /// Whether every address in `[start, end)` lies in some area
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Removes the page-aligned range `[start, end)`, splitting areas that
+    /// straddle either end
+    ///
+    /// # Returns
+    /// The removed pieces of areas
+    pub fn remove_range(&mut self, start: u64, end: u64) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<u64> = self.areas.range(start..end).map(|(&s, _)| s).collect();
+        starts
+            .into_iter()
+            .filter_map(|start| self.areas.remove(&start))
+            .collect()
+    }
+
+    /// Gives every area in the page-aligned range `[start, end)` new page
+    /// flags, splitting areas that straddle either end
+    pub fn protect_range(&mut self, start: u64, end: u64, flags: PageTableFlags) {
+        self.split_at(start);
+        self.split_at(end);
+        for vma in self.areas.range_mut(start..end).map(|(_, vma)| vma) {
+            vma.flags = flags;
+        }
+    }
+
+    /// Finds a free page-aligned range of `len` bytes, at `hint` if that is
+    /// free and otherwise at the lowest address from `MMAP_START` on. Room
+    /// for stacks to grow to their limit is kept free.
+    pub fn find_free(&self, len: u64, hint: u64) -> Option<u64> {
+        let hint = page_align_down(hint);
+        if hint != 0 && self.is_free(hint, len) {
+            return Some(hint);
+        }
+
+        let mut candidate = MMAP_START;
+        for vma in self.areas.values() {
+            let limit = self.lowest_start(vma);
+            if limit <= candidate {
+                candidate = candidate.max(vma.end);
+                continue;
+            }
+            if limit - candidate >= len {
+                return Some(candidate);
+            }
+            candidate = vma.end;
+        }
+        let end = candidate.checked_add(len)?;
+        (end <= USER_SPACE_END).then_some(candidate)
+    }
+
+    /// Whether `[start, start + len)` lies in user space, clear of every
+    /// area and of the room stacks may grow into
+    pub fn is_free(&self, start: u64, len: u64) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        end <= USER_SPACE_END
+            && self
+                .areas
+                .values()
+                .all(|vma| vma.end <= start || end <= self.lowest_start(vma))
+    }
+
+    /// Moves the end of the heap from `old_end` up to `new_end`, both page
+    /// aligned
+    ///
+    /// # Returns
+    /// False if the heap would run into another area
+    pub fn grow_heap(&mut self, old_end: u64, new_end: u64) -> bool {
+        if !self.is_free(old_end, new_end - old_end) {
+            return false;
+        }
+        match self.areas.get_mut(&self.brk_start) {
+            Some(heap) if heap.end == old_end => heap.end = new_end,
+            _ => {
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE;
+                self.areas
+                    .insert(old_end, Vma::anonymous(old_end, new_end, flags));
+            }
+        }
+        true
+    }
+
+    /// Lowest address an area may ever start at, leaving a guard page below
+    /// a stack grown to its limit
+    fn lowest_start(&self, vma: &Vma) -> u64 {
+        if vma.grows_down {
+            vma.end
+                .saturating_sub(self.stack_limit)
+                .saturating_sub(PAGE_SIZE as u64)
+        } else {
+            vma.start
+        }
+    }
+
+    /// Splits the area containing `addr` in two at `addr`
+    fn split_at(&mut self, addr: u64) {
+        let Some(vma) = self.find(addr) else {
+            return;
+        };
+        if vma.start == addr {
+            return;
+        }
+        let lower_start = vma.start;
+        let upper = Vma {
+            start: addr,
+            ..vma.clone()
+        };
+        if let Some(lower) = self.areas.get_mut(&lower_start) {
+            lower.end = addr;
+        }
+        self.areas.insert(addr, upper);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Extends the stack above `addr` down to the page containing it
+    ///
+    /// # Returns
+    /// Whether `addr` now lies in a stack. Fails if the stack would exceed
+    /// `stack_limit` or come within a guard page of the area below it.
+    fn grow_stack(&mut self, addr: u64) -> bool {
+        let Some((&start, stack)) = self.areas.range(addr..).next() else {
+            return false;
+        };
+        let new_start = addr & !(PAGE_SIZE as u64 - 1);
+        if !stack.grows_down || stack.end - new_start > self.stack_limit {
+            return false;
+        }
+        if self
+            .areas
+            .range(..new_start)
+            .next_back()
+            .is_some_and(|(_, below)| below.end + PAGE_SIZE as u64 > new_start)
+        {
+            return false;
+        }
+
+        let mut stack = self.areas.remove(&start).expect("Stack area vanished");
+        stack.start = new_start;
+        self.areas.insert(new_start, stack);
+        true
+    }
+}
+
+impl Default for VmaList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps the page containing `addr` if it lies in one of the process' areas
+/// and the area permits the access, growing a stack if needed.
+///
+/// # Arguments
+/// * `pcb` - the process that touched `addr`
+/// * `vmas` - the process' locked memory areas
+/// * `addr` - the user address accessed
+/// * `access` - how it was accessed
+///
+/// # Returns
+/// Whether the page is now mapped so that the access may be retried, also
+/// when another thread mapped it first. If not, the access is a genuine
+/// fault: outside every area, not permitted by the area or by the page
+/// already mapped, or past the process' `RLIMIT_RSS` limit. It also fails
+/// when no frame is left for the page or its page tables.
+pub fn handle_vma_fault(pcb: &PCB, vmas: &mut VmaList, addr: u64, access: Access) -> bool {
+    if addr >= USER_SPACE_END {
+        return false;
+    }
+    if vmas.find(addr).is_none() && !vmas.grow_stack(addr) {
+        return false;
+    }
+    let vma = vmas.find(addr).expect("Area vanished").clone();
+    if !vma.permits(access) {
+        return false;
+    }
+
+    let page: Page = Page::containing_address(VirtAddr::new(addr));
+    let mut mapper = unsafe { pcb.create_mapper() };
+    // Another thread faulting on the page may have mapped it meanwhile
+    if let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address()) {
+        return flags_permit(flags, access);
+    }
+
+    if vmas.resident >= pcb.rlimits.max_resident_frames() {
+        return false;
+    }
+    let huge_fits = vmas.resident + HUGE_PAGE_FRAMES <= pcb.rlimits.max_resident_frames();
+
+    // Map the whole 2 MiB block at once if it is anonymous and untouched
+    let huge_page: Page<Size2MiB> = Page::containing_address(page.start_address());
+    let huge_start = huge_page.start_address().as_u64();
+    if huge_fits
+        && matches!(vma.backing, Backing::Anonymous)
+        && vma.start <= huge_start
+        && huge_start + Size2MiB::SIZE <= vma.end
+        && matches!(
+            mapper.translate_page(huge_page),
+            Err(TranslateError::PageNotMapped)
+        )
+    {
+        if let Some(frame) = alloc_sized_frame::<Size2MiB>() {
+            let mapped = unsafe {
+                let dst = (*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+                write_bytes(dst, 0, HUGE_PAGE_SIZE);
+                mapper.map_to(huge_page, frame, vma.flags, &mut CachedFrameAllocator)
+            };
+            match mapped {
+                Ok(flush) => {
+                    flush.ignore();
+                    vmas.resident += HUGE_PAGE_FRAMES;
+                    return true;
+                }
+                // Fall back to a single page
+                Err(_) => dealloc_sized_frame(frame),
+            }
+        }
+    }
+
+    // Fill the frame before mapping it, so no other core sees it half-written
+    let Some(frame) = alloc_frame() else {
+        return false;
+    };
+    let mapped = unsafe {
+        let dst = (*HHDM_OFFSET + frame.start_address().as_u64()).as_mut_ptr();
+        vma.fill_page(page.start_address().as_u64(), dst);
+        mapper.map_to(page, frame, vma.flags, &mut CachedFrameAllocator)
+    };
+    match mapped {
+        Ok(flush) => {
+            flush.ignore();
+            vmas.resident += 1;
+            true
+        }
+        Err(_) => {
+            dealloc_frame(frame);
+            false
+        }
+    }
+}
+
+/// Maps every unmapped page of `[addr, addr + len)` that lies in one of the
+/// process' areas, so the kernel can access the range through the HHDM like
+/// the process would. Stops at the first page that cannot be mapped.
+///
+/// # Arguments
+/// * `pcb` - the process that owns the range
+/// * `vmas` - the process' locked memory areas
+/// * `addr` - start of the range
+/// * `len` - length of the range in bytes
+/// * `write` - whether the range will be written
+pub fn fault_in_range(pcb: &PCB, vmas: &mut VmaList, addr: u64, len: usize, write: bool) {
+    let Some(end) = addr.checked_add(len as u64) else {
+        return;
+    };
+    if len == 0 || end > USER_SPACE_END {
+        return;
+    }
+    let access = if write { Access::Write } else { Access::Read };
+
+    let mut page = addr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        let mapped = unsafe { pcb.create_mapper() }
+            .translate_addr(VirtAddr::new(page))
+            .is_some();
+        if !mapped && !handle_vma_fault(pcb, vmas, page, access) {
+            return;
+        }
+        page += PAGE_SIZE as u64;
+    }
+}
+
+/// Splits the huge pages holding `start` and `end`, so that none is only
+/// partly inside `[start, end)`
+///
+/// # Returns
+/// False if no frame was free for a new page table
+fn split_range_ends(mapper: &mut OffsetPageTable, start: u64, end: u64) -> bool {
+    [start, end]
+        .into_iter()
+        .filter(|addr| addr % Size2MiB::SIZE != 0)
+        .all(|addr| split_huge_page(mapper, VirtAddr::new(addr)).is_ok())
+}
+
+/// Unmaps the page-aligned range `[start, end)` and drops it from the
+/// process' areas. Frames no other process shares are freed once every
+/// core's TLB has dropped the range.
+///
+/// # Arguments
+/// * `pcb` - the process that owns the range
+/// * `vmas` - the process' locked memory areas
+/// * `start` - start of the range
+/// * `end` - end of the range
+///
+/// # Returns
+/// False, changing nothing, if a huge page only partly in the range could
+/// not be split
+pub fn unmap_range(pcb: &PCB, vmas: &mut VmaList, start: u64, end: u64) -> bool {
+    let mut mapper = unsafe { pcb.create_mapper() };
+    if !split_range_ends(&mut mapper, start, end) {
+        return false;
+    }
+    let removed = vmas.remove_range(start, end);
+    let mut unmapped = 0;
+    let mut freed = Vec::new();
+    let mut freed_huge = Vec::new();
+
+    for vma in removed {
+        let mut addr = vma.start;
+        while addr < vma.end {
+            let vaddr = VirtAddr::new(addr);
+            let TranslateResult::Mapped { frame, .. } = mapper.translate(vaddr) else {
+                addr += PAGE_SIZE as u64;
+                continue;
+            };
+            match frame {
+                MappedFrame::Size4KiB(_) => {
+                    let page = Page::<Size4KiB>::containing_address(vaddr);
+                    let (frame, flush) = mapper.unmap(page).expect("Unmap failed");
+                    flush.ignore();
+                    unmapped += 1;
+                    if release_frame(frame) {
+                        freed.push(frame);
+                    }
+                    addr += PAGE_SIZE as u64;
+                }
+                // Huge pages are split before a fork shares them, and those
+                // at the ends of the range were split above
+                MappedFrame::Size2MiB(_) => {
+                    let page = Page::<Size2MiB>::containing_address(vaddr);
+                    let (frame, flush) = mapper.unmap(page).expect("Unmap failed");
+                    flush.ignore();
+                    unmapped += HUGE_PAGE_FRAMES;
+                    freed_huge.push(frame);
+                    addr += Size2MiB::SIZE;
+                }
+                MappedFrame::Size1GiB(_) => unreachable!("1 GiB page in user memory"),
+            }
+        }
+    }
+
+    // One shootdown for the whole range, before any core may reuse a frame
+    if unmapped > 0 {
+        tlb_shootdown_range(start, end);
+    }
+    freed.into_iter().for_each(dealloc_frame);
+    freed_huge.into_iter().for_each(dealloc_sized_frame);
+    vmas.resident = vmas.resident.saturating_sub(unmapped);
+    true
+}
+
+/// Changes the page flags of the page-aligned range `[start, end)`
+///
+/// Pages already mapped get the new flags at once. Writable pages whose frame
+/// is still shared stay read-only and copy-on-write.
+///
+/// # Arguments
+/// * `pcb` - the process that owns the range
+/// * `vmas` - the process' locked memory areas
+/// * `start` - start of the range
+/// * `end` - end of the range
+/// * `flags` - the new page flags
+///
+/// # Returns
+/// False, changing nothing, if part of the range is not in any area or a
+/// huge page only partly in the range could not be split
+pub fn protect_range(
+    pcb: &PCB,
+    vmas: &mut VmaList,
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+) -> bool {
+    if !vmas.covers(start, end) {
+        return false;
+    }
+    let mut mapper = unsafe { pcb.create_mapper() };
+    if !split_range_ends(&mut mapper, start, end) {
+        return false;
+    }
+    vmas.protect_range(start, end, flags);
+
+    let mut addr = start;
+    while addr < end {
+        let vaddr = VirtAddr::new(addr);
+        let TranslateResult::Mapped { frame, .. } = mapper.translate(vaddr) else {
+            addr += PAGE_SIZE as u64;
+            continue;
+        };
+        match frame {
+            MappedFrame::Size4KiB(frame) => {
+                let mut page_flags = flags;
+                if flags.contains(PageTableFlags::WRITABLE) && frame_refcount(frame) > 1 {
+                    page_flags.remove(PageTableFlags::WRITABLE);
+                    page_flags.insert(COW_FLAG);
+                }
+                update_permissions::<Size4KiB>(
+                    Page::containing_address(vaddr),
+                    &mut mapper,
+                    page_flags,
+                );
+                addr += PAGE_SIZE as u64;
+            }
+            // Huge pages are never shared, so need no copy-on-write, and
+            // those at the ends of the range were split above
+            MappedFrame::Size2MiB(_) => {
+                update_permissions::<Size2MiB>(Page::containing_address(vaddr), &mut mapper, flags);
+                addr += Size2MiB::SIZE;
+            }
+            MappedFrame::Size1GiB(_) => unreachable!("1 GiB page in user memory"),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    fn stack(start: u64, end: u64) -> Vma {
+        Vma {
+            grows_down: true,
+            ..Vma::anonymous(
+                start,
+                end,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            )
+        }
+    }
+
+    #[test_case]
+    fn test_vma_insert_and_find() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut vmas = VmaList::new();
+            assert!(vmas.insert(Vma::anonymous(0x1000, 0x3000, PageTableFlags::PRESENT)));
+            assert!(vmas.insert(Vma::anonymous(0x3000, 0x4000, PageTableFlags::PRESENT)));
+            assert!(!vmas.insert(Vma::anonymous(0x2000, 0x5000, PageTableFlags::PRESENT)));
+            assert!(!vmas.insert(Vma::anonymous(0x0, 0x2000, PageTableFlags::PRESENT)));
+
+            assert_eq!(vmas.find(0x2fff).map(|vma| vma.start), Some(0x1000));
+            assert_eq!(vmas.find(0x3000).map(|vma| vma.start), Some(0x3000));
+            assert!(vmas.find(0x4000).is_none());
+            assert!(vmas.find(0xfff).is_none());
+        }
+    }
+
+    #[test_case]
+    fn test_vma_split_and_free_ranges() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut vmas = VmaList::new();
+            let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            assert!(vmas.insert(Vma::anonymous(0x1000, 0x5000, flags)));
+
+            vmas.protect_range(0x2000, 0x3000, flags | PageTableFlags::WRITABLE);
+            assert!(vmas.find(0x2000).unwrap().permits(Access::Write));
+            assert!(!vmas.find(0x1000).unwrap().permits(Access::Write));
+            assert!(!vmas.find(0x3000).unwrap().permits(Access::Write));
+            assert!(vmas.covers(0x1000, 0x5000));
+
+            let removed = vmas.remove_range(0x2800, 0x4000);
+            assert_eq!(removed.len(), 2);
+            assert!(!vmas.covers(0x1000, 0x5000));
+            assert_eq!(vmas.find(0x2000).map(|vma| vma.end), Some(0x2800));
+            assert!(vmas.find(0x3000).is_none());
+            assert_eq!(vmas.find(0x4000).map(|vma| vma.start), Some(0x4000));
+
+            assert!(vmas.is_free(0x3000, 0x1000));
+            assert!(!vmas.is_free(0x3000, 0x2000));
+            assert_eq!(vmas.find_free(0x1000, 0x3000), Some(0x3000));
+            assert_eq!(vmas.find_free(0x1000, 0x4000), Some(MMAP_START));
+        }
+    }
+
+    #[test_case]
+    fn test_stack_growth_limits() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut vmas = VmaList::new();
+            vmas.stack_limit = 0x4000;
+            assert!(vmas.insert(Vma::anonymous(0x1000, 0x2000, PageTableFlags::PRESENT)));
+            assert!(vmas.insert(stack(0x8000, 0x9000)));
+
+            // Within the limit
+            assert!(vmas.grow_stack(0x7ff8));
+            assert_eq!(vmas.find(0x7000).map(|vma| vma.start), Some(0x7000));
+            // Beyond the limit
+            assert!(!vmas.grow_stack(0x4fff));
+            assert!(vmas.grow_stack(0x5000));
+
+            // Only a guard page left above the area below
+            vmas.stack_limit = 0x8000;
+            assert!(vmas.grow_stack(0x3000));
+            assert!(!vmas.grow_stack(0x2000));
+            // Not below a stack
+            assert!(!vmas.grow_stack(0x800));
+        }
+    }
+}
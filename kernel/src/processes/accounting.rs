@@ -0,0 +1,107 @@
+//! Per-process CPU and memory accounting
+//!
+//! - Timer ticks spent in user and kernel mode, charged by the timer handler
+//! - Virtual runtime of the running thread, charged per tick by nice weight
+//! - Context switches and the core a process last ran on
+//! - Resident frames, kept up to date by the page fault handler
+//!
+//! Ticks are counted by each core's timer, so they are approximate: ticks that
+//! passed since the timer last fired are charged in full to whatever was
+//! running when it fires.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::{
+    events::{current_running_event_info, vruntime_per_tick},
+    processes::process::{PCB, PROCESS_TABLE},
+};
+
+/// Slot of the saved `cs` in a register frame built by an interrupt entry point
+const FRAME_CS_SLOT: usize = 16;
+
+/// Counters kept for every process
+#[derive(Debug)]
+pub struct ProcessStats {
+    /// Timer ticks of the creating core when the process was created
+    pub created: u64,
+    pub user_ticks: AtomicU64,
+    pub kernel_ticks: AtomicU64,
+    /// Times one of the process' threads was switched onto a core
+    pub context_switches: AtomicU64,
+    /// Core a thread of the process last ran on
+    pub last_core: AtomicU32,
+    /// User ticks of children that have been waited for
+    pub child_user_ticks: AtomicU64,
+    /// Kernel ticks of children that have been waited for
+    pub child_kernel_ticks: AtomicU64,
+}
+
+impl ProcessStats {
+    pub fn new(created: u64) -> Self {
+        Self {
+            created,
+            user_ticks: AtomicU64::new(0),
+            kernel_ticks: AtomicU64::new(0),
+            context_switches: AtomicU64::new(0),
+            last_core: AtomicU32::new(0),
+            child_user_ticks: AtomicU64::new(0),
+            child_kernel_ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a thread of the process being switched onto `core`
+    pub fn switched_in(&self, core: u32) {
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+        self.last_core.store(core, Ordering::Relaxed);
+    }
+
+    /// Adds a collected child's ticks, and those of its own children, to the
+    /// child totals
+    pub fn add_child(&self, child: &ProcessStats) {
+        let user = child.user_ticks.load(Ordering::Relaxed)
+            + child.child_user_ticks.load(Ordering::Relaxed);
+        let kernel = child.kernel_ticks.load(Ordering::Relaxed)
+            + child.child_kernel_ticks.load(Ordering::Relaxed);
+        self.child_user_ticks.fetch_add(user, Ordering::Relaxed);
+        self.child_kernel_ticks.fetch_add(kernel, Ordering::Relaxed);
+    }
+}
+
+/// Charges timer ticks to the process and thread running on this core, if
+/// any
+///
+/// * `rsp`: the register frame saved by the timer interrupt, which tells
+///   whether user or kernel code was interrupted
+/// * `ticks`: ticks since the timer last fired
+pub fn charge_ticks(rsp: u64, ticks: u64) {
+    let event = current_running_event_info();
+    if event.pid == 0 || ticks == 0 {
+        return;
+    }
+    let Some(process) = PROCESS_TABLE.read().get(&event.pid).cloned() else {
+        return;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let stats = &pcb.stats;
+
+    if let Some(thread) = pcb.threads.lock().get(&event.tid) {
+        let tcb = unsafe { &*thread.tcb.get() };
+        tcb.vruntime
+            .fetch_add(vruntime_per_tick(pcb.nice) * ticks, Ordering::Relaxed);
+    }
+
+    let cs = unsafe { *(rsp as *const u64).add(FRAME_CS_SLOT) };
+    if cs & 3 == 3 {
+        stats.user_ticks.fetch_add(ticks, Ordering::Relaxed);
+    } else {
+        stats.kernel_ticks.fetch_add(ticks, Ordering::Relaxed);
+    }
+}
+
+/// Returns the frames mapped into a process' user memory
+///
+/// Frames shared copy-on-write count for every process mapping them. A
+/// zombie has no memory left.
+pub fn resident_frames(pcb: &PCB) -> u64 {
+    pcb.lock_vmas().resident
+}
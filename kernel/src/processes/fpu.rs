@@ -0,0 +1,224 @@
+//! x87, SSE and AVX register state
+//!
+//! The kernel is built without floating point or SIMD instructions, so the
+//! state a thread leaves in these registers survives until another thread's
+//! state is loaded. Each thread keeps a save area, written when it leaves
+//! its core and loaded by `call_process` before it re-enters ring 3.
+//!
+//! XSAVE/XRSTOR are used where supported, saving every component enabled in
+//! XCR0; otherwise only the legacy x87/SSE area is saved with FXSAVE/FXRSTOR.
+
+use alloc::{boxed::Box, vec};
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use raw_cpuid::CpuId;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+/// Size of the FXSAVE area, also the legacy part of an XSAVE area
+const FXSAVE_SIZE: usize = 512;
+/// Offset of the x87 control word in the legacy area
+const FCW_OFFSET: usize = 0;
+/// Offset of MXCSR in the legacy area
+const MXCSR_OFFSET: usize = 24;
+/// Offset of the mask of MXCSR bits the CPU supports
+const MXCSR_MASK_OFFSET: usize = 28;
+/// Supported MXCSR bits when the CPU stores a zero mask
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+/// Size of the XSAVE header that follows the legacy area
+const XSAVE_HEADER_SIZE: usize = 64;
+/// x87 control word after `fninit`: all exceptions masked
+const DEFAULT_FCW: u16 = 0x037f;
+/// MXCSR after reset: all exceptions masked
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Whether XSAVE/XRSTOR are used instead of FXSAVE/FXRSTOR. Read by
+/// `call_process`.
+pub static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// Size of a save area for the state components enabled on every core
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// Enables SSE, and XSAVE with AVX where supported, on the current core
+///
+/// Must run on every core before any process does.
+///
+/// # Arguments
+/// * `cpu_id` - ID of the CPU being initialized (0 for BSP, >0 for APs)
+pub fn init(cpu_id: u32) {
+    let features = CpuId::new()
+        .get_feature_info()
+        .expect("CPUID feature information unavailable");
+    assert!(
+        features.has_fxsave_fxstor(),
+        "FXSAVE/FXRSTOR are not supported"
+    );
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if features.has_xsave() {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+
+        if features.has_xsave() {
+            let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.has_avx() {
+                components |= XCr0Flags::AVX;
+            }
+            XCr0::write(components);
+        }
+    }
+
+    if cpu_id == 0 {
+        let area_size = CpuId::new()
+            .get_extended_state_info()
+            .filter(|_| features.has_xsave())
+            .map_or(FXSAVE_SIZE, |info| {
+                info.xsave_area_size_enabled_features() as usize
+            });
+        AREA_SIZE.store(area_size, Ordering::Relaxed);
+        USE_XSAVE.store(features.has_xsave(), Ordering::Release);
+    }
+}
+
+/// XSAVE areas must be 64-byte aligned
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct Block([u8; 64]);
+
+/// Saved x87, SSE and AVX registers of a thread
+pub struct FpuState {
+    area: Box<[Block]>,
+}
+
+impl FpuState {
+    /// Creates the state a thread starts with: registers cleared and all
+    /// floating point exceptions masked
+    pub fn new() -> Self {
+        let blocks = AREA_SIZE
+            .load(Ordering::Relaxed)
+            .div_ceil(size_of::<Block>());
+        let mut state = Self {
+            area: vec![Block([0; 64]); blocks].into_boxed_slice(),
+        };
+
+        let legacy = state.bytes_mut();
+        legacy[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        legacy[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.area.as_mut_ptr().cast(),
+                self.area.len() * size_of::<Block>(),
+            )
+        }
+    }
+
+    /// The save area, as copied into a signal frame
+    pub fn bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.area.as_ptr().cast(),
+                self.area.len() * size_of::<Block>(),
+            )
+        }
+    }
+
+    /// Replaces the saved registers with a save area the process supplied,
+    /// clearing the fields that would make FXRSTOR or XRSTOR fault
+    ///
+    /// # Arguments
+    /// * `area` - a save area as returned by `bytes`
+    pub fn load_bytes(&mut self, area: &[u8]) {
+        let bytes = self.bytes_mut();
+        let mxcsr_mask = match read_u32(bytes, MXCSR_MASK_OFFSET) {
+            0 => DEFAULT_MXCSR_MASK,
+            mask => mask,
+        };
+        bytes.copy_from_slice(area);
+
+        let mxcsr = read_u32(bytes, MXCSR_OFFSET) & mxcsr_mask;
+        bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&mxcsr.to_le_bytes());
+
+        if USE_XSAVE.load(Ordering::Acquire) {
+            // Only components enabled in XCR0, in the standard format
+            let header = &mut bytes[FXSAVE_SIZE..FXSAVE_SIZE + XSAVE_HEADER_SIZE];
+            let components = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let components = components & XCr0::read_raw();
+            header.fill(0);
+            header[..8].copy_from_slice(&components.to_le_bytes());
+        }
+    }
+
+    /// Loads the saved registers into this core, for the thread running on it
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Acquire) {
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Address of the save area, as loaded by `call_process`
+    pub fn as_ptr(&self) -> *const u8 {
+        self.area.as_ptr().cast()
+    }
+
+    /// Saves the registers of the thread that last ran on this core
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Acquire) {
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FpuState")
+            .field("size", &(self.area.len() * size_of::<Block>()))
+            .finish()
+    }
+}
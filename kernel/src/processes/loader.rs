@@ -4,127 +4,252 @@ use crate::{
         processes::{STACK_SIZE, STACK_START},
     },
     memory::{
-        frame_allocator::with_generic_allocator,
-        paging::{create_mapping, update_permissions},
+        user_access::copy_to_user,
+        vma::{page_align_down, page_align_up, Backing, Vma, VmaList},
     },
+    processes::process::PCB,
 };
-use core::ptr::{copy_nonoverlapping, write_bytes};
+use alloc::{sync::Arc, vec::Vec};
 use goblin::{
     elf::Elf,
-    elf64::program_header::{PF_W, PF_X, PT_LOAD},
-};
-use x86_64::{
-    structures::paging::{
-        mapper::CleanUp, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+    elf64::{
+        header::{EM_X86_64, ET_EXEC},
+        program_header::{PF_W, PF_X, PT_LOAD, PT_PHDR},
     },
-    VirtAddr,
 };
-
-// We import our new helper
-use crate::memory::paging::map_kernel_frame;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
 /// Function for initializing addresss space for process using ELF executable
 ///
+/// Records a file-backed area for every PT_LOAD segment and an anonymous
+/// area for the stack, and places the heap after the image. Nothing is
+/// mapped: pages are filled in on first access.
+///
 /// # Arguments:
 /// * 'elf_bytes' - byte stream of ELF executable to parse
-/// * 'user_mapper' - Page table for user that maps VAs from section headers to frames
-/// * 'kernel mapper' - kernel page table for mapping VAs to frames for writing ELF metadata to frames
+/// * 'vmas' - the process' memory areas, which must be empty
 ///
 /// # Returns:
 /// Virtual address of the top of user stack and entry point for process
-pub fn load_elf(
-    elf_bytes: &[u8],
-    user_mapper: &mut impl Mapper<Size4KiB>,
-    kernel_mapper: &mut OffsetPageTable<'static>,
-) -> (VirtAddr, u64) {
+pub fn load_elf(elf_bytes: &[u8], vmas: &mut VmaList) -> (VirtAddr, u64) {
     let elf = Elf::parse(elf_bytes).expect("Parsing ELF failed");
+    let data: Arc<[u8]> = Arc::from(elf_bytes);
+
     for ph in elf.program_headers.iter() {
         if ph.p_type != PT_LOAD {
             continue;
         }
 
-        let virt_addr = VirtAddr::new(ph.p_vaddr);
-        let mem_size = ph.p_memsz as usize;
-        let file_size = ph.p_filesz as usize;
-        let offset = ph.p_offset as usize;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if (ph.p_flags & PF_W) != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if (ph.p_flags & PF_X) == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
 
-        let start_page = Page::containing_address(virt_addr);
-        let end_page = Page::containing_address(virt_addr + (mem_size - 1) as u64);
+        let segment = Vma {
+            start: page_align_down(ph.p_vaddr),
+            end: page_align_up(ph.p_vaddr + ph.p_memsz),
+            flags,
+            backing: Backing::File {
+                data: Arc::clone(&data),
+                offset: ph.p_offset,
+                vaddr: ph.p_vaddr,
+                size: ph.p_filesz,
+            },
+            grows_down: false,
+        };
+        vmas.brk_start = vmas.brk_start.max(segment.end);
+        assert!(vmas.insert(segment), "Overlapping ELF segments");
+    }
+    // The heap starts empty right after the image
+    vmas.brk = vmas.brk_start;
+
+    // The stack grows down from here on demand
+    let stack_end = STACK_START + STACK_SIZE as u64;
+    let stack_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let stack = Vma {
+        grows_down: true,
+        ..Vma::anonymous(STACK_START, stack_end, stack_flags)
+    };
+    assert!(vmas.insert(stack), "ELF segment overlaps the stack");
+
+    (VirtAddr::new(stack_end), elf.header.e_entry)
+}
 
-        // Build final page flags
-        let default_flags =
-            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+/// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
 
-        // For each page in [start_page..end_page], create user mapping,
-        // then do a kernel alias to copy data in
-        for page in Page::range_inclusive(start_page, end_page) {
-            let frame = create_mapping(page, user_mapper, Some(default_flags));
-            let kernel_alias = map_kernel_frame(kernel_mapper, frame, default_flags);
-            // now `kernel_alias` is a kernel virtual address of that same frame
-
-            let page_offset =
-                page.start_address()
-                    .as_u64()
-                    .saturating_sub(start_page.start_address().as_u64()) as usize;
-            let page_remaining = PAGE_SIZE - (page_offset % PAGE_SIZE);
-            let to_copy = core::cmp::min(file_size.saturating_sub(page_offset), page_remaining);
-
-            if to_copy > 0 {
-                let dest = kernel_alias.as_mut_ptr::<u8>();
-                let src = &elf_bytes[offset + page_offset..offset + page_offset + to_copy];
-                unsafe {
-                    copy_nonoverlapping(src.as_ptr(), dest, to_copy);
-                }
-            }
-
-            let bss_start = file_size.saturating_sub(page_offset);
-            if bss_start < page_remaining {
-                // i.e. if this page has some leftover space beyond file_size
-                let zero_offset_in_page = core::cmp::max(bss_start, 0);
-                let zero_len = page_remaining.saturating_sub(zero_offset_in_page);
-                if zero_len > 0 {
-                    unsafe {
-                        let dest = kernel_alias.as_mut_ptr::<u8>().add(zero_offset_in_page);
-                        write_bytes(dest, 0, zero_len);
-                    }
-                }
-            }
-
-            if (ph.p_flags & PF_W) != 0 {
-                flags |= PageTableFlags::WRITABLE;
-            }
-            if (ph.p_flags & PF_X) == 0 {
-                flags |= PageTableFlags::NO_EXECUTE;
-            }
-            update_permissions(page, user_mapper, flags);
-
-            let unmap_page: Page<Size4KiB> = Page::containing_address(kernel_alias);
-            // unmap the frame, but do not actually deallocate it
-            // the physical frame is still used by the process in its own mapping
-            kernel_mapper
-                .unmap(unmap_page)
-                .expect("Unmapping kernel frame failed")
-                .1
-                .flush();
-            with_generic_allocator(|allocator| unsafe { kernel_mapper.clean_up(allocator) });
-
-            update_permissions(page, user_mapper, flags);
-        }
+/// Checks that an ELF executable can be loaded by `load_elf`
+///
+/// # Arguments:
+/// * 'elf_bytes' - byte stream of the ELF executable
+///
+/// # Returns:
+/// Whether the file is a static x86_64 executable whose segments lie in the
+/// file and below the user stack, without sharing pages, and whose entry
+/// point lies in one of them
+pub fn is_loadable_elf(elf_bytes: &[u8]) -> bool {
+    let Ok(elf) = Elf::parse(elf_bytes) else {
+        return false;
+    };
+    if elf.header.e_machine != EM_X86_64 || elf.header.e_type != ET_EXEC {
+        return false;
+    }
+
+    let segments_valid = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .all(|ph| {
+            ph.p_memsz > 0
+                && ph.p_filesz <= ph.p_memsz
+                && ph
+                    .p_offset
+                    .checked_add(ph.p_filesz)
+                    .is_some_and(|end| end <= elf_bytes.len() as u64)
+                && ph
+                    .p_vaddr
+                    .checked_add(ph.p_memsz)
+                    .is_some_and(|end| end <= STACK_START)
+        });
+    if !segments_valid {
+        return false;
+    }
+
+    let entry = elf.header.e_entry;
+    let entry_loaded = elf
+        .program_headers
+        .iter()
+        .any(|ph| ph.p_type == PT_LOAD && (ph.p_vaddr..ph.p_vaddr + ph.p_memsz).contains(&entry));
+    if !entry_loaded {
+        return false;
     }
 
-    // Map user stack
-    let stack_start = VirtAddr::new(STACK_START);
-    let stack_end = VirtAddr::new(STACK_START + STACK_SIZE as u64);
-    let start_page = Page::containing_address(stack_start);
-    let end_page = Page::containing_address(stack_end);
+    // Each segment becomes its own memory area, so no two may share a page
+    let mut pages: Vec<(u64, u64)> = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| {
+            (
+                page_align_down(ph.p_vaddr),
+                page_align_up(ph.p_vaddr + ph.p_memsz),
+            )
+        })
+        .collect();
+    pages.sort_unstable();
+    pages.windows(2).all(|pair| pair[0].1 <= pair[1].0)
+}
+
+/// Builds the auxiliary vector describing a loaded ELF executable
+///
+/// # Arguments:
+/// * 'elf_bytes' - byte stream of the ELF executable
+///
+/// # Returns:
+/// (type, value) pairs ending with `AT_NULL`
+pub fn auxiliary_vector(elf_bytes: &[u8]) -> Vec<(u64, u64)> {
+    let elf = Elf::parse(elf_bytes).expect("Parsing ELF failed");
+    let header = &elf.header;
+    let mut auxv = Vec::new();
+
+    // The program headers are only visible to the process if a segment maps them
+    let phdr_size = header.e_phentsize as u64 * header.e_phnum as u64;
+    let phdr = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_PHDR)
+        .map(|ph| ph.p_vaddr)
+        .or_else(|| {
+            elf.program_headers
+                .iter()
+                .find(|ph| {
+                    ph.p_type == PT_LOAD
+                        && ph.p_offset <= header.e_phoff
+                        && header.e_phoff + phdr_size <= ph.p_offset + ph.p_filesz
+                })
+                .map(|ph| ph.p_vaddr + (header.e_phoff - ph.p_offset))
+        });
+    if let Some(phdr) = phdr {
+        auxv.push((AT_PHDR, phdr));
+    }
 
-    let stack_flags =
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    auxv.push((AT_PHENT, header.e_phentsize as u64));
+    auxv.push((AT_PHNUM, header.e_phnum as u64));
+    auxv.push((AT_PAGESZ, PAGE_SIZE as u64));
+    auxv.push((AT_ENTRY, header.e_entry));
+    auxv.push((AT_NULL, 0));
+    auxv
+}
 
-    for page in Page::range_inclusive(start_page, end_page) {
-        create_mapping(page, user_mapper, Some(stack_flags));
+/// Writes a System V initial process stack below `stack_top`
+///
+/// From the returned stack pointer up: argc, the argv pointers, NULL, the
+/// envp pointers, NULL, the auxiliary vector, then the strings themselves.
+///
+/// # Arguments:
+/// * 'pcb' - the process whose stack is written
+/// * 'stack_top' - first address past the mapped user stack
+/// * 'args' - argument strings, without NUL terminators
+/// * 'env' - environment strings, without NUL terminators
+/// * 'auxv' - auxiliary vector, ending with `AT_NULL`
+///
+/// # Returns:
+/// The 16-byte aligned stack pointer, which points at argc
+pub fn build_initial_stack(
+    pcb: &PCB,
+    stack_top: u64,
+    args: &[Vec<u8>],
+    env: &[Vec<u8>],
+    auxv: &[(u64, u64)],
+) -> Result<u64, i64> {
+    let mut string_top = stack_top;
+    let arg_ptrs = push_strings(pcb, &mut string_top, args)?;
+    let env_ptrs = push_strings(pcb, &mut string_top, env)?;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(args.len() as u64);
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    for &(kind, value) in auxv {
+        words.push(kind);
+        words.push(value);
     }
 
-    (stack_end, elf.header.e_entry)
+    let rsp = (string_top - (words.len() * 8) as u64) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    copy_to_user(pcb, rsp, &bytes)?;
+    Ok(rsp)
+}
+
+/// Returns how many bytes `build_initial_stack` writes below the stack top,
+/// including the worst-case alignment of the stack pointer
+pub fn initial_stack_size(args: &[Vec<u8>], env: &[Vec<u8>], auxv: &[(u64, u64)]) -> usize {
+    let strings: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * auxv.len();
+    strings + words * 8 + 0xf
+}
+
+/// Copies NUL-terminated strings downwards from `top`, returning their addresses
+fn push_strings(pcb: &PCB, top: &mut u64, strings: &[Vec<u8>]) -> Result<Vec<u64>, i64> {
+    let mut addrs = Vec::with_capacity(strings.len());
+    for string in strings {
+        *top -= string.len() as u64 + 1;
+        copy_to_user(pcb, *top, string)?;
+        copy_to_user(pcb, *top + string.len() as u64, &[0])?;
+        addrs.push(*top);
+    }
+    Ok(addrs)
 }
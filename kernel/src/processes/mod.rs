@@ -1,15 +1,57 @@
+pub mod accounting;
+pub mod fpu;
 pub mod loader;
 pub mod process;
 pub mod registers;
+pub mod rlimit;
+pub mod signals;
+pub mod thread;
+
+/// Prepares the current core to run processes
+///
+/// # Arguments
+/// * `cpu_id` - ID of the CPU being initialized (0 for BSP, >0 for APs)
+pub fn init(cpu_id: u32) {
+    fpu::init(cpu_id);
+}
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        constants::processes::INFINITE_LOOP,
+        constants::{
+            errno::{EINVAL, EPERM},
+            memory::PAGE_SIZE,
+            processes::INFINITE_LOOP,
+            signals::{SIGCHLD, SIGKILL, SIGTERM, SIGUSR1},
+            syscalls::{RLIMIT_NICE, RLIMIT_NOFILE},
+        },
         events::schedule_process,
         interrupts::x2apic,
-        processes::process::{create_process, run_process_ring3},
+        memory::{
+            cow::handle_cow_fault,
+            vma::{handle_vma_fault, Access, Vma},
+        },
+        processes::{
+            accounting::{resident_frames, ProcessStats},
+            fpu::FpuState,
+            loader::{auxiliary_vector, is_loadable_elf},
+            process::{
+                child_waitable, create_process, exit_wait_status, reap_child, run_process_ring3,
+                terminate_process, ChildStatus, ProcessState, PROCESS_TABLE,
+            },
+            registers::Registers,
+            rlimit::{RLimit, RLimits},
+            signals::{deliver_signals, sig_bit, signal_wait_status},
+            thread::{stop_thread, UnsafeTCB, TCB},
+        },
     };
+    use alloc::sync::Arc;
+    use core::{
+        future::Future,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+    use futures::task::{waker, ArcWake};
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
     #[test_case]
     fn test_simple_process() {
@@ -22,4 +64,232 @@ mod tests {
 
         assert!(matches!(cpuid, 0));
     }
+
+    #[test_case]
+    fn test_exec_image_checks() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            assert!(is_loadable_elf(INFINITE_LOOP));
+            assert!(!is_loadable_elf(b"\x7fELF not really"));
+            assert!(!is_loadable_elf(&INFINITE_LOOP[..64]));
+
+            // An entry point outside every segment, here in the kernel half
+            let mut bad_entry = INFINITE_LOOP.to_vec();
+            bad_entry[24..32].copy_from_slice(&0xffff_8000_0000_0000u64.to_le_bytes());
+            assert!(!is_loadable_elf(&bad_entry));
+
+            let auxv = auxiliary_vector(INFINITE_LOOP);
+            assert_eq!(auxv.last(), Some(&(0, 0)));
+            assert!(auxv.contains(&(6, 4096)));
+        }
+    }
+
+    #[test_case]
+    fn test_zombie_reaped_by_parent() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let parent = create_process(INFINITE_LOOP);
+            let child = create_process(INFINITE_LOOP);
+            {
+                let table = PROCESS_TABLE.read();
+                unsafe {
+                    (*table[&parent].pcb.get()).children.push(child);
+                    (*table[&child].pcb.get()).ppid = parent;
+                }
+            }
+
+            assert!(matches!(reap_child(parent, -1), ChildStatus::Running));
+
+            terminate_process(&mut PROCESS_TABLE.write(), child, exit_wait_status(7));
+            assert!(PROCESS_TABLE.read().contains_key(&child));
+
+            assert!(matches!(
+                reap_child(parent, child as i64),
+                ChildStatus::Exited(pid, 0x700) if pid == child
+            ));
+            assert!(!PROCESS_TABLE.read().contains_key(&child));
+            assert!(matches!(reap_child(parent, -1), ChildStatus::NoChildren));
+
+            terminate_process(&mut PROCESS_TABLE.write(), parent, 0);
+        }
+    }
+
+    #[test_case]
+    fn test_signal_default_actions() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let pid = create_process(INFINITE_LOOP);
+            let process = PROCESS_TABLE.read()[&pid].clone();
+            let pcb = unsafe { &*process.pcb.get() };
+            let mut registers = Registers::new();
+            let mut fpu = FpuState::new();
+
+            // Ignored by default
+            pcb.signals.lock().post(SIGCHLD);
+            assert_eq!(deliver_signals(pcb, &mut registers, &mut fpu), None);
+
+            // Blocked signals stay pending, but SIGKILL cannot be blocked
+            pcb.signals
+                .lock()
+                .set_blocked(sig_bit(SIGTERM) | sig_bit(SIGKILL));
+            pcb.signals.lock().post(SIGTERM);
+            assert_eq!(deliver_signals(pcb, &mut registers, &mut fpu), None);
+            assert_ne!(pcb.signals.lock().pending & sig_bit(SIGTERM), 0);
+            pcb.signals.lock().post(SIGKILL);
+            assert_eq!(
+                deliver_signals(pcb, &mut registers, &mut fpu),
+                Some(SIGKILL)
+            );
+
+            pcb.signals.lock().set_blocked(0);
+            pcb.signals.lock().post(SIGUSR1);
+            assert_eq!(
+                deliver_signals(pcb, &mut registers, &mut fpu),
+                Some(SIGTERM)
+            );
+
+            terminate_process(&mut PROCESS_TABLE.write(), pid, signal_wait_status(SIGKILL));
+            assert!(!PROCESS_TABLE.read().contains_key(&pid));
+            assert_eq!(pcb.state, ProcessState::Zombie);
+        }
+    }
+
+    #[test_case]
+    fn test_exit_waits_for_running_threads() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let pid = create_process(INFINITE_LOOP);
+            let process = PROCESS_TABLE.read()[&pid].clone();
+            let pcb = unsafe { &mut *process.pcb.get() };
+
+            let mut thread = TCB::new(pid + 1000, pid, Registers::new());
+            thread.state = ProcessState::Running;
+            let thread = Arc::new(UnsafeTCB::init(thread));
+            pcb.threads.lock().insert(pid + 1000, thread.clone());
+
+            terminate_process(&mut PROCESS_TABLE.write(), pid, exit_wait_status(1));
+            assert_eq!(pcb.state, ProcessState::Terminated);
+            let tcb = unsafe { &mut *thread.tcb.get() };
+            assert!(tcb.exit_requested.load(Ordering::Acquire));
+
+            stop_thread(&mut PROCESS_TABLE.write(), pid, tcb);
+            assert_eq!(pcb.state, ProcessState::Zombie);
+            assert!(!PROCESS_TABLE.read().contains_key(&pid));
+        }
+    }
+
+    #[test_case]
+    fn test_process_accounting() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let pid = create_process(INFINITE_LOOP);
+            let process = PROCESS_TABLE.read()[&pid].clone();
+            let pcb = unsafe { &*process.pcb.get() };
+
+            // Pages are only mapped on first access
+            assert_eq!(resident_frames(pcb), 0);
+
+            let child = ProcessStats::new(0);
+            child.user_ticks.store(3, Ordering::Relaxed);
+            child.child_kernel_ticks.store(2, Ordering::Relaxed);
+            pcb.stats.add_child(&child);
+            assert_eq!(pcb.stats.child_user_ticks.load(Ordering::Relaxed), 3);
+            assert_eq!(pcb.stats.child_kernel_ticks.load(Ordering::Relaxed), 2);
+
+            terminate_process(&mut PROCESS_TABLE.write(), pid, 0);
+            assert_eq!(resident_frames(pcb), 0);
+        }
+    }
+
+    #[test_case]
+    fn test_fault_on_mapped_page() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let pid = create_process(INFINITE_LOOP);
+            let process = PROCESS_TABLE.read()[&pid].clone();
+            let pcb = unsafe { &*process.pcb.get() };
+            {
+                let mut vmas = pcb.lock_vmas();
+                let start = vmas.find_free(2 * PAGE_SIZE as u64, 0).unwrap();
+                let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                assert!(vmas.insert(Vma::anonymous(start, start + PAGE_SIZE as u64, flags)));
+
+                assert!(handle_vma_fault(pcb, &mut vmas, start, Access::Read));
+                // A thread that lost the race to map the page retries
+                assert!(handle_vma_fault(pcb, &mut vmas, start + 8, Access::Read));
+                assert!(!handle_vma_fault(pcb, &mut vmas, start, Access::Write));
+                assert_eq!(vmas.resident, 1);
+                // Read-only and never shared, so a write is a violation
+                assert!(!handle_cow_fault(pcb.pml4_frame, VirtAddr::new(start)));
+
+                let writable = flags | PageTableFlags::WRITABLE;
+                let page = start + PAGE_SIZE as u64;
+                assert!(vmas.insert(Vma::anonymous(page, page + PAGE_SIZE as u64, writable)));
+                assert!(handle_vma_fault(pcb, &mut vmas, page, Access::Write));
+                // As if another thread had already copied the page
+                assert!(handle_cow_fault(pcb.pml4_frame, VirtAddr::new(page)));
+            }
+            terminate_process(&mut PROCESS_TABLE.write(), pid, 0);
+        }
+    }
+
+    #[test_case]
+    fn test_rlimit_rules() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let mut limits = RLimits::new();
+            let files = limits.get(RLIMIT_NOFILE).unwrap();
+
+            let lower = RLimit { cur: 8, max: 16 };
+            assert_eq!(limits.set(RLIMIT_NOFILE, lower), Ok(()));
+            assert_eq!(limits.current(RLIMIT_NOFILE), 8);
+            // The soft limit may not pass the hard one, which cannot be raised
+            assert_eq!(
+                limits.set(RLIMIT_NOFILE, RLimit { cur: 32, max: 16 }),
+                Err(EINVAL)
+            );
+            assert_eq!(limits.set(RLIMIT_NOFILE, files), Err(EPERM));
+            assert_eq!(limits.set(u64::MAX, lower), Err(EINVAL));
+            // Nice values may not be lowered unless the limit is raised
+            assert_eq!(limits.current(RLIMIT_NICE), 0);
+            assert_eq!(limits.get(u64::MAX), None);
+        }
+    }
+
+    struct WakeFlag(AtomicBool);
+
+    impl ArcWake for WakeFlag {
+        fn wake_by_ref(arc: &Arc<Self>) {
+            arc.0.store(true, Ordering::Release);
+        }
+    }
+
+    #[test_case]
+    fn test_child_exit_wakes_waiters() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let parent = create_process(INFINITE_LOOP);
+            let child = create_process(INFINITE_LOOP);
+            let process = PROCESS_TABLE.read()[&parent].clone();
+            let pcb = unsafe { &mut *process.pcb.get() };
+            pcb.children.push(child);
+            unsafe { (*PROCESS_TABLE.read()[&child].pcb.get()).ppid = parent };
+            assert!(!child_waitable(parent, -1));
+
+            let flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+            let flag_waker = waker(flag.clone());
+            pcb.child_exit.register(&flag_waker);
+            pcb.child_exit.register(&flag_waker);
+            assert_eq!(pcb.child_exit.len(), 1);
+
+            terminate_process(&mut PROCESS_TABLE.write(), child, exit_wait_status(3));
+            assert!(flag.0.load(Ordering::Acquire));
+            assert!(pcb.child_exit.is_empty());
+            assert!(child_waitable(parent, -1));
+
+            let status = pcb
+                .child_exit
+                .wait_until(|| match reap_child(parent, -1) {
+                    ChildStatus::Running => None,
+                    status => Some(status),
+                })
+                .await;
+            assert!(matches!(status, ChildStatus::Exited(pid, 0x300) if pid == child));
+            assert!(!pcb.child_exit.unregister(&flag_waker));
+
+            terminate_process(&mut PROCESS_TABLE.write(), parent, 0);
+        }
+    }
 }
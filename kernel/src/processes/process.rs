@@ -1,59 +1,114 @@
 extern crate alloc;
 
 use crate::{
-    constants::processes::PROCESS_TIMESLICE,
+    constants::{
+        errno::EAGAIN,
+        processes::{INIT_PID, PROCESS_TIMESLICE},
+        signals::SIGSEGV,
+        syscalls::SYSCALL_INSTRUCTION_LEN,
+    },
     debug,
     events::{
-        current_running_event_info, nanosleep_current_process, runner_timestamp, schedule_process,
-        EventInfo,
-    },
-    interrupts::{
-        gdt,
-        x2apic::{self, nanos_to_ticks},
+        current_running_event_info, nanosleep_current_process, runner_timestamp,
+        schedule_blocked_process, schedule_thread, EventInfo, WaitQueue,
     },
-    ipc::namespace::Namespace,
+    interrupts::{gdt, tsc::monotonic_nanos, x2apic},
+    ipc::{fd_table::FdTable, namespace::Namespace},
     memory::{
-        frame_allocator::{alloc_frame, with_generic_allocator},
+        cow::{duplicate_user_space, release_frame},
+        frame_allocator::{alloc_frame, dealloc_frame, dealloc_sized_frame},
+        frame_cache::CachedFrameAllocator,
+        slab::SlabCache,
+        tlb::{flush_pending_shootdown, tlb_shootdown_all},
+        vma::VmaList,
         HHDM_OFFSET, MAPPER,
     },
-    processes::{loader::load_elf, registers::Registers},
+    processes::{
+        accounting::{resident_frames, ProcessStats},
+        fpu::{FpuState, USE_XSAVE},
+        loader::{auxiliary_vector, build_initial_stack, load_elf},
+        registers::Registers,
+        rlimit::{may_add_task, RLimits},
+        signals::{deliver_signals, signal_wait_status, SignalState},
+        thread::{get_thread, request_threads_exit, stop_thread, ThreadMap, UnsafeTCB, TCB},
+    },
     serial_println,
 };
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
     arch::naked_asm,
     cell::UnsafeCell,
     sync::atomic::{AtomicU32, Ordering},
 };
-use spin::rwlock::RwLock;
+use spin::{rwlock::RwLock, Mutex, MutexGuard};
 use x86_64::{
     instructions::interrupts,
-    structures::paging::{FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    PhysAddr,
 };
 
 // process counter must be thread-safe
 // PID 0 will ONLY be used for errors/PID not found
 static NEXT_PID: AtomicU32 = AtomicU32::new(1);
 
+/// Hands out a new PID. Thread IDs are drawn from the same counter, so a TID
+/// never names a different process.
+pub fn next_pid() -> u32 {
+    NEXT_PID.fetch_add(1, Ordering::SeqCst)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
     New,
     Ready,
     Running,
     Blocked,
+    /// Exiting, but some of its threads are still on a core
     Terminated,
+    /// Exited, waiting for its parent to collect the exit status
+    Zombie,
 }
 
 #[derive(Debug)]
 pub struct PCB {
     pub pid: u32,
     pub state: ProcessState,
-    pub kernel_rsp: u64,
-    pub kernel_rip: u64,
-    pub next_preemption_time: u64,
-    pub registers: Registers,
+    /// The process' threads by TID; the first one has the process' PID
+    pub threads: Mutex<ThreadMap>,
     pub pml4_frame: PhysFrame<Size4KiB>, // this process' page table,
+    /// Ranges of user memory the process may access. The lock also guards
+    /// the user half of the page table; take it with `lock_vmas`.
+    pub vmas: Mutex<VmaList>,
     pub namespace: Namespace,
+    /// Never held while copying to or from user memory
+    pub fd_table: Mutex<FdTable>,
+    pub ppid: u32, // 0 if the process has no parent
+    pub children: Vec<u32>,
+    /// Wait status reported to the parent once the process is a zombie
+    pub exit_status: i32,
+    /// Woken when a child exits or is reparented to this process
+    pub child_exit: WaitQueue,
+    /// Never held while copying to or from user memory
+    pub signals: Mutex<SignalState>,
+    /// The exception that killed the process, if one did
+    pub fault: Option<Fault>,
+    pub stats: ProcessStats,
+    pub rlimits: RLimits,
+    /// Nice value of the process' threads, from -20 to 19
+    pub nice: i32,
+}
+
+/// A CPU exception raised by a process in ring 3
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub exception: &'static str,
+    pub rip: u64,
+    pub error_code: Option<u64>,
+    /// Faulting address of a page fault
+    pub address: Option<u64>,
 }
 
 pub struct UnsafePCB {
@@ -67,7 +122,11 @@ impl UnsafePCB {
     }
 }
 unsafe impl Sync for UnsafePCB {}
-type ProcessTable = Arc<RwLock<BTreeMap<u32, Arc<UnsafePCB>>>>;
+
+/// Slab cache for `Arc<UnsafePCB>`
+pub(crate) static PCB_CACHE: SlabCache = SlabCache::for_arc::<UnsafePCB>("pcb");
+pub type ProcessMap = BTreeMap<u32, Arc<UnsafePCB>>;
+type ProcessTable = Arc<RwLock<ProcessMap>>;
 
 // global process table must be thread-safe
 lazy_static::lazy_static! {
@@ -76,14 +135,280 @@ lazy_static::lazy_static! {
 }
 
 impl PCB {
-    /// Creates a page table mapper for temporary use during only process creation and cleanup
+    /// Creates a mapper for the process' page table
     /// # Safety
-    /// TODO
-    pub unsafe fn create_mapper(&mut self) -> OffsetPageTable<'_> {
+    /// The caller must hold the `vmas` lock while it changes user mappings,
+    /// and no other mapper of the table may be in use
+    pub unsafe fn create_mapper(&self) -> OffsetPageTable<'_> {
         let virt = *HHDM_OFFSET + self.pml4_frame.start_address().as_u64();
         let ptr = virt.as_mut_ptr::<PageTable>();
         OffsetPageTable::new(unsafe { &mut *ptr }, *HHDM_OFFSET)
     }
+
+    /// Locks the process' address space: its memory areas and the user half
+    /// of its page table
+    pub fn lock_vmas(&self) -> MutexGuard<'_, VmaList> {
+        loop {
+            if let Some(vmas) = self.vmas.try_lock() {
+                return vmas;
+            }
+            // The holder may be waiting for this core to flush its TLB
+            interrupts::without_interrupts(flush_pending_shootdown);
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Looks up the process running on the current core
+///
+/// # Returns
+/// The PCB of the running process, or None if a kernel event is running
+pub fn current_process() -> Option<Arc<UnsafePCB>> {
+    let pid = current_running_event_info().pid;
+    if pid == 0 {
+        return None;
+    }
+
+    PROCESS_TABLE.read().get(&pid).cloned()
+}
+
+/// Outcome of looking for an exited child
+pub enum ChildStatus {
+    /// The child with this PID exited with this wait status
+    Exited(u32, i32),
+    /// Matching children exist but none has exited
+    Running,
+    /// The process has no matching children
+    NoChildren,
+}
+
+/// Encodes the wait status of a process that called exit
+///
+/// * `code`: the exit code; only the low 8 bits are reported
+pub fn exit_wait_status(code: u64) -> i32 {
+    ((code & 0xff) << 8) as i32
+}
+
+/// Starts the exit of a process
+///
+/// Its children are handed to init and every thread is stopped before it
+/// next returns to ring 3. Once none of them is on a core the process becomes
+/// a zombie and its memory is freed. It stays in the table until its parent
+/// collects the exit status, unless it has no parent. Does nothing if the
+/// process is already exiting.
+///
+/// * `process_table`: the locked process table
+/// * `pid`: the exiting process
+/// * `exit_status`: wait status reported to the parent
+pub fn terminate_process(process_table: &mut ProcessMap, pid: u32, exit_status: i32) {
+    let Some(process) = process_table.get(&pid).cloned() else {
+        return;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+    if matches!(pcb.state, ProcessState::Terminated | ProcessState::Zombie) {
+        return;
+    }
+
+    pcb.state = ProcessState::Terminated;
+    pcb.exit_status = exit_status;
+    request_threads_exit(pcb, 0);
+
+    reparent_children(process_table, pid, core::mem::take(&mut pcb.children));
+    release_stopped_process(process_table, pid);
+}
+
+/// Turns an exiting process into a zombie and frees its memory, unless one
+/// of its threads is still on a core
+///
+/// * `process_table`: the locked process table
+/// * `pid`: the exiting process
+pub fn release_stopped_process(process_table: &mut ProcessMap, pid: u32) {
+    let Some(process) = process_table.get(&pid).cloned() else {
+        return;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+    let running = pcb
+        .threads
+        .lock()
+        .values()
+        .any(|thread| unsafe { (*thread.tcb.get()).state } == ProcessState::Running);
+    if pcb.state != ProcessState::Terminated || running {
+        return;
+    }
+
+    pcb.state = ProcessState::Zombie;
+    clear_process_frames(pcb);
+    // Close pipe ends now, so readers see end of file without waiting for
+    // the zombie to be collected
+    pcb.fd_table.lock().clear();
+
+    match process_table.get(&pcb.ppid) {
+        Some(parent) => wake_child_waiter(parent),
+        None => {
+            process_table.remove(&pid);
+        }
+    }
+}
+
+/// Terminates the running process after one of its threads raised a CPU
+/// exception in ring 3, then returns to the event loop
+///
+/// * `fault`: the exception, kept in the zombie unless the process was
+///   already exiting
+/// * `sig`: the signal the parent sees as the cause of death
+pub fn kill_faulting_process(fault: Fault, sig: u32) -> ! {
+    let event: EventInfo = current_running_event_info();
+    if event.pid == 0 {
+        panic!("User mode exception outside of a process: {:#x?}", fault);
+    }
+
+    serial_println!(
+        "Process {} killed by signal {}: {:#x?}",
+        event.pid,
+        sig,
+        fault
+    );
+
+    let preemption_info = {
+        let mut process_table = PROCESS_TABLE.write();
+        let process = process_table
+            .get(&event.pid)
+            .expect("Process not found")
+            .clone();
+        let pcb = unsafe { &mut *process.pcb.get() };
+        let thread = pcb
+            .threads
+            .lock()
+            .get(&event.tid)
+            .cloned()
+            .expect("Thread not found");
+        let tcb = unsafe { &mut *thread.tcb.get() };
+
+        if pcb.state != ProcessState::Terminated {
+            pcb.fault = Some(fault);
+        }
+        terminate_process(&mut process_table, event.pid, signal_wait_status(sig));
+        stop_thread(&mut process_table, event.pid, tcb);
+        (tcb.kernel_rsp, tcb.kernel_rip)
+    };
+
+    unsafe {
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1,
+            options(noreturn)
+        );
+    }
+}
+
+/// Hands the children of an exiting process to init, or drops the ones
+/// that already exited if there is no init to collect them
+fn reparent_children(process_table: &mut ProcessMap, pid: u32, children: Vec<u32>) {
+    let init = match process_table.get(&INIT_PID) {
+        Some(init) if INIT_PID != pid => Some(init.clone()),
+        _ => None,
+    };
+
+    let mut zombie_orphan = false;
+    for child_pid in children {
+        let Some(child) = process_table.get(&child_pid).cloned() else {
+            continue;
+        };
+        let child = unsafe { &mut *child.pcb.get() };
+        let is_zombie = child.state == ProcessState::Zombie;
+
+        match &init {
+            Some(init) => {
+                child.ppid = INIT_PID;
+                unsafe { (*init.pcb.get()).children.push(child_pid) };
+                zombie_orphan |= is_zombie;
+            }
+            None => {
+                child.ppid = 0;
+                if is_zombie {
+                    process_table.remove(&child_pid);
+                }
+            }
+        }
+    }
+
+    if let (Some(init), true) = (init, zombie_orphan) {
+        wake_child_waiter(&init);
+    }
+}
+
+fn wake_child_waiter(parent: &UnsafePCB) {
+    unsafe { (*parent.pcb.get()).child_exit.wake_all() };
+}
+
+/// Looks for an exited child without collecting it
+///
+/// * `target`: PID of the child to look for, or -1 for any child
+fn find_exited_child(process_table: &ProcessMap, parent: &PCB, target: i64) -> ChildStatus {
+    let mut found = false;
+    for &child_pid in parent.children.iter() {
+        if target != -1 && target != child_pid as i64 {
+            continue;
+        }
+        found = true;
+
+        let Some(child) = process_table.get(&child_pid) else {
+            continue;
+        };
+        let child = unsafe { &*child.pcb.get() };
+        if child.state == ProcessState::Zombie {
+            return ChildStatus::Exited(child_pid, child.exit_status);
+        }
+    }
+
+    if found {
+        ChildStatus::Running
+    } else {
+        ChildStatus::NoChildren
+    }
+}
+
+/// Collects an exited child, removing it from the process table
+///
+/// * `parent_pid`: the waiting process
+/// * `target`: PID of the child to wait for, or -1 for any child
+pub fn reap_child(parent_pid: u32, target: i64) -> ChildStatus {
+    let mut process_table = PROCESS_TABLE.write();
+    let Some(parent) = process_table.get(&parent_pid).cloned() else {
+        return ChildStatus::NoChildren;
+    };
+    let parent = unsafe { &mut *parent.pcb.get() };
+
+    let status = find_exited_child(&process_table, parent, target);
+    if let ChildStatus::Exited(child_pid, _) = status {
+        if let Some(child) = process_table.remove(&child_pid) {
+            parent.stats.add_child(unsafe { &(*child.pcb.get()).stats });
+        }
+        parent.children.retain(|&pid| pid != child_pid);
+    }
+    status
+}
+
+/// Looks for an exited child like `reap_child`, but leaves it in the table
+///
+/// * `parent_pid`: the waiting process
+/// * `target`: PID of the child to look for, or -1 for any child
+pub fn peek_child(parent_pid: u32, target: i64) -> ChildStatus {
+    let process_table = PROCESS_TABLE.read();
+    let Some(parent) = process_table.get(&parent_pid) else {
+        return ChildStatus::NoChildren;
+    };
+    let parent = unsafe { &*parent.pcb.get() };
+    find_exited_child(&process_table, parent, target)
+}
+
+/// Whether `reap_child` would return without waiting for a child to exit
+pub fn child_waitable(parent_pid: u32, target: i64) -> bool {
+    !matches!(peek_child(parent_pid, target), ChildStatus::Running)
 }
 
 /// # Safety
@@ -101,37 +426,47 @@ pub unsafe fn print_process_table(process_table: &PROCESS_TABLE) {
 
     for (pid, pcb) in table.iter() {
         let pcb = pcb.pcb.get();
+        let stats = &(*pcb).stats;
         serial_println!(
-            "PID {}: State: {:?}, Registers: {:?}, SP: {:#x}, PC: {:#x}",
+            "PID {}: State: {:?}, PPID: {}, Created: {}, User ticks: {}, Kernel ticks: {}, \
+             Switches: {}, Core: {}, Frames: {}",
             pid,
             (*pcb).state,
-            (*pcb).registers,
-            (*pcb).registers.rsp,
-            (*pcb).registers.rip
+            (*pcb).ppid,
+            stats.created,
+            stats.user_ticks.load(Ordering::Relaxed),
+            stats.kernel_ticks.load(Ordering::Relaxed),
+            stats.context_switches.load(Ordering::Relaxed),
+            stats.last_core.load(Ordering::Relaxed),
+            resident_frames(&*pcb)
         );
+        for (tid, tcb) in (*pcb).threads.lock().iter() {
+            let tcb = tcb.tcb.get();
+            serial_println!(
+                "  TID {}: State: {:?}, Registers: {:?}, SP: {:#x}, PC: {:#x}",
+                tid,
+                (*tcb).state,
+                (*tcb).registers,
+                (*tcb).registers.rsp,
+                (*tcb).registers.rip
+            );
+        }
     }
     serial_println!("========================");
 }
 
 pub fn create_process(elf_bytes: &[u8]) -> u32 {
-    let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst);
+    let pid = next_pid();
 
     // Build a new process address space
     let process_pml4_frame = unsafe { create_process_page_table() };
-    let mut mapper = unsafe {
-        let virt = *HHDM_OFFSET + process_pml4_frame.start_address().as_u64();
-        let ptr = virt.as_mut_ptr::<PageTable>();
-        OffsetPageTable::new(&mut *ptr, *HHDM_OFFSET)
-    };
-    let (stack_top, entry_point) = load_elf(elf_bytes, &mut mapper, &mut MAPPER.lock());
+    let mut vmas = VmaList::new();
+    let (stack_top, entry_point) = load_elf(elf_bytes, &mut vmas);
 
-    let process = Arc::new(UnsafePCB::init(PCB {
+    let main_thread = TCB::new(
         pid,
-        state: ProcessState::New,
-        kernel_rsp: 0,
-        kernel_rip: 0,
-        next_preemption_time: 0,
-        registers: Registers {
+        pid,
+        Registers {
             rax: 0,
             rbx: 0,
             rcx: 0,
@@ -151,8 +486,28 @@ pub fn create_process(elf_bytes: &[u8]) -> u32 {
             rip: entry_point,
             rflags: 0x202,
         },
+    );
+
+    let process = Arc::new(UnsafePCB::init(PCB {
+        pid,
+        state: ProcessState::New,
+        threads: Mutex::new(BTreeMap::from([(
+            pid,
+            Arc::new(UnsafeTCB::init(main_thread)),
+        )])),
         pml4_frame: process_pml4_frame,
+        vmas: Mutex::new(vmas),
         namespace: Namespace::new(),
+        fd_table: Mutex::new(FdTable::with_stdio()),
+        ppid: 0,
+        children: Vec::new(),
+        exit_status: 0,
+        child_exit: WaitQueue::new(),
+        signals: Mutex::new(SignalState::new()),
+        fault: None,
+        stats: ProcessStats::new(runner_timestamp()),
+        rlimits: RLimits::new(),
+        nice: 0,
     }));
     let pid = unsafe { (*process.pcb.get()).pid };
     PROCESS_TABLE.write().insert(pid, Arc::clone(&process));
@@ -161,6 +516,78 @@ pub fn create_process(elf_bytes: &[u8]) -> u32 {
     pid
 }
 
+/// Duplicates a process, sharing its user memory copy-on-write
+///
+/// The child gets a copy of the parent's namespace and shares its open files.
+/// Only the forking thread is copied, as the child's main thread. It is not
+/// scheduled.
+///
+/// * `parent`: the process to duplicate, which must be running on this core
+/// * `registers`: the forking thread's registers at the fork; the child
+///   resumes from them with `rax` set to 0
+///
+/// Returns the PID of the child, or `EAGAIN` if the parent has reached its
+/// `RLIMIT_NPROC` limit
+pub fn fork_process(parent: &mut PCB, registers: Registers) -> Result<u32, i64> {
+    if !may_add_task(parent) {
+        return Err(EAGAIN);
+    }
+    let pid = next_pid();
+
+    let child_pml4_frame = unsafe { create_process_page_table() };
+    let vmas = {
+        let vmas = parent.lock_vmas();
+        unsafe {
+            duplicate_user_space(parent.pml4_frame, child_pml4_frame);
+        }
+        // The parent's writable pages just became read-only, also for its
+        // threads on other cores
+        tlb_shootdown_all();
+        vmas.clone()
+    };
+
+    let mut main_thread = TCB::new(
+        pid,
+        pid,
+        Registers {
+            rax: 0,
+            ..registers
+        },
+    );
+    main_thread.state = ProcessState::Ready;
+    // The kernel does not touch these registers, so they still hold the
+    // parent's values
+    main_thread.fpu.save();
+
+    let process = Arc::new(UnsafePCB::init(PCB {
+        pid,
+        state: ProcessState::Ready,
+        threads: Mutex::new(BTreeMap::from([(
+            pid,
+            Arc::new(UnsafeTCB::init(main_thread)),
+        )])),
+        pml4_frame: child_pml4_frame,
+        vmas: Mutex::new(vmas),
+        namespace: parent.namespace.clone(),
+        fd_table: Mutex::new(parent.fd_table.lock().clone()),
+        ppid: parent.pid,
+        children: Vec::new(),
+        exit_status: 0,
+        child_exit: WaitQueue::new(),
+        signals: Mutex::new(parent.signals.lock().fork()),
+        fault: None,
+        stats: ProcessStats::new(runner_timestamp()),
+        rlimits: parent.rlimits.clone(),
+        nice: parent.nice,
+    }));
+    let mut process_table = PROCESS_TABLE.write();
+    process_table.insert(pid, process);
+    parent.children.push(pid);
+    drop(process_table);
+    debug!("Forked process {} from {}", pid, parent.pid);
+    Ok(pid)
+}
+
 /// # Safety
 ///
 /// TODO
@@ -185,25 +612,94 @@ unsafe fn create_process_page_table() -> PhysFrame<Size4KiB> {
 /// Clear the PML4 associated with the PCB
 ///
 /// * `pcb`: The process PCB to clear memory for
-pub fn clear_process_frames(pcb: &mut PCB) {
+pub fn clear_process_frames(pcb: &PCB) {
     let pml4_frame = pcb.pml4_frame;
-    let mapper = unsafe { pcb.create_mapper() };
-
-    with_generic_allocator(|deallocator| {
-        // Iterate over first 256 entries (user space)
-        for i in 0..256 {
-            let entry = &mapper.level_4_table()[i];
-            if entry.is_unused() {
-                continue;
-            }
+    clear_user_mappings(pcb, &mut pcb.lock_vmas());
+
+    // This core may still be on the process' page table; leave it before freeing
+    let (active_frame, cr3_flags) = Cr3::read();
+    if active_frame == pml4_frame {
+        let kernel_pml4 = MAPPER.lock().level_4_table() as *const PageTable as u64;
+        let kernel_frame =
+            PhysFrame::containing_address(PhysAddr::new(kernel_pml4 - HHDM_OFFSET.as_u64()));
+        unsafe { Cr3::write(kernel_frame, cr3_flags) };
+    }
 
-            let pdpt_frame = PhysFrame::containing_address(entry.addr());
-            unsafe {
-                free_page_table(pdpt_frame, 3, deallocator, HHDM_OFFSET.as_u64());
-            }
+    dealloc_frame(pml4_frame);
+}
+
+/// Unmaps a process' user memory, freeing its frames and user page tables
+/// but keeping the PML4 and its kernel mappings. Its memory areas are dropped.
+///
+/// * `pcb`: The process PCB to clear user memory for
+/// * `vmas`: the process' locked memory areas
+pub fn clear_user_mappings(pcb: &PCB, vmas: &mut VmaList) {
+    vmas.clear();
+    let mut mapper = unsafe { pcb.create_mapper() };
+
+    // Iterate over first 256 entries (user space)
+    for entry in mapper.level_4_table_mut().iter_mut().take(256) {
+        if entry.is_unused() {
+            continue;
+        }
+
+        let pdpt_frame = PhysFrame::containing_address(entry.addr());
+        unsafe {
+            free_page_table(
+                pdpt_frame,
+                3,
+                &mut CachedFrameAllocator,
+                HHDM_OFFSET.as_u64(),
+            );
         }
-        unsafe { deallocator.deallocate_frame(pml4_frame) };
+        entry.set_unused();
+    }
+}
+
+/// Replaces a process' program image
+///
+/// Tears down the old user memory, loads the executable and builds a new
+/// stack holding the arguments and environment. Caught signals revert to
+/// their default action. Only the calling thread is kept; it resumes at the
+/// entry point with all other registers cleared.
+/// If the stack cannot be written once the old image is gone, the process
+/// is sent `SIGSEGV`.
+///
+/// * `pcb`: the process, none of whose threads may be running
+/// * `tid`: the thread that called exec
+/// * `elf_bytes`: the executable, already checked with `is_loadable_elf`
+/// * `args`: argument strings, without NUL terminators, already checked to
+///   fit with `initial_stack_size`
+/// * `env`: environment strings, without NUL terminators
+pub fn exec_process(pcb: &PCB, tid: u32, elf_bytes: &[u8], args: &[Vec<u8>], env: &[Vec<u8>]) {
+    let (stack_top, entry_point) = {
+        let mut vmas = pcb.lock_vmas();
+        clear_user_mappings(pcb, &mut vmas);
+        // This core may still have the process' page table loaded
+        x86_64::instructions::tlb::flush_all();
+        load_elf(elf_bytes, &mut vmas)
+    };
+    pcb.signals.lock().reset_handlers();
+
+    let auxv = auxiliary_vector(elf_bytes);
+    let rsp = build_initial_stack(pcb, stack_top.as_u64(), args, env, &auxv).unwrap_or_else(|_| {
+        // There is no old image left to return an error to
+        pcb.signals.lock().force(SIGSEGV);
+        stack_top.as_u64()
     });
+
+    let mut threads = pcb.threads.lock();
+    threads.retain(|&other, _| other == tid);
+    let thread = threads.get(&tid).expect("Thread not found");
+    let tcb = unsafe { &mut *thread.tcb.get() };
+    tcb.user_stack = None;
+    tcb.fpu = FpuState::new();
+    tcb.registers = Registers {
+        rsp,
+        rip: entry_point,
+        rflags: 0x202,
+        ..Registers::new()
+    };
 }
 
 /// Helper function to recursively multi level page tables
@@ -226,13 +722,22 @@ unsafe fn free_page_table(
             continue;
         }
 
-        if level > 1 {
+        if level > 1 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // Huge pages are never shared, as fork splits them
+            if level == 3 {
+                dealloc_sized_frame(PhysFrame::<Size1GiB>::containing_address(entry.addr()));
+            } else {
+                dealloc_sized_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr()));
+            }
+        } else if level > 1 {
             let child_frame = PhysFrame::containing_address(entry.addr());
             free_page_table(child_frame, level - 1, deallocator, hhdm_offset);
         } else {
-            // Free level one page
+            // Free level one page, unless another process still shares it
             let page_frame = PhysFrame::containing_address(entry.addr());
-            deallocator.deallocate_frame(page_frame);
+            if release_frame(page_frame) {
+                deallocator.deallocate_frame(page_frame);
+            }
         }
         entry.set_unused();
     }
@@ -247,32 +752,51 @@ use x86_64::registers::control::{Cr3, Cr3Flags};
 ///
 /// TODO
 #[no_mangle]
-pub async unsafe fn run_process_ring3(pid: u32) {
+pub async unsafe fn run_process_ring3(pid: u32, tid: u32) {
     interrupts::disable();
 
-    let process = {
-        let process_table = PROCESS_TABLE.read();
-        let process = process_table
-            .get(&pid)
-            .expect("Could not find process from process table");
-        process.clone()
+    // The process may have been reaped, or the thread joined, since this
+    // event was scheduled
+    let Some(process) = PROCESS_TABLE.read().get(&pid).cloned() else {
+        return;
+    };
+    let Some(thread) = get_thread(pid, tid) else {
+        return;
     };
 
     // Do not lock lowest common denominator
-    // Once kernel threads are in, will need lock around PCB
-    // But not TCB
+    // The PCB is shared by the process' threads, the TCB is only touched by
+    // the core running the thread
     let process = process.pcb.get();
+    let thread = thread.tcb.get();
+    (*thread).parked.lock().take();
+
+    if (*thread).exit_requested.load(Ordering::Acquire) {
+        stop_thread(&mut PROCESS_TABLE.write(), pid, &mut *thread);
+        return;
+    }
+
+    if let Some(sig) = deliver_signals(&*process, &mut (*thread).registers, &mut (*thread).fpu) {
+        debug!("Process {} terminated by signal {}", pid, sig);
+        let mut process_table = PROCESS_TABLE.write();
+        terminate_process(&mut process_table, pid, signal_wait_status(sig));
+        stop_thread(&mut process_table, pid, &mut *thread);
+        return;
+    }
 
-    (*process).next_preemption_time = runner_timestamp() + nanos_to_ticks(PROCESS_TIMESLICE);
+    (*thread).next_preemption_time = monotonic_nanos() + PROCESS_TIMESLICE;
+    (*process)
+        .stats
+        .switched_in(x2apic::current_core_id() as u32);
 
     Cr3::write((*process).pml4_frame, Cr3Flags::empty());
 
     let user_cs = gdt::GDT.1.user_code_selector.0 as u64;
     let user_ds = gdt::GDT.1.user_data_selector.0 as u64;
 
-    let registers = &(*process).registers.clone();
+    let registers = &(*thread).registers.clone();
 
-    (*process).kernel_rip = return_process as usize as u64;
+    (*thread).kernel_rip = return_process as usize as u64;
 
     // Stack layout to move into user mode
     unsafe {
@@ -287,8 +811,9 @@ pub async unsafe fn run_process_ring3(pid: u32) {
             in("rdi") registers as *const Registers,
             in("rsi") user_ds,
             in("rdx") user_cs,
-            in("rcx") &(*process).kernel_rsp,
-            in("r8")  &(*process).state
+            in("rcx") &(*thread).kernel_rsp,
+            in("r8")  &(*thread).state,
+            in("r9")  (*thread).fpu.as_ptr()
         );
     }
 }
@@ -302,6 +827,7 @@ unsafe fn call_process(
     user_cs: u64,
     kernel_rsp: *const u64,
     process_state: *const u8,
+    fpu_area: *const u8,
 ) {
     naked_asm!(
         //save callee-saved registers
@@ -328,6 +854,16 @@ unsafe fn call_process(
         "mov rax, [rdi + 136]",
         "push rax", //rflags
         "push rdx", //cs
+        // Restore x87/SSE/AVX state, every component enabled in XCR0
+        "mov eax, -1",
+        "mov edx, -1",
+        "cmp byte ptr [rip + {use_xsave}], 0",
+        "je 2f",
+        "xrstor64 [r9]",
+        "jmp 3f",
+        "2:",
+        "fxrstor64 [r9]",
+        "3:",
         "mov rax, [rdi + 128]",
         "push rax",             //rip
         "mov byte ptr [r8], 2", //set state to ProcessState::Running
@@ -349,6 +885,7 @@ unsafe fn call_process(
         "mov rdi, [rdi+40]",
         "sti",   //enable interrupts
         "iretq", // call process
+        use_xsave = sym USE_XSAVE,
     );
 }
 
@@ -383,52 +920,48 @@ pub fn preempt_process(rsp: u64) {
         return;
     }
 
-    // Get PCB from PID
+    // Get TCB from PID and TID
     let preemption_info = unsafe {
-        let mut process_table = PROCESS_TABLE.write();
-        let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
-
-        let pcb = process.pcb.get();
+        let thread = get_thread(event.pid, event.tid).expect("Thread not found");
+        let tcb = thread.tcb.get();
 
         // Don't preempt if conditions are not met
-        if (*pcb).state != ProcessState::Running
-            || (*pcb).next_preemption_time <= runner_timestamp()
+        if (*tcb).state != ProcessState::Running || (*tcb).next_preemption_time <= monotonic_nanos()
         {
             return;
         }
 
-        // save registers to the PCB
+        // save registers to the TCB
         let stack_ptr: *const u64 = rsp as *const u64;
 
-        (*pcb).registers.rax = *stack_ptr.add(0);
-        (*pcb).registers.rbx = *stack_ptr.add(1);
-        (*pcb).registers.rcx = *stack_ptr.add(2);
-        (*pcb).registers.rdx = *stack_ptr.add(3);
-        (*pcb).registers.rsi = *stack_ptr.add(4);
-        (*pcb).registers.rdi = *stack_ptr.add(5);
-        (*pcb).registers.r8 = *stack_ptr.add(6);
-        (*pcb).registers.r9 = *stack_ptr.add(7);
-        (*pcb).registers.r10 = *stack_ptr.add(8);
-        (*pcb).registers.r11 = *stack_ptr.add(9);
-        (*pcb).registers.r12 = *stack_ptr.add(10);
-        (*pcb).registers.r13 = *stack_ptr.add(11);
-        (*pcb).registers.r14 = *stack_ptr.add(12);
-        (*pcb).registers.r15 = *stack_ptr.add(13);
-        (*pcb).registers.rbp = *stack_ptr.add(14);
+        (*tcb).registers.rax = *stack_ptr.add(0);
+        (*tcb).registers.rbx = *stack_ptr.add(1);
+        (*tcb).registers.rcx = *stack_ptr.add(2);
+        (*tcb).registers.rdx = *stack_ptr.add(3);
+        (*tcb).registers.rsi = *stack_ptr.add(4);
+        (*tcb).registers.rdi = *stack_ptr.add(5);
+        (*tcb).registers.r8 = *stack_ptr.add(6);
+        (*tcb).registers.r9 = *stack_ptr.add(7);
+        (*tcb).registers.r10 = *stack_ptr.add(8);
+        (*tcb).registers.r11 = *stack_ptr.add(9);
+        (*tcb).registers.r12 = *stack_ptr.add(10);
+        (*tcb).registers.r13 = *stack_ptr.add(11);
+        (*tcb).registers.r14 = *stack_ptr.add(12);
+        (*tcb).registers.r15 = *stack_ptr.add(13);
+        (*tcb).registers.rbp = *stack_ptr.add(14);
         // saved from interrupt stack frame
-        (*pcb).registers.rsp = *stack_ptr.add(18);
-        (*pcb).registers.rip = *stack_ptr.add(15);
-        (*pcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).registers.rsp = *stack_ptr.add(18);
+        (*tcb).registers.rip = *stack_ptr.add(15);
+        (*tcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).fpu.save();
 
-        (*pcb).state = ProcessState::Ready;
+        (*tcb).state = ProcessState::Ready;
 
-        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        ((*tcb).kernel_rsp, (*tcb).kernel_rip)
     };
 
     unsafe {
-        schedule_process(event.pid);
+        schedule_thread(event.pid, event.tid);
 
         // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
         core::arch::asm!(
@@ -450,45 +983,42 @@ pub fn block_process(rsp: u64) {
         return;
     }
 
-    // Get PCB from PID
+    // Get TCB from PID and TID
     let preemption_info = unsafe {
-        let mut process_table = PROCESS_TABLE.write();
-        let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
+        let thread = get_thread(event.pid, event.tid).expect("Thread not found");
+        let tcb = thread.tcb.get();
 
-        let pcb = process.pcb.get();
-
-        // save registers to the PCB
+        // save registers to the TCB
         let stack_ptr: *const u64 = rsp as *const u64;
 
-        (*pcb).registers.rax = *stack_ptr.add(0);
-        (*pcb).registers.rbx = *stack_ptr.add(1);
-        (*pcb).registers.rcx = *stack_ptr.add(2);
-        (*pcb).registers.rdx = *stack_ptr.add(3);
-        (*pcb).registers.rsi = *stack_ptr.add(4);
-        (*pcb).registers.rdi = *stack_ptr.add(5);
-        (*pcb).registers.r8 = *stack_ptr.add(6);
-        (*pcb).registers.r9 = *stack_ptr.add(7);
-        (*pcb).registers.r10 = *stack_ptr.add(8);
-        (*pcb).registers.r11 = *stack_ptr.add(9);
-        (*pcb).registers.r12 = *stack_ptr.add(10);
-        (*pcb).registers.r13 = *stack_ptr.add(11);
-        (*pcb).registers.r14 = *stack_ptr.add(12);
-        (*pcb).registers.r15 = *stack_ptr.add(13);
-        (*pcb).registers.rbp = *stack_ptr.add(14);
+        (*tcb).registers.rax = *stack_ptr.add(0);
+        (*tcb).registers.rbx = *stack_ptr.add(1);
+        (*tcb).registers.rcx = *stack_ptr.add(2);
+        (*tcb).registers.rdx = *stack_ptr.add(3);
+        (*tcb).registers.rsi = *stack_ptr.add(4);
+        (*tcb).registers.rdi = *stack_ptr.add(5);
+        (*tcb).registers.r8 = *stack_ptr.add(6);
+        (*tcb).registers.r9 = *stack_ptr.add(7);
+        (*tcb).registers.r10 = *stack_ptr.add(8);
+        (*tcb).registers.r11 = *stack_ptr.add(9);
+        (*tcb).registers.r12 = *stack_ptr.add(10);
+        (*tcb).registers.r13 = *stack_ptr.add(11);
+        (*tcb).registers.r14 = *stack_ptr.add(12);
+        (*tcb).registers.r15 = *stack_ptr.add(13);
+        (*tcb).registers.rbp = *stack_ptr.add(14);
         // saved from interrupt stack frame
-        (*pcb).registers.rsp = *stack_ptr.add(18);
-        (*pcb).registers.rip = *stack_ptr.add(15);
-        (*pcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).registers.rsp = *stack_ptr.add(18);
+        (*tcb).registers.rip = *stack_ptr.add(15);
+        (*tcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).fpu.save();
 
-        (*pcb).state = ProcessState::Blocked;
+        (*tcb).state = ProcessState::Blocked;
 
-        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        ((*tcb).kernel_rsp, (*tcb).kernel_rip)
     };
 
     unsafe {
-        // TODO schedule blocked process
+        // Whoever the thread waits for schedules it again, see `block_on`
 
         // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
         core::arch::asm!(
@@ -504,51 +1034,150 @@ pub fn block_process(rsp: u64) {
     }
 }
 
-pub fn sleep_process(rsp: u64, nanos: u64) {
+/// Parks the running thread on a wait queue until it is woken, then runs its
+/// syscall again from the start
+///
+/// The syscall must not have changed anything it would repeat, and must wait
+/// on a condition that is changed before `queue` is woken. A parked thread is
+/// also woken to exit or to handle a signal, after which the syscall restarts.
+///
+/// * `rsp`: the saved register frame of the syscall
+/// * `queue`: the queue to wait on
+/// * `ready`: checks the condition once the thread is registered, in case the
+///   queue was woken since the syscall last checked it. It is called exactly
+///   once and dropped before the thread is switched out, so it may own what
+///   keeps `queue` alive.
+pub fn park_process(rsp: u64, queue: &WaitQueue, ready: impl FnOnce() -> bool) {
     let event: EventInfo = current_running_event_info();
     if event.pid == 0 {
         return;
     }
 
-    // Get PCB from PID
     let preemption_info = unsafe {
-        let mut process_table = PROCESS_TABLE.write();
-        let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
+        let process = PROCESS_TABLE.read().get(&event.pid).cloned();
+        let process = process.expect("Process not found");
+        let thread = get_thread(event.pid, event.tid).expect("Thread not found");
+        let tcb = thread.tcb.get();
+
+        (*tcb).registers = Registers::from_stack_frame(rsp);
+        // rax still holds the syscall number
+        (*tcb).registers.rip -= SYSCALL_INSTRUCTION_LEN;
+        (*tcb).fpu.save();
+        (*tcb).state = ProcessState::Blocked;
+        // Once registered, the thread may be woken and run on another core,
+        // which replaces these
+        let preemption_info = ((*tcb).kernel_rsp, (*tcb).kernel_rip);
+
+        let waker = schedule_blocked_process(event.pid, event.tid);
+        *(*tcb).parked.lock() = Some(waker.clone());
+        queue.register(&waker);
+
+        // Whatever woke the thread in the meantime may have missed it. The
+        // event runs the thread only once, so being woken twice is harmless.
+        if ready()
+            || (*tcb).exit_requested.load(Ordering::Acquire)
+            || (*process.pcb.get()).signals.lock().deliverable()
+        {
+            waker.wake();
+        }
+
+        preemption_info
+    };
+
+    unsafe {
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1
+        );
+
+        x2apic::send_eoi();
+
+        core::arch::asm!("ret");
+    }
+}
+
+/// Puts the running thread back in the scheduler without waiting for its
+/// timeslice to end, e.g. so pending signals are delivered right away
+///
+/// * `rsp`: the saved register frame of the thread
+pub fn yield_process(rsp: u64) {
+    let event: EventInfo = current_running_event_info();
+    if event.pid == 0 {
+        return;
+    }
+
+    let preemption_info = unsafe {
+        let thread = get_thread(event.pid, event.tid).expect("Thread not found");
+        let tcb = thread.tcb.get();
+        (*tcb).registers = Registers::from_stack_frame(rsp);
+        (*tcb).fpu.save();
+        (*tcb).state = ProcessState::Ready;
+
+        ((*tcb).kernel_rsp, (*tcb).kernel_rip)
+    };
+
+    unsafe {
+        schedule_thread(event.pid, event.tid);
 
-        let pcb = process.pcb.get();
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1
+        );
+
+        x2apic::send_eoi();
+
+        core::arch::asm!("ret");
+    }
+}
+
+pub fn sleep_process(rsp: u64, nanos: u64) {
+    let event: EventInfo = current_running_event_info();
+    if event.pid == 0 {
+        return;
+    }
+
+    // Get TCB from PID and TID
+    let preemption_info = unsafe {
+        let thread = get_thread(event.pid, event.tid).expect("Thread not found");
+        let tcb = thread.tcb.get();
 
-        // save registers to the PCB
+        // save registers to the TCB
         let stack_ptr: *const u64 = rsp as *const u64;
 
-        (*pcb).registers.rax = *stack_ptr.add(0);
-        (*pcb).registers.rbx = *stack_ptr.add(1);
-        (*pcb).registers.rcx = *stack_ptr.add(2);
-        (*pcb).registers.rdx = *stack_ptr.add(3);
-        (*pcb).registers.rsi = *stack_ptr.add(4);
-        (*pcb).registers.rdi = *stack_ptr.add(5);
-        (*pcb).registers.r8 = *stack_ptr.add(6);
-        (*pcb).registers.r9 = *stack_ptr.add(7);
-        (*pcb).registers.r10 = *stack_ptr.add(8);
-        (*pcb).registers.r11 = *stack_ptr.add(9);
-        (*pcb).registers.r12 = *stack_ptr.add(10);
-        (*pcb).registers.r13 = *stack_ptr.add(11);
-        (*pcb).registers.r14 = *stack_ptr.add(12);
-        (*pcb).registers.r15 = *stack_ptr.add(13);
-        (*pcb).registers.rbp = *stack_ptr.add(14);
+        (*tcb).registers.rax = *stack_ptr.add(0);
+        (*tcb).registers.rbx = *stack_ptr.add(1);
+        (*tcb).registers.rcx = *stack_ptr.add(2);
+        (*tcb).registers.rdx = *stack_ptr.add(3);
+        (*tcb).registers.rsi = *stack_ptr.add(4);
+        (*tcb).registers.rdi = *stack_ptr.add(5);
+        (*tcb).registers.r8 = *stack_ptr.add(6);
+        (*tcb).registers.r9 = *stack_ptr.add(7);
+        (*tcb).registers.r10 = *stack_ptr.add(8);
+        (*tcb).registers.r11 = *stack_ptr.add(9);
+        (*tcb).registers.r12 = *stack_ptr.add(10);
+        (*tcb).registers.r13 = *stack_ptr.add(11);
+        (*tcb).registers.r14 = *stack_ptr.add(12);
+        (*tcb).registers.r15 = *stack_ptr.add(13);
+        (*tcb).registers.rbp = *stack_ptr.add(14);
         // saved from interrupt stack frame
-        (*pcb).registers.rsp = *stack_ptr.add(18);
-        (*pcb).registers.rip = *stack_ptr.add(15);
-        (*pcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).registers.rsp = *stack_ptr.add(18);
+        (*tcb).registers.rip = *stack_ptr.add(15);
+        (*tcb).registers.rflags = *stack_ptr.add(17);
+        (*tcb).fpu.save();
 
-        (*pcb).state = ProcessState::Blocked;
+        (*tcb).state = ProcessState::Blocked;
 
-        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        ((*tcb).kernel_rsp, (*tcb).kernel_rip)
     };
 
     unsafe {
-        nanosleep_current_process(event.pid, nanos);
+        nanosleep_current_process(event.pid, event.tid, nanos);
 
         // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
         core::arch::asm!(
@@ -72,6 +72,66 @@ impl Registers {
     }
 }
 
+impl Registers {
+    /// Reads the registers a process was interrupted with from the frame
+    /// pushed by an interrupt or syscall entry point.
+    ///
+    /// # Safety
+    /// `rsp` must point to a register frame laid out by `push_registers`
+    /// followed by an interrupt stack frame
+    pub unsafe fn from_stack_frame(rsp: u64) -> Self {
+        let stack_ptr: *const u64 = rsp as *const u64;
+        Self {
+            rax: *stack_ptr.add(0),
+            rbx: *stack_ptr.add(1),
+            rcx: *stack_ptr.add(2),
+            rdx: *stack_ptr.add(3),
+            rsi: *stack_ptr.add(4),
+            rdi: *stack_ptr.add(5),
+            r8: *stack_ptr.add(6),
+            r9: *stack_ptr.add(7),
+            r10: *stack_ptr.add(8),
+            r11: *stack_ptr.add(9),
+            r12: *stack_ptr.add(10),
+            r13: *stack_ptr.add(11),
+            r14: *stack_ptr.add(12),
+            r15: *stack_ptr.add(13),
+            rbp: *stack_ptr.add(14),
+            rip: *stack_ptr.add(15),
+            rflags: *stack_ptr.add(17),
+            rsp: *stack_ptr.add(18),
+        }
+    }
+
+    /// Overwrites a saved register frame, so the interrupted process resumes
+    /// with these registers. The code and stack segments are kept.
+    ///
+    /// # Safety
+    /// `rsp` must point to a register frame laid out by `push_registers`
+    /// followed by an interrupt stack frame
+    pub unsafe fn write_to_stack_frame(&self, rsp: u64) {
+        let stack_ptr: *mut u64 = rsp as *mut u64;
+        *stack_ptr.add(0) = self.rax;
+        *stack_ptr.add(1) = self.rbx;
+        *stack_ptr.add(2) = self.rcx;
+        *stack_ptr.add(3) = self.rdx;
+        *stack_ptr.add(4) = self.rsi;
+        *stack_ptr.add(5) = self.rdi;
+        *stack_ptr.add(6) = self.r8;
+        *stack_ptr.add(7) = self.r9;
+        *stack_ptr.add(8) = self.r10;
+        *stack_ptr.add(9) = self.r11;
+        *stack_ptr.add(10) = self.r12;
+        *stack_ptr.add(11) = self.r13;
+        *stack_ptr.add(12) = self.r14;
+        *stack_ptr.add(13) = self.r15;
+        *stack_ptr.add(14) = self.rbp;
+        *stack_ptr.add(15) = self.rip;
+        *stack_ptr.add(17) = self.rflags;
+        *stack_ptr.add(18) = self.rsp;
+    }
+}
+
 impl Default for Registers {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,120 @@
+//! Resource limits
+//!
+//! Each process has a soft and a hard limit per resource. The soft limit is
+//! enforced; a process may move it anywhere up to the hard limit and lower
+//! the hard limit, but never raise it again. Limits are inherited on fork and
+//! kept across exec.
+//!
+//! Enforced limits:
+//! - `RLIMIT_STACK`: how far a stack may grow, applied to the memory areas
+//! - `RLIMIT_RSS`: user memory mapped to frames, checked when a page is
+//!   faulted in
+//! - `RLIMIT_NPROC`: children not yet waited for plus threads, checked by
+//!   fork and thread creation
+//! - `RLIMIT_NOFILE`: open file descriptors, checked when one is allocated
+//! - `RLIMIT_NICE`: how far `setpriority` may lower the nice value; 0 by
+//!   default, so it may not be lowered at all
+
+use crate::{
+    constants::{
+        errno::{EINVAL, EPERM},
+        memory::PAGE_SIZE,
+        processes::{DEFAULT_MAX_FILES, DEFAULT_MAX_PROCESSES, DEFAULT_MAX_RESIDENT, STACK_LIMIT},
+        syscalls::{
+            RLIMIT_NICE, RLIMIT_NOFILE, RLIMIT_NPROC, RLIMIT_RSS, RLIMIT_STACK, RLIM_INFINITY,
+            RLIM_NLIMITS,
+        },
+    },
+    processes::process::PCB,
+};
+
+/// A soft and hard limit, laid out like `struct rlimit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RLimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl RLimit {
+    const fn both(limit: u64) -> Self {
+        Self {
+            cur: limit,
+            max: limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RLimits {
+    limits: [RLimit; RLIM_NLIMITS],
+}
+
+impl RLimits {
+    /// Limits of a process created by the kernel
+    pub fn new() -> Self {
+        let mut limits = [RLimit::both(RLIM_INFINITY); RLIM_NLIMITS];
+        limits[RLIMIT_STACK as usize] = RLimit {
+            cur: STACK_LIMIT,
+            max: RLIM_INFINITY,
+        };
+        limits[RLIMIT_RSS as usize] = RLimit::both(DEFAULT_MAX_RESIDENT);
+        limits[RLIMIT_NPROC as usize] = RLimit::both(DEFAULT_MAX_PROCESSES);
+        limits[RLIMIT_NOFILE as usize] = RLimit::both(DEFAULT_MAX_FILES);
+        limits[RLIMIT_NICE as usize] = RLimit::both(0);
+        Self { limits }
+    }
+
+    /// Returns the limits of a resource, or None if there is no such resource
+    pub fn get(&self, resource: u64) -> Option<RLimit> {
+        self.limits.get(resource as usize).copied()
+    }
+
+    /// The enforced limit of a resource
+    pub fn current(&self, resource: u64) -> u64 {
+        self.limits[resource as usize].cur
+    }
+
+    /// Replaces the limits of a resource
+    ///
+    /// Fails with `EINVAL` for an unknown resource or a soft limit above the
+    /// hard one, and with `EPERM` if the hard limit would be raised.
+    pub fn set(&mut self, resource: u64, limit: RLimit) -> Result<(), i64> {
+        let old = self.limits.get_mut(resource as usize).ok_or(EINVAL)?;
+        if limit.cur > limit.max {
+            return Err(EINVAL);
+        }
+        if limit.max > old.max {
+            return Err(EPERM);
+        }
+        *old = limit;
+        Ok(())
+    }
+
+    /// Most frames the process may have mapped at once
+    pub fn max_resident_frames(&self) -> u64 {
+        self.current(RLIMIT_RSS) / PAGE_SIZE as u64
+    }
+}
+
+impl Default for RLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sets a resource limit of a process and applies it where it is kept
+/// outside `RLimits`
+pub fn set_rlimit(pcb: &mut PCB, resource: u64, limit: RLimit) -> Result<(), i64> {
+    pcb.rlimits.set(resource, limit)?;
+    if resource == RLIMIT_STACK {
+        pcb.lock_vmas().stack_limit = limit.cur;
+    }
+    Ok(())
+}
+
+/// Whether a process may add another child or thread
+pub fn may_add_task(pcb: &PCB) -> bool {
+    let tasks = pcb.children.len() + pcb.threads.lock().len();
+    (tasks as u64) < pcb.rlimits.current(RLIMIT_NPROC)
+}
@@ -0,0 +1,309 @@
+//! Signals
+//!
+//! - Per-process pending and blocked masks and registered actions
+//! - Delivery when a process is about to resume in ring 3, either by taking
+//!   the default action or by building a signal frame on the user stack
+//!
+//! A handler is entered with the signal number in `rdi` and returns to the
+//! `restorer` registered with it, which must call `sigreturn`. Signals sent to
+//! a blocked process are delivered once it is scheduled again.
+
+use alloc::vec;
+use core::mem::size_of;
+
+use crate::{
+    constants::{
+        errno::EFAULT,
+        memory::USER_SPACE_END,
+        signals::{
+            NSIG, SA_NODEFER, SA_RESETHAND, SIGCHLD, SIGCONT, SIGKILL, SIGSEGV, SIGSTOP, SIGTSTP,
+            SIGTTIN, SIGTTOU, SIGURG, SIGWINCH, SIG_DFL, SIG_IGN,
+        },
+    },
+    memory::user_access::{copy_from_user, copy_to_user},
+    processes::{fpu::FpuState, process::PCB, registers::Registers},
+};
+
+/// Bytes below the interrupted stack pointer that a handler must not clobber
+const RED_ZONE_SIZE: u64 = 128;
+
+/// Flags a signal handler may change through `sigreturn`: CF, PF, AF, ZF,
+/// SF, TF, DF and OF
+const USER_RFLAGS: u64 = 0xdd5;
+
+/// Interrupts enabled and the reserved bit set
+const BASE_RFLAGS: u64 = 0x202;
+
+/// Returns the bit of `sig` in a signal mask
+pub const fn sig_bit(sig: u32) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Signals that can be neither caught, ignored nor blocked
+pub const UNBLOCKABLE: u64 = sig_bit(SIGKILL) | sig_bit(SIGSTOP);
+
+/// What happens to a process when a signal is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Ignore,
+    Terminate,
+    Handle(SigAction),
+}
+
+/// A signal action as registered with `sigaction`. The layout matches the
+/// user structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// Saved on the user stack while a handler runs, above its return address.
+/// The thread's FPU save area follows it.
+#[repr(C)]
+struct SignalFrame {
+    registers: Registers,
+    blocked: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    pub pending: u64,
+    pub blocked: u64,
+    actions: [SigAction; NSIG],
+}
+
+impl SignalState {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); NSIG],
+        }
+    }
+
+    /// State for a forked child: same actions and mask, nothing pending
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Resets caught signals to their default action, as the handlers are
+    /// gone with the old program image. Ignored signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+
+    /// Marks a signal as pending
+    pub fn post(&mut self, sig: u32) {
+        self.pending |= sig_bit(sig);
+    }
+
+    /// Marks a signal as pending with its default action and unblocks it,
+    /// for faults the process cannot be allowed to handle or ignore
+    pub fn force(&mut self, sig: u32) {
+        self.actions[sig as usize] = SigAction::default();
+        self.blocked &= !sig_bit(sig);
+        self.post(sig);
+    }
+
+    pub fn action(&self, sig: u32) -> SigAction {
+        self.actions[sig as usize]
+    }
+
+    pub fn set_action(&mut self, sig: u32, action: SigAction) {
+        self.actions[sig as usize] = action;
+    }
+
+    /// Replaces the blocked mask; SIGKILL and SIGSTOP cannot be blocked
+    pub fn set_blocked(&mut self, blocked: u64) {
+        self.blocked = blocked & !UNBLOCKABLE;
+    }
+
+    /// Whether a pending signal is not blocked
+    pub fn deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// Takes the lowest pending signal that is not blocked
+    fn take_next(&mut self) -> Option<u32> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let sig = deliverable.trailing_zeros() + 1;
+        self.pending &= !sig_bit(sig);
+        Some(sig)
+    }
+
+    fn disposition(&self, sig: u32) -> Disposition {
+        let action = self.actions[sig as usize];
+        match action.handler {
+            _ if UNBLOCKABLE & sig_bit(sig) != 0 => default_disposition(sig),
+            SIG_DFL => default_disposition(sig),
+            SIG_IGN => Disposition::Ignore,
+            _ => Disposition::Handle(action),
+        }
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks that `sig` is a signal number, 1 to `NSIG - 1`
+pub fn valid_signal(sig: u64) -> bool {
+    (1..NSIG as u64).contains(&sig)
+}
+
+/// Default action of a signal. Stopping is not supported, so job control
+/// signals are ignored.
+fn default_disposition(sig: u32) -> Disposition {
+    match sig {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+            Disposition::Ignore
+        }
+        _ => Disposition::Terminate,
+    }
+}
+
+/// Encodes the wait status of a process terminated by a signal
+pub fn signal_wait_status(sig: u32) -> i32 {
+    (sig & 0x7f) as i32
+}
+
+/// Delivers pending signals to a thread about to return to ring 3
+///
+/// Ignored signals are discarded. The first caught signal gets a signal
+/// frame on the thread's user stack and its saved registers are pointed at
+/// the handler; the rest stay pending until the handler returns.
+///
+/// * `pcb`: the thread's process
+/// * `registers`: the saved registers of the thread, which must not be running
+/// * `fpu`: the saved FPU state of the thread
+///
+/// # Returns
+/// The signal whose default action terminates the process, if any. The
+/// caller terminates it instead of resuming it.
+pub fn deliver_signals(pcb: &PCB, registers: &mut Registers, fpu: &mut FpuState) -> Option<u32> {
+    loop {
+        let (sig, disposition) = {
+            let mut signals = pcb.signals.lock();
+            let sig = signals.take_next()?;
+            (sig, signals.disposition(sig))
+        };
+        match disposition {
+            Disposition::Ignore => continue,
+            Disposition::Terminate => return Some(sig),
+            Disposition::Handle(action) => {
+                return match enter_handler(pcb, registers, fpu, sig, action) {
+                    Ok(()) => None,
+                    // Nowhere to put the frame
+                    Err(_) => Some(SIGSEGV),
+                };
+            }
+        }
+    }
+}
+
+/// Pushes a signal frame and points the saved registers at the handler,
+/// which starts with a cleared FPU state
+fn enter_handler(
+    pcb: &PCB,
+    registers: &mut Registers,
+    fpu: &mut FpuState,
+    sig: u32,
+    action: SigAction,
+) -> Result<(), i64> {
+    let frame = SignalFrame {
+        registers: *registers,
+        blocked: pcb.signals.lock().blocked,
+    };
+
+    // The handler starts as if called: rsp + 8 is 16-byte aligned
+    let fpu_bytes = fpu.bytes();
+    let frame_addr = registers
+        .rsp
+        .wrapping_sub(RED_ZONE_SIZE + (size_of::<SignalFrame>() + fpu_bytes.len()) as u64)
+        & !0xf;
+    let return_addr = frame_addr.wrapping_sub(8);
+
+    let frame_bytes = unsafe {
+        core::slice::from_raw_parts(
+            &frame as *const SignalFrame as *const u8,
+            size_of::<SignalFrame>(),
+        )
+    };
+    copy_to_user(pcb, frame_addr, frame_bytes)?;
+    copy_to_user(pcb, frame_addr + size_of::<SignalFrame>() as u64, fpu_bytes)?;
+    copy_to_user(pcb, return_addr, &action.restorer.to_le_bytes())?;
+    *fpu = FpuState::new();
+
+    let mut signals = pcb.signals.lock();
+    let mut blocked = signals.blocked | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        blocked |= sig_bit(sig);
+    }
+    signals.set_blocked(blocked);
+    if action.flags & SA_RESETHAND != 0 {
+        signals.set_action(sig, SigAction::default());
+    }
+
+    *registers = Registers {
+        rdi: sig as u64,
+        rsp: return_addr,
+        rip: action.handler,
+        rflags: BASE_RFLAGS,
+        ..Registers::new()
+    };
+    Ok(())
+}
+
+/// Restores the registers, FPU state and blocked mask saved when a handler
+/// was entered
+///
+/// * `pcb`: the process returning from its handler
+/// * `fpu`: the returning thread's FPU save area, overwritten with the saved
+///   state for the caller to load
+/// * `frame_addr`: user address of the signal frame, which is the stack
+///   pointer once the handler has returned to its restorer
+///
+/// # Returns
+/// The registers to resume with, or `Err(EFAULT)` if the frame is unreadable
+/// or would resume outside user space
+pub fn restore_signal_frame(
+    pcb: &PCB,
+    fpu: &mut FpuState,
+    frame_addr: u64,
+) -> Result<Registers, i64> {
+    let mut frame_bytes = [0u8; size_of::<SignalFrame>()];
+    copy_from_user(pcb, &mut frame_bytes, frame_addr)?;
+    let frame: SignalFrame = unsafe { core::ptr::read_unaligned(frame_bytes.as_ptr().cast()) };
+    let mut fpu_bytes = vec![0u8; fpu.bytes().len()];
+    copy_from_user(
+        pcb,
+        &mut fpu_bytes,
+        frame_addr + size_of::<SignalFrame>() as u64,
+    )?;
+
+    let mut registers = frame.registers;
+    if registers.rip >= USER_SPACE_END || registers.rsp >= USER_SPACE_END {
+        return Err(EFAULT);
+    }
+    registers.rflags = (registers.rflags & USER_RFLAGS) | BASE_RFLAGS;
+
+    fpu.load_bytes(&fpu_bytes);
+    pcb.signals.lock().set_blocked(frame.blocked);
+    Ok(registers)
+}
@@ -1,3 +1,7 @@
+section .rodata
+msg:    db "Hello world!", 10
+msg_len equ $ - msg
+
 section .text
     global _start
 
@@ -10,6 +14,8 @@ _loop:
     cmp rbx, 0
     jg _loop
 
+    lea rdi, [rel msg]
+    mov rsi, msg_len
     int 0x80
     mov rax, 60
     int 0x80
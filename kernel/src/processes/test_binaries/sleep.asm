@@ -1,8 +1,14 @@
+section .rodata
+msg:    db "Hello world!", 10
+msg_len equ $ - msg
+
 section .text
     global _start
 
 _start:
     mov rax, 3
+    lea rdi, [rel msg]
+    mov rsi, msg_len
     int 0x80
 
     mov rdi, 5000000000
@@ -0,0 +1,278 @@
+//! Threads
+//!
+//! - A thread control block (TCB) per thread, holding what the scheduler saves
+//!   and restores; everything else is shared through the process' PCB
+//! - Creating threads with their own user stacks, exiting and joining them
+//!
+//! The first thread of a process has the process' PID as its TID. New threads
+//! are spread over the cores, so threads of one process run in parallel.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{
+    constants::{
+        errno::{EAGAIN, ENOMEM, ESRCH},
+        processes::THREAD_STACK_SIZE,
+    },
+    events::{current_running_event_info, next_core, schedule_thread_on, WaitQueue},
+    memory::{
+        slab::SlabCache,
+        vma::{unmap_range, Vma},
+    },
+    processes::{
+        fpu::FpuState,
+        process::{
+            next_pid, release_stopped_process, ProcessMap, ProcessState, PCB, PROCESS_TABLE,
+        },
+        registers::Registers,
+        rlimit::may_add_task,
+    },
+};
+
+#[derive(Debug)]
+pub struct TCB {
+    pub tid: u32,
+    pub pid: u32,
+    pub state: ProcessState,
+    pub kernel_rsp: u64,
+    pub kernel_rip: u64,
+    /// When the thread's timeslice ends, on the TSC clock in nanoseconds
+    pub next_preemption_time: u64,
+    pub registers: Registers,
+    /// x87, SSE and AVX registers, saved whenever `registers` are
+    pub fpu: FpuState,
+    /// Stack allocated by `create_thread`, unmapped once the thread is joined
+    pub user_stack: Option<(u64, u64)>,
+    /// Value passed to `thread_exit`, returned by `thread_join`
+    pub exit_value: u64,
+    /// Set when the thread must not return to user mode again
+    pub exit_requested: AtomicBool,
+    /// Woken when the thread stops
+    pub stopped: WaitQueue,
+    /// Runs the thread again while it is parked on a wait queue
+    pub parked: Mutex<Option<Waker>>,
+    /// Time the thread has run, scaled by its process' nice weight; orders
+    /// threads under the fair scheduling policy
+    pub vruntime: AtomicU64,
+}
+
+impl TCB {
+    pub fn new(tid: u32, pid: u32, registers: Registers) -> Self {
+        Self {
+            tid,
+            pid,
+            state: ProcessState::New,
+            kernel_rsp: 0,
+            kernel_rip: 0,
+            next_preemption_time: 0,
+            registers,
+            fpu: FpuState::new(),
+            user_stack: None,
+            exit_value: 0,
+            exit_requested: AtomicBool::new(false),
+            stopped: WaitQueue::new(),
+            parked: Mutex::new(None),
+            vruntime: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Slab cache for `Arc<UnsafeTCB>`
+pub(crate) static TCB_CACHE: SlabCache = SlabCache::for_arc::<UnsafeTCB>("tcb");
+
+#[derive(Debug)]
+pub struct UnsafeTCB {
+    pub tcb: UnsafeCell<TCB>,
+}
+impl UnsafeTCB {
+    pub fn init(tcb: TCB) -> Self {
+        UnsafeTCB {
+            tcb: UnsafeCell::new(tcb),
+        }
+    }
+}
+unsafe impl Sync for UnsafeTCB {}
+pub type ThreadMap = BTreeMap<u32, Arc<UnsafeTCB>>;
+
+/// Looks up a thread of a process
+pub fn get_thread(pid: u32, tid: u32) -> Option<Arc<UnsafeTCB>> {
+    let process = PROCESS_TABLE.read().get(&pid).cloned()?;
+    let pcb = unsafe { &*process.pcb.get() };
+    let thread = pcb.threads.lock().get(&tid).cloned();
+    thread
+}
+
+/// Nice value of a thread's process and the thread's virtual runtime, to
+/// schedule it with
+pub fn sched_state(pid: u32, tid: u32) -> (i32, u64) {
+    let Some(process) = PROCESS_TABLE.read().get(&pid).cloned() else {
+        return (0, 0);
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let vruntime = pcb.threads.lock().get(&tid).map_or(0, |thread| {
+        let tcb = unsafe { &*thread.tcb.get() };
+        tcb.vruntime.load(Ordering::Relaxed)
+    });
+    (pcb.nice, vruntime)
+}
+
+/// Looks up the thread running on the current core
+///
+/// # Returns
+/// The TCB of the running thread, or None if a kernel event is running
+pub fn current_thread() -> Option<Arc<UnsafeTCB>> {
+    let event = current_running_event_info();
+    if event.pid == 0 {
+        return None;
+    }
+    get_thread(event.pid, event.tid)
+}
+
+/// Starts a new thread in a process, on the next core in turn
+///
+/// The thread is entered as if called, with its argument in `rdi`. It must
+/// end with `thread_exit`, as returning jumps to address 0.
+///
+/// * `pcb`: the process to add the thread to
+/// * `entry`: user address the thread starts at
+/// * `stack_top`: top of the thread's user stack, or 0 to allocate one of
+///   `THREAD_STACK_SIZE` bytes
+/// * `arg`: value passed to the thread
+///
+/// Returns the TID, `EAGAIN` if the process has reached its `RLIMIT_NPROC`
+/// limit or `ENOMEM` if no stack could be allocated
+pub fn create_thread(pcb: &PCB, entry: u64, stack_top: u64, arg: u64) -> Result<u32, i64> {
+    if !may_add_task(pcb) {
+        return Err(EAGAIN);
+    }
+    let user_stack = if stack_top == 0 {
+        let mut vmas = pcb.lock_vmas();
+        let start = vmas.find_free(THREAD_STACK_SIZE, 0).ok_or(ENOMEM)?;
+        let end = start + THREAD_STACK_SIZE;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE;
+        if !vmas.insert(Vma::anonymous(start, end, flags)) {
+            return Err(ENOMEM);
+        }
+        Some((start, end))
+    } else {
+        None
+    };
+    let top = user_stack.map_or(stack_top, |(_, end)| end);
+
+    let tid = next_pid();
+    let mut thread = TCB::new(
+        tid,
+        pcb.pid,
+        Registers {
+            rdi: arg,
+            // As after a call: rsp + 8 is 16-byte aligned
+            rsp: (top & !0xf) - 8,
+            rip: entry,
+            rflags: 0x202,
+            ..Registers::new()
+        },
+    );
+    thread.state = ProcessState::Ready;
+    thread.user_stack = user_stack;
+    pcb.threads
+        .lock()
+        .insert(tid, Arc::new(UnsafeTCB::init(thread)));
+
+    schedule_thread_on(next_core(), pcb.pid, tid);
+    Ok(tid)
+}
+
+/// Ends a thread that will not return to user mode again
+///
+/// The thread stays in its process until it is joined. If the process is
+/// exiting and this was its last running thread, the process' memory is
+/// released.
+///
+/// * `process_table`: the locked process table
+/// * `pid`: the thread's process
+/// * `thread`: the thread, which must not be running on another core
+pub fn stop_thread(process_table: &mut ProcessMap, pid: u32, thread: &mut TCB) {
+    thread.state = ProcessState::Terminated;
+    thread.stopped.wake_all();
+    release_stopped_process(process_table, pid);
+}
+
+/// Asks every thread of a process but `keep_tid` to stop before it next
+/// returns to user mode
+pub fn request_threads_exit(pcb: &PCB, keep_tid: u32) {
+    for (&tid, thread) in pcb.threads.lock().iter() {
+        if tid != keep_tid {
+            let tcb = unsafe { &*thread.tcb.get() };
+            tcb.exit_requested.store(true, Ordering::Release);
+            unpark_thread(tcb);
+        }
+    }
+}
+
+/// Runs a thread parked on a wait queue again, so it sees an exit request
+/// or a signal. Its syscall restarts, parking it again if still waiting.
+pub fn unpark_thread(thread: &TCB) {
+    let waker = thread.parked.lock().take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Whether a thread of the process other than `tid` is running on a core
+pub fn other_threads_running(pcb: &PCB, tid: u32) -> bool {
+    pcb.threads.lock().iter().any(|(&other, thread)| {
+        other != tid && unsafe { (*thread.tcb.get()).state } == ProcessState::Running
+    })
+}
+
+/// Waits until a thread stops, then removes it from its process and frees
+/// the stack `create_thread` allocated for it
+///
+/// # Returns
+/// The thread's exit value, or `ESRCH` if there is no such thread
+pub struct JoinThread {
+    pub pid: u32,
+    pub tid: u32,
+}
+
+impl Future for JoinThread {
+    type Output = Result<u64, i64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(process) = PROCESS_TABLE.read().get(&self.pid).cloned() else {
+            return Poll::Ready(Err(ESRCH));
+        };
+        let pcb = unsafe { &*process.pcb.get() };
+        let Some(thread) = pcb.threads.lock().get(&self.tid).cloned() else {
+            return Poll::Ready(Err(ESRCH));
+        };
+        let tcb = unsafe { &*thread.tcb.get() };
+
+        // Register before checking so a stop in between is not missed
+        tcb.stopped.register(cx.waker());
+        if tcb.state != ProcessState::Terminated {
+            return Poll::Pending;
+        }
+
+        pcb.threads.lock().remove(&self.tid);
+        if let (Some((start, end)), ProcessState::Ready | ProcessState::New) =
+            (tcb.user_stack, pcb.state)
+        {
+            // Stacks are too small for huge pages, so nothing needs a split
+            unmap_range(pcb, &mut pcb.lock_vmas(), start, end);
+        }
+        Poll::Ready(Ok(tcb.exit_value))
+    }
+}
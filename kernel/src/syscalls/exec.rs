@@ -0,0 +1,114 @@
+//! Program execution system calls
+//!
+//! `execve` reads an executable through the calling process' namespace and
+//! replaces the process image with it. Arguments and environment are copied
+//! in before the process blocks, since the old image is gone once the new one
+//! is loaded.
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    constants::{
+        errno::{E2BIG, EFAULT, ENOEXEC, ESRCH},
+        processes::{MAX_EXEC_SIZE, STACK_SIZE},
+        syscalls::ARG_MAX,
+    },
+    events::{current_running_event_info, yield_now},
+    memory::user_access::{copy_from_user, strncpy_from_user},
+    processes::{
+        loader::{auxiliary_vector, initial_stack_size, is_loadable_elf},
+        process::{current_process, exec_process, PCB},
+        thread::{other_threads_running, request_threads_exit},
+    },
+    syscalls::{
+        block_on,
+        file::{copy_path, read_file},
+    },
+};
+
+/// Copies a NULL-terminated user array of string pointers, charging each
+/// string, its NUL and its pointer against `budget`
+fn copy_string_array(pcb: &PCB, array: u64, budget: &mut usize) -> Result<Vec<Vec<u8>>, i64> {
+    let mut strings = Vec::new();
+    if array == 0 {
+        return Ok(strings);
+    }
+
+    loop {
+        let slot = array.checked_add(8 * strings.len() as u64).ok_or(EFAULT)?;
+        let mut ptr = [0u8; 8];
+        copy_from_user(pcb, &mut ptr, slot)?;
+        let ptr = u64::from_le_bytes(ptr);
+        if ptr == 0 {
+            return Ok(strings);
+        }
+
+        let mut string = vec![0u8; *budget];
+        let len = strncpy_from_user(pcb, &mut string, ptr)?;
+        let cost = len + 1 + 8;
+        if cost > *budget {
+            return Err(E2BIG);
+        }
+        *budget -= cost;
+        string.truncate(len);
+        strings.push(string);
+    }
+}
+
+/// Replaces the calling process with a program from its namespace
+///
+/// * `path`: user address of the NUL-terminated path of the executable
+/// * `argv`: user address of a NULL-terminated array of argument strings
+/// * `envp`: user address of a NULL-terminated array of environment strings
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Does not return on success. Open files and mounts are kept, and the
+/// process' other threads are stopped. Executables larger than
+/// `MAX_EXEC_SIZE` fail with `E2BIG`.
+pub fn sys_execve(path: u64, argv: u64, envp: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let tid = current_running_event_info().tid;
+
+    let path = match copy_path(pcb, path) {
+        Ok(path) => path,
+        Err(errno) => return -errno,
+    };
+    let mut budget = ARG_MAX;
+    let args = match copy_string_array(pcb, argv, &mut budget) {
+        Ok(args) => args,
+        Err(errno) => return -errno,
+    };
+    let env = match copy_string_array(pcb, envp, &mut budget) {
+        Ok(env) => env,
+        Err(errno) => return -errno,
+    };
+
+    block_on(rsp, async move {
+        let elf_bytes = {
+            let pcb: &PCB = unsafe { &*process.pcb.get() };
+            match read_file(&pcb.namespace, &path, MAX_EXEC_SIZE).await {
+                Ok(elf_bytes) => elf_bytes,
+                Err(errno) => return -errno,
+            }
+        };
+        if !is_loadable_elf(&elf_bytes) {
+            return -ENOEXEC;
+        }
+        if initial_stack_size(&args, &env, &auxiliary_vector(&elf_bytes)) > STACK_SIZE {
+            return -E2BIG;
+        }
+
+        let pcb = unsafe { &*process.pcb.get() };
+        // The old image must not run on other cores while it is replaced
+        request_threads_exit(pcb, tid);
+        while other_threads_running(pcb, tid) {
+            yield_now().await;
+        }
+        exec_process(pcb, tid, &elf_bytes, &args, &env);
+        // Lands in the new image's cleared rax
+        0
+    })
+}
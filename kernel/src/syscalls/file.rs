@@ -0,0 +1,450 @@
+//! File system calls
+//!
+//! Paths are resolved through the calling process' namespace and file
+//! operations are forwarded to the owning mount over 9P. Requests to a mount
+//! block the process until the reply arrives. Descriptors 0, 1 and 2 refer to
+//! the serial console. Pipes are handled in `syscalls::pipe`.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bytes::Bytes;
+use core::sync::atomic::Ordering;
+
+use crate::{
+    constants::{
+        errno::{E2BIG, EBADF, EFAULT, EINVAL, EIO, EMFILE, ENAMETOOLONG, ENOENT, ESPIPE, ESRCH},
+        syscalls::{PATH_MAX, RLIMIT_NOFILE, SEEK_CUR, SEEK_END, SEEK_SET},
+    },
+    ipc::{
+        error::Error,
+        fd_table::{clunk, FileKind, OpenFlags},
+        messages::{Message, MAX_MESSAGE_SIZE},
+        mnt_manager,
+        mount_manager::MountId,
+        namespace::Namespace,
+        requests::{Topen, Tread, Twrite},
+    },
+    memory::user_access::{copy_from_user, copy_to_user, strncpy_from_user},
+    processes::process::{current_process, PCB},
+    serial,
+    syscalls::{
+        block_on,
+        pipe::{read_pipe, write_pipe},
+        syscall_handlers::sys_print,
+    },
+};
+
+/// Room reserved for 9P headers in a message carrying file data (IOHDRSZ)
+const IO_HEADER_SIZE: u32 = 24;
+
+/// Most bytes moved by a single read or write to a mount
+const MAX_IO_SIZE: u64 = (MAX_MESSAGE_SIZE - IO_HEADER_SIZE) as u64;
+
+/// 9P open modes
+const OREAD: u8 = 0;
+const OWRITE: u8 = 1;
+const ORDWR: u8 = 2;
+const OTRUNC: u8 = 0x10;
+
+/// Snapshot of a file descriptor taken before blocking
+struct OpenFile {
+    kind: FileKind,
+    mount_id: usize,
+    fid: u32,
+    flags: OpenFlags,
+    offset: u64,
+}
+
+fn lookup(pcb: &PCB, fd: u64) -> Result<OpenFile, i64> {
+    let desc = pcb.fd_table.lock().get(fd as usize).ok_or(EBADF)?;
+    Ok(OpenFile {
+        kind: desc.kind,
+        mount_id: desc.mount_id,
+        fid: desc.fid,
+        flags: desc.flags.clone(),
+        offset: desc.offset.load(Ordering::Relaxed),
+    })
+}
+
+fn ipc_errno(error: Error) -> i64 {
+    match error {
+        Error::BadFileDescriptor => EBADF,
+        Error::InvalidPath | Error::TooManyComponents => EINVAL,
+        Error::NotFound | Error::NoMount | Error::NotMount => ENOENT,
+        Error::TooManyOpenFiles => EMFILE,
+        _ => EIO,
+    }
+}
+
+fn open_mode(flags: &OpenFlags) -> u8 {
+    let mut mode = if flags.contains(OpenFlags::RDWR) {
+        ORDWR
+    } else if flags.contains(OpenFlags::WRITE) {
+        OWRITE
+    } else {
+        OREAD
+    };
+    if flags.contains(OpenFlags::TRUNC) {
+        mode |= OTRUNC;
+    }
+    mode
+}
+
+/// Copies a NUL-terminated path of at most `PATH_MAX` bytes from user memory
+pub(super) fn copy_path(pcb: &PCB, path: u64) -> Result<String, i64> {
+    let mut buf = vec![0u8; PATH_MAX];
+    match strncpy_from_user(pcb, &mut buf, path)? {
+        len if len < PATH_MAX => {
+            buf.truncate(len);
+            String::from_utf8(buf).map_err(|_| EINVAL)
+        }
+        _ => Err(ENAMETOOLONG),
+    }
+}
+
+/// Reads a whole file from a namespace, e.g. an executable for `execve`
+///
+/// * `max_size`: most bytes the file may hold; a larger one fails with
+///   `E2BIG` once that much has been read
+///
+/// # Returns
+/// The file contents, or a positive errno
+pub(super) async fn read_file(
+    namespace: &Namespace,
+    path: &str,
+    max_size: usize,
+) -> Result<Vec<u8>, i64> {
+    let resolution = namespace.walk_path(path).await.map_err(ipc_errno)?;
+    let mount = MountId(resolution.mount_id as u32);
+    let fid = resolution.fid;
+
+    let result = match Topen::new(0, fid, OREAD) {
+        Ok(topen) => read_opened(mount, fid, topen, max_size).await,
+        Err(_) => Err(EINVAL),
+    };
+    let _ = clunk(resolution.mount_id, fid).await;
+    result
+}
+
+/// Opens a walked fid for reading and reads all of it, up to `max_size` bytes
+async fn read_opened(
+    mount: MountId,
+    fid: u32,
+    topen: Topen,
+    max_size: usize,
+) -> Result<Vec<u8>, i64> {
+    match mnt_manager
+        .send_request(mount, fid, Message::Topen(topen))
+        .await
+    {
+        Ok(Message::Ropen(_)) => {}
+        Ok(_) => return Err(EIO),
+        Err(e) => return Err(ipc_errno(e)),
+    }
+
+    let mut contents = Vec::new();
+    loop {
+        if contents.len() > max_size {
+            break Err(E2BIG);
+        }
+        // One byte past the limit tells a file that is too large
+        let count = (max_size + 1 - contents.len()).min(MAX_IO_SIZE as usize);
+        let Ok(tread) = Tread::new(0, fid, contents.len() as u64, count as u32) else {
+            break Err(EINVAL);
+        };
+        match mnt_manager
+            .send_request(mount, fid, Message::Tread(tread))
+            .await
+        {
+            Ok(Message::Rread(rread)) if rread.data.is_empty() => break Ok(contents),
+            Ok(Message::Rread(rread)) => contents.extend_from_slice(&rread.data),
+            Ok(_) => break Err(EIO),
+            Err(e) => break Err(ipc_errno(e)),
+        }
+    }
+}
+
+/// Opens a file in the calling process' namespace
+///
+/// * `path`: user address of a NUL-terminated path
+/// * `flags`: `OpenFlags` bits; at least one of READ and WRITE is required
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Returns the new file descriptor
+pub fn sys_open(path: u64, flags: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let flags = match OpenFlags::from_bits(flags as u32) {
+        Some(flags) if flags.intersects(OpenFlags::RDWR) => flags,
+        _ => return -EINVAL,
+    };
+
+    if pcb.fd_table.lock().open_count() as u64 >= pcb.rlimits.current(RLIMIT_NOFILE) {
+        return -EMFILE;
+    }
+    let path = match copy_path(pcb, path) {
+        Ok(path) => path,
+        Err(errno) => return -errno,
+    };
+
+    block_on(rsp, async move {
+        let resolution = {
+            let pcb: &PCB = unsafe { &*process.pcb.get() };
+            match pcb.namespace.walk_path(&path).await {
+                Ok(resolution) => resolution,
+                Err(e) => return -ipc_errno(e),
+            }
+        };
+
+        let Ok(topen) = Topen::new(0, resolution.fid, open_mode(&flags)) else {
+            let _ = clunk(resolution.mount_id, resolution.fid).await;
+            return -EINVAL;
+        };
+        let response = mnt_manager
+            .send_request(
+                MountId(resolution.mount_id as u32),
+                resolution.fid,
+                Message::Topen(topen),
+            )
+            .await;
+
+        match response {
+            Ok(Message::Ropen(_)) => {
+                let pcb = unsafe { &*process.pcb.get() };
+                let limit = pcb.rlimits.current(RLIMIT_NOFILE);
+                let allocated =
+                    pcb.fd_table
+                        .lock()
+                        .allocate(resolution.mount_id, resolution.fid, flags, limit);
+                match allocated {
+                    Ok(fd) => fd as i64,
+                    Err(e) => {
+                        // Another thread took the last descriptor meanwhile
+                        let _ = clunk(resolution.mount_id, resolution.fid).await;
+                        -ipc_errno(e)
+                    }
+                }
+            }
+            // The walked fid is of no use unopened
+            response => {
+                let _ = clunk(resolution.mount_id, resolution.fid).await;
+                match response {
+                    Err(e) => -ipc_errno(e),
+                    _ => -EIO,
+                }
+            }
+        }
+    })
+}
+
+/// Reads from a file descriptor into a user buffer
+///
+/// * `fd`: the file descriptor
+/// * `buf`: user address to read into
+/// * `count`: most bytes to read; mounts are read at most `MAX_IO_SIZE` at a time
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Returns the number of bytes read, 0 at end of file
+pub fn sys_read(fd: u64, buf: u64, count: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let file = match lookup(pcb, fd) {
+        Ok(file) if file.flags.contains(OpenFlags::READ) => file,
+        Ok(_) => return -EBADF,
+        Err(errno) => return -errno,
+    };
+
+    match file.kind {
+        FileKind::Console => {
+            let mut data = vec![0u8; count.min(MAX_IO_SIZE) as usize];
+            let read = serial::read_bytes(&mut data);
+            match copy_to_user(pcb, buf, &data[..read]) {
+                Ok(()) => read as i64,
+                Err(errno) => -errno,
+            }
+        }
+        FileKind::Pipe => {
+            // The thread may wait without returning here
+            drop(process);
+            read_pipe(pcb, fd, buf, count, rsp)
+        }
+        FileKind::Mount => {
+            let count = count.min(MAX_IO_SIZE) as u32;
+            let Ok(tread) = Tread::new(0, file.fid, file.offset, count) else {
+                return -EINVAL;
+            };
+
+            block_on(rsp, async move {
+                let response = mnt_manager
+                    .send_request(
+                        MountId(file.mount_id as u32),
+                        file.fid,
+                        Message::Tread(tread),
+                    )
+                    .await;
+
+                match response {
+                    Ok(Message::Rread(rread)) => {
+                        let pcb = unsafe { &*process.pcb.get() };
+                        if let Err(errno) = copy_to_user(pcb, buf, &rread.data) {
+                            return -errno;
+                        }
+                        let read = rread.data.len() as u64;
+                        let _ = pcb
+                            .fd_table
+                            .lock()
+                            .update_offset(fd as usize, file.offset + read);
+                        read as i64
+                    }
+                    Ok(_) => -EIO,
+                    Err(e) => -ipc_errno(e),
+                }
+            })
+        }
+    }
+}
+
+/// Writes a user buffer to a file descriptor
+///
+/// * `fd`: the file descriptor
+/// * `buf`: user address of the bytes to write
+/// * `count`: number of bytes; mounts are written at most `MAX_IO_SIZE` at a time
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Returns the number of bytes written
+pub fn sys_write(fd: u64, buf: u64, count: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let file = match lookup(pcb, fd) {
+        Ok(file) if file.flags.contains(OpenFlags::WRITE) => file,
+        Ok(_) => return -EBADF,
+        Err(errno) => return -errno,
+    };
+
+    match file.kind {
+        FileKind::Console => sys_print(buf, count),
+        FileKind::Pipe => {
+            // The thread may wait without returning here
+            drop(process);
+            write_pipe(pcb, fd, buf, count, rsp)
+        }
+        FileKind::Mount => {
+            let mut data = vec![0u8; count.min(MAX_IO_SIZE) as usize];
+            if let Err(errno) = copy_from_user(pcb, &mut data, buf) {
+                return -errno;
+            }
+            let Ok(twrite) = Twrite::new(0, file.fid, file.offset, Bytes::from(data)) else {
+                return -EFAULT;
+            };
+
+            block_on(rsp, async move {
+                let response = mnt_manager
+                    .send_request(
+                        MountId(file.mount_id as u32),
+                        file.fid,
+                        Message::Twrite(twrite),
+                    )
+                    .await;
+
+                match response {
+                    Ok(Message::Rwrite(rwrite)) => {
+                        let pcb = unsafe { &*process.pcb.get() };
+                        let written = rwrite.count as u64;
+                        let _ = pcb
+                            .fd_table
+                            .lock()
+                            .update_offset(fd as usize, file.offset + written);
+                        written as i64
+                    }
+                    Ok(_) => -EIO,
+                    Err(e) => -ipc_errno(e),
+                }
+            })
+        }
+    }
+}
+
+/// Closes a file descriptor, clunking its fid on the mount
+///
+/// * `fd`: the file descriptor
+/// * `rsp`: the saved register frame, used to block the caller
+pub fn sys_close(fd: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let Some(desc) = pcb.fd_table.lock().remove(fd as usize) else {
+        return -EBADF;
+    };
+
+    match desc.kind {
+        // A pipe end closes once no descriptor holds it
+        FileKind::Console | FileKind::Pipe => 0,
+        FileKind::Mount => {
+            // Another process forked from this one still has the file open
+            let Some(desc) = Arc::into_inner(desc) else {
+                return 0;
+            };
+
+            block_on(rsp, async move {
+                match clunk(desc.mount_id, desc.fid).await {
+                    Ok(()) => 0,
+                    Err(e) => -ipc_errno(e),
+                }
+            })
+        }
+    }
+}
+
+/// Moves the offset of a file descriptor
+///
+/// * `fd`: the file descriptor
+/// * `offset`: the offset, relative to `whence`
+/// * `whence`: `SEEK_SET` or `SEEK_CUR`. `SEEK_END` is rejected until file
+///   sizes are available from the mount.
+///
+/// Returns the new offset
+pub fn sys_lseek(fd: u64, offset: u64, whence: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let file = match lookup(pcb, fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    if matches!(file.kind, FileKind::Console | FileKind::Pipe) {
+        return -ESPIPE;
+    }
+
+    let new_offset = match whence {
+        SEEK_SET => Some(offset as i64),
+        SEEK_CUR => (file.offset as i64).checked_add(offset as i64),
+        SEEK_END => None,
+        _ => None,
+    };
+
+    match new_offset {
+        Some(new_offset) if new_offset >= 0 => {
+            match pcb
+                .fd_table
+                .lock()
+                .update_offset(fd as usize, new_offset as u64)
+            {
+                Ok(()) => new_offset,
+                Err(e) => -ipc_errno(e),
+            }
+        }
+        _ => -EINVAL,
+    }
+}
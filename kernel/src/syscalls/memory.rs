@@ -0,0 +1,211 @@
+//! Memory management system calls
+//!
+//! `brk`/`sbrk` move the end of the heap, and `mmap`, `munmap` and `mprotect`
+//! manage anonymous mappings. They change the process' memory areas and any
+//! pages already mapped; new pages are only mapped on first access.
+
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{
+    constants::{
+        errno::{EINVAL, ENODEV, ENOMEM, ESRCH},
+        memory::{PAGE_SIZE, USER_SPACE_END},
+        syscalls::{
+            MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE,
+        },
+    },
+    memory::vma::{page_align_down, page_align_up, protect_range, unmap_range, Vma, VmaList},
+    processes::process::{current_process, PCB},
+};
+
+/// Converts `mmap`/`mprotect` protection bits to page flags
+///
+/// x86_64 pages cannot be write- or execute-only, so any access makes the
+/// pages readable. Inaccessible pages stay present so their contents survive.
+fn prot_flags(prot: u64) -> Option<PageTableFlags> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
+    }
+
+    let mut flags = PageTableFlags::PRESENT;
+    if prot != 0 {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    Some(flags)
+}
+
+/// Checks a user range for `munmap`/`mprotect`, returning its page-aligned end
+fn page_range_end(addr: u64, len: u64) -> Result<u64, i64> {
+    if addr % PAGE_SIZE as u64 != 0 || len == 0 {
+        return Err(EINVAL);
+    }
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => Ok(page_align_up(end)),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Moves the program break, mapping or unmapping heap pages as needed
+fn set_brk(pcb: &PCB, vmas: &mut VmaList, new_brk: u64) -> Result<(), i64> {
+    if new_brk < vmas.brk_start || new_brk > USER_SPACE_END {
+        return Err(ENOMEM);
+    }
+
+    let old_end = page_align_up(vmas.brk);
+    let new_end = page_align_up(new_brk);
+    if new_end > old_end && !vmas.grow_heap(old_end, new_end) {
+        return Err(ENOMEM);
+    }
+    if new_end < old_end && !unmap_range(pcb, vmas, new_end, old_end) {
+        return Err(ENOMEM);
+    }
+    vmas.brk = new_brk;
+    Ok(())
+}
+
+/// Sets the end of the heap
+///
+/// * `addr`: the new program break, or 0 to only query it
+///
+/// Returns the program break, which is unchanged if it could not be moved
+pub fn sys_brk(addr: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let mut vmas = pcb.lock_vmas();
+
+    if addr != 0 {
+        // Failure is reported by returning the old break
+        let _ = set_brk(pcb, &mut vmas, addr);
+    }
+    vmas.brk as i64
+}
+
+/// Grows or shrinks the heap
+///
+/// * `increment`: signed number of bytes to move the program break by
+///
+/// Returns the previous program break
+pub fn sys_sbrk(increment: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let mut vmas = pcb.lock_vmas();
+
+    let old_brk = vmas.brk;
+    let Some(new_brk) = old_brk.checked_add_signed(increment as i64) else {
+        return -ENOMEM;
+    };
+    match set_brk(pcb, &mut vmas, new_brk) {
+        Ok(()) => old_brk as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// Creates an anonymous private mapping
+///
+/// * `addr`: where to place the mapping; a hint unless `MAP_FIXED` is set
+/// * `len`: length in bytes, rounded up to whole pages
+/// * `prot`: `PROT_*` bits for the pages
+/// * `flags`: `MAP_*` flags; must include `MAP_PRIVATE` and `MAP_ANONYMOUS`
+///
+/// The file descriptor and offset arguments are ignored, as file mappings are
+/// not supported. Returns the start of the mapping.
+pub fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let mut vmas = pcb.lock_vmas();
+
+    let Some(page_flags) = prot_flags(prot) else {
+        return -EINVAL;
+    };
+    if flags & MAP_ANONYMOUS == 0 {
+        return -ENODEV;
+    }
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || len == 0 {
+        return -EINVAL;
+    }
+    let Some(len) = len.checked_add(PAGE_SIZE as u64 - 1).map(page_align_down) else {
+        return -ENOMEM;
+    };
+
+    let start = if flags & MAP_FIXED != 0 {
+        let end = match page_range_end(addr, len) {
+            Ok(end) => end,
+            Err(errno) => return -errno,
+        };
+        if !unmap_range(pcb, &mut vmas, addr, end) {
+            return -ENOMEM;
+        }
+        addr
+    } else {
+        match vmas.find_free(len, addr) {
+            Some(start) => start,
+            None => return -ENOMEM,
+        }
+    };
+
+    if !vmas.insert(Vma::anonymous(start, start + len, page_flags)) {
+        return -ENOMEM;
+    }
+    start as i64
+}
+
+/// Removes mappings, freeing their pages
+///
+/// * `addr`: page-aligned start of the range
+/// * `len`: length in bytes, rounded up to whole pages
+///
+/// Parts of the range that are not mapped are skipped. Fails with `ENOMEM` if
+/// a huge page only partly in the range cannot be split.
+pub fn sys_munmap(addr: u64, len: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let mut vmas = pcb.lock_vmas();
+
+    match page_range_end(addr, len) {
+        Ok(end) if unmap_range(pcb, &mut vmas, addr, end) => 0,
+        Ok(_) => -ENOMEM,
+        Err(errno) => -errno,
+    }
+}
+
+/// Changes the protection of mapped memory
+///
+/// * `addr`: page-aligned start of the range
+/// * `len`: length in bytes, rounded up to whole pages
+/// * `prot`: the new `PROT_*` bits
+///
+/// Fails with `ENOMEM` if part of the range is not mapped, or a huge page only
+/// partly in it cannot be split.
+pub fn sys_mprotect(addr: u64, len: u64, prot: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let mut vmas = pcb.lock_vmas();
+
+    let Some(page_flags) = prot_flags(prot) else {
+        return -EINVAL;
+    };
+    let end = match page_range_end(addr, len) {
+        Ok(end) => end,
+        Err(errno) => return -errno,
+    };
+    if !protect_range(pcb, &mut vmas, addr, end, page_flags) {
+        return -ENOMEM;
+    }
+    0
+}
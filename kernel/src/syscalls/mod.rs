@@ -1 +1,380 @@
+//! System call interface
+//!
+//! - Dispatches system calls through a table indexed by syscall number
+//! - Writes return values back into the saved `rax` slot of the caller
+//! - Supports both `int 0x80` and the `syscall`/`sysret` fast path
+//!
+//! Arguments follow the x86_64 System V syscall convention: the number is in
+//! `rax` and arguments are in `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`.
+//! Handlers return a non-negative value on success or a negated errno.
+
+pub mod exec;
+pub mod file;
+pub mod memory;
+pub mod pipe;
+pub mod process_info;
+pub mod rlimit;
+pub mod sched;
+pub mod signal;
 pub mod syscall_handlers;
+pub mod thread;
+pub mod time;
+
+use core::{arch::naked_asm, future::Future};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    constants::{
+        errno::{ENOSYS, ESRCH},
+        syscalls::{
+            MAX_SYSCALLS, SYSCALL_BRK, SYSCALL_CLOCK_GETTIME, SYSCALL_CLOSE, SYSCALL_EXECVE,
+            SYSCALL_EXIT, SYSCALL_FORK, SYSCALL_GETPID, SYSCALL_GETPPID, SYSCALL_GETPRIORITY,
+            SYSCALL_GETRLIMIT, SYSCALL_IO_PRIORITY, SYSCALL_KILL, SYSCALL_LSEEK, SYSCALL_MMAP,
+            SYSCALL_MPROTECT, SYSCALL_MUNMAP, SYSCALL_NANOSLEEP, SYSCALL_OPEN, SYSCALL_PIPE,
+            SYSCALL_PRINT, SYSCALL_PROCINFO, SYSCALL_READ, SYSCALL_SBRK, SYSCALL_SETPRIORITY,
+            SYSCALL_SETRLIMIT, SYSCALL_SIGACTION, SYSCALL_SIGPROCMASK, SYSCALL_SIGRETURN,
+            SYSCALL_THREAD_CREATE, SYSCALL_THREAD_EXIT, SYSCALL_THREAD_JOIN, SYSCALL_TIMES,
+            SYSCALL_WAIT, SYSCALL_WAITPID, SYSCALL_WRITE,
+        },
+        MAX_CORES,
+    },
+    events::{
+        core_mask, current_running_event_info, schedule_kernel_with_affinity, schedule_thread,
+    },
+    interrupts::{gdt, x2apic},
+    processes::{process::block_process, thread::get_thread},
+};
+use exec::sys_execve;
+use file::{sys_close, sys_lseek, sys_open, sys_read, sys_write};
+use memory::{sys_brk, sys_mmap, sys_mprotect, sys_munmap, sys_sbrk};
+use pipe::sys_pipe;
+use process_info::{sys_getpid, sys_getppid, sys_procinfo, sys_times};
+use rlimit::{sys_getrlimit, sys_setrlimit};
+use sched::{sys_getpriority, sys_setpriority};
+use signal::{sys_kill, sys_sigaction, sys_sigprocmask, sys_sigreturn};
+use syscall_handlers::{sys_exit, sys_fork, sys_nanosleep, sys_print, sys_wait, sys_waitpid};
+use thread::{sys_thread_create, sys_thread_exit, sys_thread_join};
+use time::sys_clock_gettime;
+
+/// Arguments of a system call as saved on the kernel stack.
+///
+/// * `rsp`: Kernel stack pointer to the saved register frame, needed by
+///   syscalls that block or switch away from the calling process
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallArgs {
+    pub p1: u64,
+    pub p2: u64,
+    pub p3: u64,
+    pub p4: u64,
+    pub p5: u64,
+    pub p6: u64,
+    pub rsp: u64,
+}
+
+impl SyscallArgs {
+    /// Reads the syscall number and arguments from a saved register frame.
+    ///
+    /// # Arguments
+    /// * `rsp` - pointer to the frame pushed by a syscall entry point
+    ///
+    /// # Safety
+    /// `rsp` must point to a register frame laid out by `naked_syscall_handler`
+    /// or `syscall_entry`
+    pub unsafe fn from_frame(rsp: u64) -> (u64, Self) {
+        let stack_ptr: *const u64 = rsp as *const u64;
+        let syscall_num = *stack_ptr.add(0);
+        let args = Self {
+            p1: *stack_ptr.add(5),
+            p2: *stack_ptr.add(4),
+            p3: *stack_ptr.add(3),
+            p4: *stack_ptr.add(8),
+            p5: *stack_ptr.add(6),
+            p6: *stack_ptr.add(7),
+            rsp,
+        };
+        (syscall_num, args)
+    }
+}
+
+/// A system call handler, returning a value or a negated errno
+pub type SyscallHandler = fn(&SyscallArgs) -> i64;
+
+/// Table of system call handlers indexed by syscall number
+static SYSCALL_TABLE: [Option<SyscallHandler>; MAX_SYSCALLS] = {
+    let mut table: [Option<SyscallHandler>; MAX_SYSCALLS] = [None; MAX_SYSCALLS];
+    table[SYSCALL_EXIT as usize] = Some(|args| {
+        sys_exit(args.p1);
+        0
+    });
+    table[SYSCALL_PRINT as usize] = Some(|args| sys_print(args.p1, args.p2));
+    table[SYSCALL_NANOSLEEP as usize] = Some(|args| sys_nanosleep(args.p1, args.rsp));
+    table[SYSCALL_READ as usize] = Some(|args| sys_read(args.p1, args.p2, args.p3, args.rsp));
+    table[SYSCALL_WRITE as usize] = Some(|args| sys_write(args.p1, args.p2, args.p3, args.rsp));
+    table[SYSCALL_OPEN as usize] = Some(|args| sys_open(args.p1, args.p2, args.rsp));
+    table[SYSCALL_CLOSE as usize] = Some(|args| sys_close(args.p1, args.rsp));
+    table[SYSCALL_LSEEK as usize] = Some(|args| sys_lseek(args.p1, args.p2, args.p3));
+    table[SYSCALL_PIPE as usize] = Some(|args| sys_pipe(args.p1));
+    table[SYSCALL_FORK as usize] = Some(|args| sys_fork(args.rsp));
+    table[SYSCALL_WAITPID as usize] = Some(|args| sys_waitpid(args.p1, args.p2, args.p3, args.rsp));
+    table[SYSCALL_WAIT as usize] = Some(|args| sys_wait(args.p1, args.rsp));
+    table[SYSCALL_KILL as usize] = Some(|args| sys_kill(args.p1, args.p2, args.rsp));
+    table[SYSCALL_SIGACTION as usize] = Some(|args| sys_sigaction(args.p1, args.p2, args.p3));
+    table[SYSCALL_SIGPROCMASK as usize] = Some(|args| sys_sigprocmask(args.p1, args.p2, args.p3));
+    table[SYSCALL_SIGRETURN as usize] = Some(|args| sys_sigreturn(args.rsp));
+    table[SYSCALL_EXECVE as usize] = Some(|args| sys_execve(args.p1, args.p2, args.p3, args.rsp));
+    table[SYSCALL_BRK as usize] = Some(|args| sys_brk(args.p1));
+    table[SYSCALL_SBRK as usize] = Some(|args| sys_sbrk(args.p1));
+    table[SYSCALL_MMAP as usize] = Some(|args| sys_mmap(args.p1, args.p2, args.p3, args.p4));
+    table[SYSCALL_MUNMAP as usize] = Some(|args| sys_munmap(args.p1, args.p2));
+    table[SYSCALL_MPROTECT as usize] = Some(|args| sys_mprotect(args.p1, args.p2, args.p3));
+    table[SYSCALL_THREAD_CREATE as usize] =
+        Some(|args| sys_thread_create(args.p1, args.p2, args.p3));
+    table[SYSCALL_THREAD_EXIT as usize] = Some(|args| {
+        sys_thread_exit(args.p1);
+        0
+    });
+    table[SYSCALL_THREAD_JOIN as usize] = Some(|args| sys_thread_join(args.p1, args.p2, args.rsp));
+    table[SYSCALL_GETPID as usize] = Some(|_| sys_getpid());
+    table[SYSCALL_GETPPID as usize] = Some(|_| sys_getppid());
+    table[SYSCALL_TIMES as usize] = Some(|args| sys_times(args.p1));
+    table[SYSCALL_PROCINFO as usize] = Some(|args| sys_procinfo(args.p1, args.p2));
+    table[SYSCALL_GETRLIMIT as usize] = Some(|args| sys_getrlimit(args.p1, args.p2));
+    table[SYSCALL_SETRLIMIT as usize] = Some(|args| sys_setrlimit(args.p1, args.p2));
+    table[SYSCALL_GETPRIORITY as usize] = Some(|args| sys_getpriority(args.p1, args.p2));
+    table[SYSCALL_SETPRIORITY as usize] = Some(|args| sys_setpriority(args.p1, args.p2, args.p3));
+    table[SYSCALL_CLOCK_GETTIME as usize] = Some(|args| sys_clock_gettime(args.p1, args.p2));
+    table
+};
+
+/// Runs the handler registered for a system call number.
+///
+/// # Arguments
+/// * `syscall_num` - the number the caller placed in `rax`
+/// * `args` - the saved syscall arguments
+///
+/// # Returns
+/// The handler's return value, or `-ENOSYS` for an unknown number
+pub fn dispatch(syscall_num: u64, args: &SyscallArgs) -> i64 {
+    match SYSCALL_TABLE.get(syscall_num as usize).copied().flatten() {
+        Some(handler) => handler(args),
+        None => -ENOSYS,
+    }
+}
+
+/// Overwrites the saved `rax` of a syscall frame so the caller sees `value`
+/// once it resumes.
+///
+/// # Arguments
+/// * `rsp` - pointer to the saved register frame
+/// * `value` - the return value
+///
+/// # Safety
+/// `rsp` must point to a register frame laid out by a syscall entry point
+pub unsafe fn set_return_value(rsp: u64, value: i64) {
+    *(rsp as *mut u64) = value as u64;
+}
+
+/// Blocks the calling thread until `future` completes, then resumes it with
+/// the future's output as the syscall's return value.
+///
+/// The future runs as a kernel event, so syscalls can await async kernel work
+/// such as 9P requests to a mount. Does not return when called from a process.
+///
+/// # Arguments
+/// * `rsp` - pointer to the saved register frame of the syscall
+/// * `future` - the work to await, producing a return value or negated errno
+///
+/// # Returns
+/// `-ESRCH` if no process is running on this core
+pub fn block_on<F>(rsp: u64, future: F) -> i64
+where
+    F: Future<Output = i64> + Send + 'static,
+{
+    let event = current_running_event_info();
+    let (pid, tid) = (event.pid, event.tid);
+    if pid == 0 {
+        return -ESRCH;
+    }
+
+    // Kept on this core, so it only runs once the core is back in the event
+    // loop, after block_process has saved the registers the result is
+    // written into
+    schedule_kernel_with_affinity(
+        async move {
+            let ret = future.await;
+            resume_process(pid, tid, ret);
+        },
+        SYSCALL_IO_PRIORITY,
+        core_mask(x2apic::current_core_id() as u32),
+    );
+
+    block_process(rsp);
+    unreachable!("Blocked process returned into its syscall");
+}
+
+/// Schedules a thread blocked in a syscall, returning `ret` to it.
+fn resume_process(pid: u32, tid: u32, ret: i64) {
+    if let Some(thread) = get_thread(pid, tid) {
+        unsafe {
+            (*thread.tcb.get()).registers.rax = ret as u64;
+        }
+        schedule_thread(pid, tid);
+    }
+}
+
+/// Per-core scratch space used by `syscall_entry` through `gs` before a kernel
+/// stack is available. Field offsets are relied upon by the entry assembly.
+#[repr(C)]
+struct SyscallScratch {
+    kernel_rsp: u64,
+    user_rsp: u64,
+    user_cs: u64,
+    user_ss: u64,
+}
+
+static mut SYSCALL_SCRATCH: [SyscallScratch; MAX_CORES] = [const {
+    SyscallScratch {
+        kernel_rsp: 0,
+        user_rsp: 0,
+        user_cs: 0,
+        user_ss: 0,
+    }
+}; MAX_CORES];
+
+/// Enables the `syscall`/`sysret` instructions on the current core.
+///
+/// Must run after the GDT is loaded for this core.
+///
+/// # Arguments
+/// * `cpu_id` - ID of the CPU being initialized
+pub fn init(cpu_id: u32) {
+    let selectors = &gdt::GDT.1;
+
+    unsafe {
+        let scratch = &mut SYSCALL_SCRATCH[cpu_id as usize];
+        scratch.kernel_rsp = gdt::privilege_stack_top(cpu_id).as_u64();
+        scratch.user_cs = selectors.user_code_selector.0 as u64;
+        scratch.user_ss = selectors.user_data_selector.0 as u64;
+        KernelGsBase::write(VirtAddr::from_ptr(scratch as *const SyscallScratch));
+
+        Efer::update(|flags| {
+            flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS);
+        });
+    }
+
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("Invalid STAR segment selectors");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Enter the kernel with interrupts off, like the int 0x80 gate
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+/// Entry point for the `syscall` instruction.
+///
+/// Switches to the core's ring 0 stack and builds the same frame as
+/// `naked_syscall_handler`, so blocking syscalls can save and resume the
+/// process regardless of how it entered the kernel. Returns with `sysretq`,
+/// falling back to `iretq` if the return address is not canonical.
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        // Reach the scratch area and switch stacks. Swap back immediately so
+        // a syscall that never returns here leaves GS consistent.
+        "swapgs",
+        "mov gs:[8], rsp",
+        "mov rsp, gs:[0]",
+        // Fake the interrupt frame: ss, rsp, rflags, cs, rip
+        "push qword ptr gs:[24]",
+        "push qword ptr gs:[8]",
+        "push r11",
+        "push qword ptr gs:[16]",
+        "push rcx",
+        "swapgs",
+        "
+        push rbp
+        push r15
+        push r14
+        push r13
+        push r12
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rbx
+        push rax
+        ",
+        "cld",
+        "mov rdi, rsp",
+        "call syscall_handler",
+        "
+        pop rax
+        pop rbx
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+        pop rbp
+        ",
+        // sysret faults in ring 0 on a non-canonical rip
+        "mov rcx, [rsp]",
+        "mov r11, rcx",
+        "shl r11, 16",
+        "sar r11, 16",
+        "cmp r11, rcx",
+        "jne 2f",
+        "mov r11, [rsp + 16]",
+        "mov rsp, [rsp + 24]",
+        "sysretq",
+        "2:",
+        "iretq",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+
+    #[test_case]
+    fn test_unknown_syscall_returns_enosys() -> impl Future<Output = ()> + Send + 'static {
+        async {
+            let args = SyscallArgs {
+                p1: 0,
+                p2: 0,
+                p3: 0,
+                p4: 0,
+                p5: 0,
+                p6: 0,
+                rsp: 0,
+            };
+
+            assert_eq!(dispatch(MAX_SYSCALLS as u64 - 1, &args), -ENOSYS);
+            assert_eq!(dispatch(u64::MAX, &args), -ENOSYS);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Pipe system calls
+//!
+//! `pipe` opens both ends of a new pipe in the calling process. Reads and
+//! writes on the ends come through `read` and `write`, and park the thread on
+//! the pipe's wait queues until they can make progress; see `ipc::pipe`.
+
+use alloc::{sync::Arc, vec};
+
+use crate::{
+    constants::{
+        errno::{EAGAIN, EBADF, EMFILE, EPIPE, ESRCH},
+        signals::SIGPIPE,
+        syscalls::{PIPE_CAPACITY, RLIMIT_NOFILE},
+    },
+    events::WaitQueue,
+    ipc::{
+        fd_table::FileDesc,
+        pipe::{Pipe, PipeEnd},
+    },
+    memory::user_access::{copy_from_user, copy_to_user},
+    processes::process::{current_process, park_process, PCB},
+};
+
+/// Creates a pipe
+///
+/// * `fds`: user address of two `int`s, set to the read end and the write end
+pub fn sys_pipe(fds: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let limit = pcb.rlimits.current(RLIMIT_NOFILE);
+    let (read_end, write_end) = Pipe::create(PIPE_CAPACITY);
+    let (read_fd, write_fd) = {
+        let mut fd_table = pcb.fd_table.lock();
+        let Ok(read_fd) = fd_table.allocate_pipe(read_end, limit) else {
+            return -EMFILE;
+        };
+        let Ok(write_fd) = fd_table.allocate_pipe(write_end, limit) else {
+            fd_table.remove(read_fd);
+            return -EMFILE;
+        };
+        (read_fd, write_fd)
+    };
+
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+    if let Err(errno) = copy_to_user(pcb, fds, &bytes) {
+        let mut fd_table = pcb.fd_table.lock();
+        fd_table.remove(read_fd);
+        fd_table.remove(write_fd);
+        return -errno;
+    }
+    0
+}
+
+/// Looks up a descriptor that refers to a pipe end
+fn pipe_file(pcb: &PCB, fd: u64) -> Option<Arc<FileDesc>> {
+    let desc = pcb.fd_table.lock().get(fd as usize)?;
+    desc.pipe.is_some().then_some(desc)
+}
+
+fn pipe_of(desc: &FileDesc) -> &Pipe {
+    desc.pipe.as_ref().map(PipeEnd::pipe).expect("Not a pipe")
+}
+
+/// Reads from the read end of a pipe, waiting until there is data or every
+/// write end is closed
+///
+/// The caller must not hold references it expects to drop, as a waiting
+/// thread does not return here.
+///
+/// Returns the number of bytes read, 0 at end of file
+pub(super) fn read_pipe(pcb: &PCB, fd: u64, buf: u64, count: u64, rsp: u64) -> i64 {
+    let Some(desc) = pipe_file(pcb, fd) else {
+        return -EBADF;
+    };
+    let mut data = vec![0u8; count.min(PIPE_CAPACITY as u64) as usize];
+    let result = pipe_of(&desc).read(&mut data);
+
+    match result {
+        Ok(read) => match copy_to_user(pcb, buf, &data[..read]) {
+            Ok(()) => read as i64,
+            Err(errno) => -errno,
+        },
+        Err(EAGAIN) => {
+            let readable: *const WaitQueue = &pipe_of(&desc).readable;
+            // The closure keeps the pipe alive until the thread is registered
+            park_process(rsp, unsafe { &*readable }, move || {
+                pipe_of(&desc).can_read()
+            });
+            -ESRCH
+        }
+        Err(errno) => -errno,
+    }
+}
+
+/// Writes to the write end of a pipe, waiting until there is room
+///
+/// Writes only what fits once there is room, so the count returned may be
+/// short. Writing with every read end closed raises `SIGPIPE`.
+///
+/// The caller must not hold references it expects to drop, as a waiting
+/// thread does not return here.
+///
+/// Returns the number of bytes written
+pub(super) fn write_pipe(pcb: &PCB, fd: u64, buf: u64, count: u64, rsp: u64) -> i64 {
+    let mut data = vec![0u8; count.min(PIPE_CAPACITY as u64) as usize];
+    if let Err(errno) = copy_from_user(pcb, &mut data, buf) {
+        return -errno;
+    }
+    let Some(desc) = pipe_file(pcb, fd) else {
+        return -EBADF;
+    };
+    let result = pipe_of(&desc).write(&data);
+
+    match result {
+        Ok(written) => written as i64,
+        Err(EAGAIN) => {
+            let writable: *const WaitQueue = &pipe_of(&desc).writable;
+            // The closure keeps the pipe alive until the thread is registered
+            park_process(rsp, unsafe { &*writable }, move || {
+                pipe_of(&desc).can_write()
+            });
+            -ESRCH
+        }
+        Err(EPIPE) => {
+            pcb.signals.lock().post(SIGPIPE);
+            -EPIPE
+        }
+        Err(errno) => -errno,
+    }
+}
@@ -0,0 +1,151 @@
+//! Process information system calls
+//!
+//! `getpid`, `getppid` and `times` report on the calling process, and
+//! `procinfo` describes any process, so tools like `ps` and `top` can walk
+//! the process table. Times are in timer ticks.
+
+use core::{mem::size_of, sync::atomic::Ordering};
+
+use crate::{
+    constants::errno::ESRCH,
+    events::runner_timestamp,
+    memory::user_access::copy_to_user,
+    processes::{
+        accounting::resident_frames,
+        process::{current_process, PCB, PROCESS_TABLE},
+    },
+};
+
+/// CPU times reported by `times`, laid out like `struct tms`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tms {
+    pub utime: u64,
+    pub stime: u64,
+    /// User time of waited-for children and their descendants
+    pub cutime: u64,
+    /// Kernel time of waited-for children and their descendants
+    pub cstime: u64,
+}
+
+/// A process as described by `procinfo`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    /// `ProcessState` as a number: 0 new, 1 ready, 2 running, 3 blocked,
+    /// 4 exiting, 5 zombie
+    pub state: u32,
+    pub threads: u32,
+    /// Core a thread of the process last ran on
+    pub core: u32,
+    pub _reserved: u32,
+    /// Timer ticks of the creating core when the process was created
+    pub created: u64,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    pub context_switches: u64,
+    pub resident_frames: u64,
+}
+
+impl ProcInfo {
+    fn of(pcb: &PCB) -> Self {
+        let resident_frames = resident_frames(pcb);
+        let stats = &pcb.stats;
+        Self {
+            pid: pcb.pid,
+            ppid: pcb.ppid,
+            state: pcb.state as u32,
+            threads: pcb.threads.lock().len() as u32,
+            core: stats.last_core.load(Ordering::Relaxed),
+            _reserved: 0,
+            created: stats.created,
+            user_ticks: stats.user_ticks.load(Ordering::Relaxed),
+            kernel_ticks: stats.kernel_ticks.load(Ordering::Relaxed),
+            context_switches: stats.context_switches.load(Ordering::Relaxed),
+            resident_frames,
+        }
+    }
+}
+
+/// Copies a plain `repr(C)` value to user memory
+fn copy_struct_to_user<T: Copy>(pcb: &PCB, addr: u64, value: &T) -> Result<(), i64> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(pcb, addr, bytes)
+}
+
+/// Returns the PID of the calling process
+pub fn sys_getpid() -> i64 {
+    match current_process() {
+        Some(process) => unsafe { (*process.pcb.get()).pid as i64 },
+        None => -ESRCH,
+    }
+}
+
+/// Returns the PID of the calling process' parent, or 0 if it has none
+pub fn sys_getppid() -> i64 {
+    match current_process() {
+        Some(process) => unsafe { (*process.pcb.get()).ppid as i64 },
+        None => -ESRCH,
+    }
+}
+
+/// Reports the CPU time used by the calling process and its children
+///
+/// * `buf`: user address to store a `Tms` at, or 0
+///
+/// Returns the current timer tick count of this core
+pub fn sys_times(buf: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    if buf != 0 {
+        let stats = &pcb.stats;
+        let times = Tms {
+            utime: stats.user_ticks.load(Ordering::Relaxed),
+            stime: stats.kernel_ticks.load(Ordering::Relaxed),
+            cutime: stats.child_user_ticks.load(Ordering::Relaxed),
+            cstime: stats.child_kernel_ticks.load(Ordering::Relaxed),
+        };
+        if let Err(errno) = copy_struct_to_user(pcb, buf, &times) {
+            return -errno;
+        }
+    }
+    runner_timestamp() as i64
+}
+
+/// Describes a process
+///
+/// * `pid`: the lowest PID to describe; the first process at or above it is
+///   reported, so all processes are listed by passing the last PID plus one
+/// * `buf`: user address to store a `ProcInfo` at
+///
+/// Returns the PID described, or `-ESRCH` once no process is left
+pub fn sys_procinfo(pid: u64, buf: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let Ok(pid) = u32::try_from(pid) else {
+        return -ESRCH;
+    };
+
+    let target = PROCESS_TABLE
+        .read()
+        .range(pid..)
+        .next()
+        .map(|(_, p)| p.clone());
+    let Some(target) = target else {
+        return -ESRCH;
+    };
+    let info = ProcInfo::of(unsafe { &*target.pcb.get() });
+
+    let pcb = unsafe { &*process.pcb.get() };
+    match copy_struct_to_user(pcb, buf, &info) {
+        Ok(()) => info.pid as i64,
+        Err(errno) => -errno,
+    }
+}
@@ -0,0 +1,62 @@
+//! Resource limit system calls
+//!
+//! `getrlimit` and `setrlimit` read and change the limits of the calling
+//! process. Enforcement happens where each resource is taken; see
+//! `processes::rlimit`.
+
+use core::mem::size_of;
+
+use crate::{
+    constants::errno::{EINVAL, ESRCH},
+    memory::user_access::{copy_from_user, copy_to_user},
+    processes::{
+        process::current_process,
+        rlimit::{set_rlimit, RLimit},
+    },
+};
+
+/// Reads the limits of a resource
+///
+/// * `resource`: one of the `RLIMIT_*` resources
+/// * `rlim`: user address to store an `RLimit` at
+pub fn sys_getrlimit(resource: u64, rlim: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let Some(limit) = pcb.rlimits.get(resource) else {
+        return -EINVAL;
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&limit as *const RLimit as *const u8, size_of::<RLimit>())
+    };
+    match copy_to_user(pcb, rlim, bytes) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+/// Changes the limits of a resource
+///
+/// * `resource`: one of the `RLIMIT_*` resources
+/// * `rlim`: user address of the new `RLimit`
+///
+/// The hard limit can only be lowered. A limit below what is already in use
+/// only stops further use.
+pub fn sys_setrlimit(resource: u64, rlim: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+
+    let mut bytes = [0u8; size_of::<RLimit>()];
+    if let Err(errno) = copy_from_user(pcb, &mut bytes, rlim) {
+        return -errno;
+    }
+    let limit: RLimit = unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast()) };
+    match set_rlimit(pcb, resource, limit) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
@@ -0,0 +1,87 @@
+//! Scheduling system calls
+//!
+//! `getpriority` and `setpriority` read and change the nice value of a
+//! process, which weights its threads under the fair scheduling policy and
+//! starts them a priority level up under the aging one when negative; see
+//! `events::policy`.
+//!
+//! As in Linux, `getpriority` returns `20 - nice`, from 1 to 40, so that the
+//! result is never mistaken for a negated errno.
+
+use alloc::sync::Arc;
+
+use crate::{
+    constants::{
+        errno::{EINVAL, EPERM, ESRCH},
+        events::{MAX_NICE, MIN_NICE},
+        syscalls::{PRIO_PROCESS, RLIMIT_NICE},
+    },
+    processes::process::{current_process, UnsafePCB, PROCESS_TABLE},
+};
+
+// The process `who` names, the caller if 0
+fn target_process(which: u64, who: u64) -> Result<Arc<UnsafePCB>, i64> {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+    if who == 0 {
+        return current_process().ok_or(ESRCH);
+    }
+    PROCESS_TABLE
+        .read()
+        .get(&(who as u32))
+        .cloned()
+        .ok_or(ESRCH)
+}
+
+/// Reads the nice value of a process
+///
+/// * `which`: `PRIO_PROCESS`; process groups and users are not supported
+/// * `who`: PID of the process, or 0 for the caller
+///
+/// Returns `20 - nice`
+pub fn sys_getpriority(which: u64, who: u64) -> i64 {
+    match target_process(which, who) {
+        Ok(process) => {
+            let pcb = unsafe { &*process.pcb.get() };
+            20 - pcb.nice as i64
+        }
+        Err(errno) => -errno,
+    }
+}
+
+/// Changes the nice value of a process
+///
+/// * `which`: `PRIO_PROCESS`; process groups and users are not supported
+/// * `who`: PID of the process, or 0 for the caller
+/// * `nice`: the new nice value, clamped to -20..=19
+///
+/// A process may change itself and its children, and may not lower the nice
+/// value below what its `RLIMIT_NICE` allows, `20 - limit`. A process started
+/// by the kernel stands in for a privileged user and may do either. Anything
+/// else fails with `EPERM`. Threads already queued keep their old
+/// priority level until they next run.
+pub fn sys_setpriority(which: u64, who: u64, nice: u64) -> i64 {
+    let Some(caller) = current_process() else {
+        return -ESRCH;
+    };
+    let process = match target_process(which, who) {
+        Ok(process) => process,
+        Err(errno) => return -errno,
+    };
+    let caller = unsafe { &*caller.pcb.get() };
+    let pcb = unsafe { &mut *process.pcb.get() };
+
+    let privileged = caller.ppid == 0;
+    if pcb.pid != caller.pid && pcb.ppid != caller.pid && !privileged {
+        return -EPERM;
+    }
+
+    let nice = (nice as i64).clamp(MIN_NICE as i64, MAX_NICE as i64) as i32;
+    let ceiling = caller.rlimits.current(RLIMIT_NICE);
+    if nice < pcb.nice && (20 - nice) as u64 > ceiling && !privileged {
+        return -EPERM;
+    }
+    pcb.nice = nice;
+    0
+}
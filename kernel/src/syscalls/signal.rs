@@ -0,0 +1,199 @@
+//! Signal system calls
+//!
+//! Sending signals, registering handlers, masking signals and returning from
+//! a handler. Delivery itself happens in `processes::signals` when a process
+//! resumes.
+
+use core::mem::size_of;
+
+use crate::{
+    constants::{
+        errno::{EINVAL, ESRCH},
+        memory::USER_SPACE_END,
+        signals::{SIGSEGV, SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK},
+    },
+    memory::user_access::{copy_from_user, copy_to_user},
+    processes::{
+        process::{current_process, yield_process, ProcessState, PROCESS_TABLE},
+        registers::Registers,
+        signals::{restore_signal_frame, sig_bit, valid_signal, SigAction, UNBLOCKABLE},
+        thread::{current_thread, unpark_thread},
+    },
+    syscalls::set_return_value,
+};
+
+/// Sends a signal to a process
+///
+/// * `pid`: the target process; process groups are not supported
+/// * `sig`: the signal, or 0 to only check that the process exists
+/// * `rsp`: the saved register frame, used to deliver a signal the caller
+///   sends itself before returning
+pub fn sys_kill(pid: u64, sig: u64, rsp: u64) -> i64 {
+    if sig != 0 && !valid_signal(sig) {
+        return -EINVAL;
+    }
+    if pid as i64 <= 0 {
+        return -EINVAL;
+    }
+
+    let target = PROCESS_TABLE.read().get(&(pid as u32)).cloned();
+    let Some(target) = target else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*target.pcb.get() };
+    if matches!(pcb.state, ProcessState::Terminated | ProcessState::Zombie) {
+        return -ESRCH;
+    }
+    if sig == 0 {
+        return 0;
+    }
+    pcb.signals.lock().post(sig as u32);
+    for thread in pcb.threads.lock().values() {
+        unpark_thread(unsafe { &*thread.tcb.get() });
+    }
+
+    let self_signal = current_process().is_some_and(|current| {
+        let current = unsafe { &*current.pcb.get() };
+        current.pid == pcb.pid
+    });
+    if self_signal && pcb.signals.lock().deliverable() {
+        // Deliver before the process runs on
+        unsafe {
+            set_return_value(rsp, 0);
+        }
+        yield_process(rsp);
+    }
+    0
+}
+
+/// Examines and changes the action taken for a signal
+///
+/// * `sig`: the signal; SIGKILL and SIGSTOP cannot be changed
+/// * `act`: user address of the new `SigAction`, or 0 to leave it
+/// * `oldact`: user address to store the previous `SigAction` at, or 0
+pub fn sys_sigaction(sig: u64, act: u64, oldact: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    if !valid_signal(sig) {
+        return -EINVAL;
+    }
+    let sig = sig as u32;
+
+    let new_action = if act != 0 {
+        if UNBLOCKABLE & sig_bit(sig) != 0 {
+            return -EINVAL;
+        }
+        let mut bytes = [0u8; size_of::<SigAction>()];
+        if let Err(errno) = copy_from_user(pcb, &mut bytes, act) {
+            return -errno;
+        }
+        let mut action: SigAction = unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast()) };
+        if action.handler != SIG_DFL && action.handler != SIG_IGN {
+            // The handler is entered with iretq, which faults in the kernel
+            // on a non-canonical address
+            if action.handler >= USER_SPACE_END
+                || action.restorer == 0
+                || action.restorer >= USER_SPACE_END
+            {
+                return -EINVAL;
+            }
+        }
+        action.mask &= !UNBLOCKABLE;
+        Some(action)
+    } else {
+        None
+    };
+
+    if oldact != 0 {
+        let old_action = pcb.signals.lock().action(sig);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &old_action as *const SigAction as *const u8,
+                size_of::<SigAction>(),
+            )
+        };
+        if let Err(errno) = copy_to_user(pcb, oldact, bytes) {
+            return -errno;
+        }
+    }
+
+    if let Some(action) = new_action {
+        pcb.signals.lock().set_action(sig, action);
+    }
+    0
+}
+
+/// Examines and changes the blocked signal mask
+///
+/// * `how`: `SIG_BLOCK`, `SIG_UNBLOCK` or `SIG_SETMASK`
+/// * `set`: user address of the mask to apply, or 0 to leave the mask
+/// * `oldset`: user address to store the previous mask at, or 0
+pub fn sys_sigprocmask(how: u64, set: u64, oldset: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let old_blocked = pcb.signals.lock().blocked;
+
+    let new_blocked = if set != 0 {
+        let mut bytes = [0u8; 8];
+        if let Err(errno) = copy_from_user(pcb, &mut bytes, set) {
+            return -errno;
+        }
+        let mask = u64::from_le_bytes(bytes);
+        match how {
+            SIG_BLOCK => Some(old_blocked | mask),
+            SIG_UNBLOCK => Some(old_blocked & !mask),
+            SIG_SETMASK => Some(mask),
+            _ => return -EINVAL,
+        }
+    } else {
+        None
+    };
+
+    if oldset != 0 {
+        if let Err(errno) = copy_to_user(pcb, oldset, &old_blocked.to_le_bytes()) {
+            return -errno;
+        }
+    }
+
+    if let Some(blocked) = new_blocked {
+        pcb.signals.lock().set_blocked(blocked);
+    }
+    0
+}
+
+/// Returns from a signal handler, resuming where the signal interrupted
+///
+/// * `rsp`: the saved register frame; its user stack pointer is the signal
+///   frame once the handler has returned to its restorer
+///
+/// Does not return. A corrupt frame kills the process with SIGSEGV.
+pub fn sys_sigreturn(rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let Some(thread) = current_thread() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    let tcb = unsafe { &mut *thread.tcb.get() };
+
+    let frame_addr = unsafe { Registers::from_stack_frame(rsp) }.rsp;
+    match restore_signal_frame(pcb, &mut tcb.fpu, frame_addr) {
+        Ok(registers) => {
+            unsafe { registers.write_to_stack_frame(rsp) };
+            // Yielding saves the live FPU registers over the restored area
+            tcb.fpu.restore();
+        }
+        Err(_) => pcb.signals.lock().force(SIGSEGV),
+    }
+
+    // Resume through the scheduler, which restores every register and
+    // delivers any signal the handler left pending
+    yield_process(rsp);
+    unreachable!("Yielded process returned into sigreturn");
+}
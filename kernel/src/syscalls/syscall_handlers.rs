@@ -1,13 +1,34 @@
 use crate::{
+    constants::{
+        errno::{ECHILD, EFAULT, EINVAL, ESRCH},
+        syscalls::WNOHANG,
+    },
     debug,
-    events::{current_running_event_info, EventInfo},
+    events::{current_running_event_info, schedule_process, EventInfo},
     interrupts::x2apic,
-    processes::process::{clear_process_frames, sleep_process, ProcessState, PROCESS_TABLE},
+    memory::user_access::{copy_from_user, copy_to_user},
+    processes::{
+        process::{
+            child_waitable, current_process, exit_wait_status, fork_process, park_process,
+            peek_child, reap_child, sleep_process, terminate_process, ChildStatus, PROCESS_TABLE,
+        },
+        registers::Registers,
+        thread::stop_thread,
+    },
+    serial,
+    syscalls::set_return_value,
 };
 
-pub fn sys_exit() {
-    // TODO handle hierarchy (parent processes), resources, threads, etc.
-    // TODO recursive page table walk to handle cleaning up process memory
+/// Bytes copied from user memory per step when printing
+const PRINT_CHUNK_SIZE: usize = 256;
+
+/// Terminates the calling process, with all its threads
+///
+/// * `code`: exit code reported to the parent; only the low 8 bits are kept
+///
+/// The process becomes a zombie until its parent waits for it.
+pub fn sys_exit(code: u64) {
+    // TODO resources, etc.
     let event: EventInfo = current_running_event_info();
 
     if event.pid == 0 {
@@ -20,16 +41,22 @@ pub fn sys_exit() {
     let preemption_info = unsafe {
         let mut process_table = PROCESS_TABLE.write();
         let process = process_table
-            .get_mut(&event.pid)
-            .expect("Process not found");
+            .get(&event.pid)
+            .expect("Process not found")
+            .clone();
 
         let pcb = process.pcb.get();
+        let thread = (*pcb)
+            .threads
+            .lock()
+            .get(&event.tid)
+            .cloned()
+            .expect("Thread not found");
+        let tcb = thread.tcb.get();
 
-        (*pcb).state = ProcessState::Terminated;
-
-        clear_process_frames(&mut *pcb);
-        process_table.remove(&event.pid);
-        ((*pcb).kernel_rsp, (*pcb).kernel_rip)
+        terminate_process(&mut process_table, event.pid, exit_wait_status(code));
+        stop_thread(&mut process_table, event.pid, &mut *tcb);
+        ((*tcb).kernel_rsp, (*tcb).kernel_rip)
     };
 
     unsafe {
@@ -44,7 +71,125 @@ pub fn sys_exit() {
     }
 }
 
-pub fn sys_nanosleep(nanos: u64, rsp: u64) {
+pub fn sys_nanosleep(nanos: u64, rsp: u64) -> i64 {
+    // The process resumes from its saved registers, so report success there
+    unsafe {
+        set_return_value(rsp, 0);
+    }
     sleep_process(rsp, nanos);
     x2apic::send_eoi();
+    0
+}
+
+/// Prints a buffer from the calling process to the serial console
+///
+/// * `buf`: user address of the bytes to print
+/// * `len`: number of bytes to print
+///
+/// Returns the number of bytes printed or `-EFAULT`
+pub fn sys_print(buf: u64, len: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -EFAULT;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let mut chunk = [0u8; PRINT_CHUNK_SIZE];
+    let mut printed: u64 = 0;
+    while printed < len {
+        let size = (len - printed).min(PRINT_CHUNK_SIZE as u64) as usize;
+        if let Err(errno) = copy_from_user(pcb, &mut chunk[..size], buf.wrapping_add(printed)) {
+            return -errno;
+        }
+        serial::write_bytes(&chunk[..size]);
+        printed += size as u64;
+    }
+
+    printed as i64
+}
+
+/// Duplicates the calling process
+///
+/// * `rsp`: the saved register frame, which the child resumes from
+///
+/// Returns the child's PID to the parent and 0 to the child
+pub fn sys_fork(rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &mut *process.pcb.get() };
+
+    let registers = unsafe { Registers::from_stack_frame(rsp) };
+    match fork_process(pcb, registers) {
+        Ok(child) => {
+            schedule_process(child);
+            child as i64
+        }
+        Err(errno) => -errno,
+    }
+}
+
+/// Waits for a child process to exit and collects its status
+///
+/// * `pid`: the child to wait for, or -1 (or 0, as there are no process
+///   groups) for any child
+/// * `wstatus`: user address to store the wait status at, or 0
+/// * `options`: `WNOHANG` to return 0 instead of blocking
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Returns the PID of the collected child
+pub fn sys_waitpid(pid: u64, wstatus: u64, options: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let parent_pid = unsafe { (*process.pcb.get()).pid };
+
+    let target = match pid as i64 {
+        0 | -1 => -1,
+        target if target > 0 => target,
+        _ => return -EINVAL,
+    };
+    if options & !WNOHANG != 0 {
+        return -EINVAL;
+    }
+
+    loop {
+        match peek_child(parent_pid, target) {
+            ChildStatus::Exited(child, status) => {
+                // The child stays waitable if the status cannot be stored
+                if let Err(errno) = report_child(parent_pid, wstatus, status) {
+                    return -errno;
+                }
+                // Another thread of the parent may have collected it meanwhile
+                if let ChildStatus::Exited(..) = reap_child(parent_pid, child as i64) {
+                    return child as i64;
+                }
+            }
+            ChildStatus::NoChildren => return -ECHILD,
+            ChildStatus::Running if options & WNOHANG != 0 => return 0,
+            ChildStatus::Running => {
+                let pcb = unsafe { &*process.pcb.get() };
+                // The thread waits without returning here
+                drop(process);
+                park_process(rsp, &pcb.child_exit, || child_waitable(parent_pid, target));
+                return -ESRCH;
+            }
+        }
+    }
+}
+
+/// Waits for any child process to exit, like `waitpid(-1, wstatus, 0)`
+pub fn sys_wait(wstatus: u64, rsp: u64) -> i64 {
+    sys_waitpid(-1i64 as u64, wstatus, 0, rsp)
+}
+
+/// Stores an exited child's wait status in the parent's memory
+fn report_child(parent_pid: u32, wstatus: u64, status: i32) -> Result<(), i64> {
+    if wstatus == 0 {
+        return Ok(());
+    }
+    let Some(process) = PROCESS_TABLE.read().get(&parent_pid).cloned() else {
+        return Err(ESRCH);
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+    copy_to_user(pcb, wstatus, &status.to_le_bytes())
 }
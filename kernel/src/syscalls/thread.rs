@@ -0,0 +1,130 @@
+//! Thread system calls
+//!
+//! Threads share their process' memory, files and signal handlers. A new
+//! thread may be placed on any core. `exit` ends every thread of the process,
+//! while `thread_exit` only ends the calling one.
+
+use crate::{
+    constants::{
+        errno::{EDEADLK, EINVAL, ESRCH},
+        memory::USER_SPACE_END,
+    },
+    events::current_running_event_info,
+    memory::user_access::copy_to_user,
+    processes::{
+        process::{
+            current_process, exit_wait_status, terminate_process, ProcessState, PROCESS_TABLE,
+        },
+        thread::{create_thread, stop_thread, JoinThread},
+    },
+    syscalls::block_on,
+};
+
+/// Starts a new thread in the calling process
+///
+/// * `entry`: user address the thread starts at; it must end with
+///   `thread_exit`
+/// * `stack_top`: top of the thread's user stack, or 0 to have one allocated
+/// * `arg`: value passed to the thread in `rdi`
+///
+/// Returns the TID of the new thread
+pub fn sys_thread_create(entry: u64, stack_top: u64, arg: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    if entry >= USER_SPACE_END || stack_top >= USER_SPACE_END {
+        return -EINVAL;
+    }
+    match create_thread(pcb, entry, stack_top, arg) {
+        Ok(tid) => tid as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// Ends the calling thread
+///
+/// * `value`: returned to a thread joining this one
+///
+/// If no other thread of the process is left, the process exits with `value`
+/// as its exit code. Does not return.
+pub fn sys_thread_exit(value: u64) {
+    let event = current_running_event_info();
+    if event.pid == 0 {
+        panic!("Calling thread_exit from outside of process");
+    }
+
+    let preemption_info = unsafe {
+        let mut process_table = PROCESS_TABLE.write();
+        let process = process_table
+            .get(&event.pid)
+            .expect("Process not found")
+            .clone();
+        let pcb = &*process.pcb.get();
+        let thread = pcb
+            .threads
+            .lock()
+            .get(&event.tid)
+            .cloned()
+            .expect("Thread not found");
+        let tcb = &mut *thread.tcb.get();
+
+        tcb.exit_value = value;
+        let last = !pcb.threads.lock().iter().any(|(&tid, other)| {
+            tid != event.tid && (*other.tcb.get()).state != ProcessState::Terminated
+        });
+        if last {
+            terminate_process(&mut process_table, event.pid, exit_wait_status(value));
+        }
+        stop_thread(&mut process_table, event.pid, tcb);
+        (tcb.kernel_rsp, tcb.kernel_rip)
+    };
+
+    unsafe {
+        // Restore kernel RSP + PC -> RIP from where it was stored in run/resume process
+        core::arch::asm!(
+            "mov rsp, {0}",
+            "push {1}",
+            "ret",
+            in(reg) preemption_info.0,
+            in(reg) preemption_info.1
+        );
+    }
+}
+
+/// Waits for a thread of the calling process to end and collects it
+///
+/// * `tid`: the thread to wait for
+/// * `retval`: user address to store the thread's exit value at, or 0
+/// * `rsp`: the saved register frame, used to block the caller
+///
+/// Fails with `EDEADLK` if a thread tries to join itself.
+pub fn sys_thread_join(tid: u64, retval: u64, rsp: u64) -> i64 {
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let event = current_running_event_info();
+
+    if tid == event.tid as u64 {
+        return -EDEADLK;
+    }
+    let Ok(tid) = u32::try_from(tid) else {
+        return -ESRCH;
+    };
+
+    let pid = event.pid;
+    block_on(rsp, async move {
+        let value = match (JoinThread { pid, tid }).await {
+            Ok(value) => value,
+            Err(errno) => return -errno,
+        };
+        if retval != 0 {
+            let pcb = unsafe { &*process.pcb.get() };
+            if let Err(errno) = copy_to_user(pcb, retval, &value.to_le_bytes()) {
+                return -errno;
+            }
+        }
+        0
+    })
+}
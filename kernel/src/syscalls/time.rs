@@ -0,0 +1,35 @@
+//! Clock system calls
+//!
+//! `clock_gettime` reads the TSC clock; see `interrupts::tsc`.
+
+use core::mem::size_of;
+
+use crate::{
+    constants::errno::ESRCH,
+    interrupts::tsc::{clock_gettime, Timespec},
+    memory::user_access::copy_to_user,
+    processes::process::current_process,
+};
+
+/// Reads a clock
+///
+/// * `clock`: `CLOCK_MONOTONIC` or `CLOCK_BOOTTIME`
+/// * `tp`: user address to store a `Timespec` at
+pub fn sys_clock_gettime(clock: u64, tp: u64) -> i64 {
+    let time = match clock_gettime(clock) {
+        Ok(time) => time,
+        Err(errno) => return -errno,
+    };
+    let Some(process) = current_process() else {
+        return -ESRCH;
+    };
+    let pcb = unsafe { &*process.pcb.get() };
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&time as *const Timespec as *const u8, size_of::<Timespec>())
+    };
+    match copy_to_user(pcb, tp, bytes) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}